@@ -0,0 +1,174 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::rdb::save::write_file;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Base,
+    Incr,
+}
+
+impl FileType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FileType::Base => "b",
+            FileType::Incr => "i",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub seq: u64,
+    pub file_type: FileType,
+}
+
+/// Which files make up the AOF, one base snapshot followed by the incremental logs
+/// appended since, in replay order.
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    pub base: Option<ManifestEntry>,
+    pub incrs: Vec<ManifestEntry>,
+}
+
+pub fn manifest_name(appendfilename: &str) -> String {
+    format!("{}.manifest", appendfilename)
+}
+
+pub fn base_name(appendfilename: &str, seq: u64) -> String {
+    format!("{}.{}.base.rdb", appendfilename, seq)
+}
+
+pub fn incr_name(appendfilename: &str, seq: u64) -> String {
+    format!("{}.{}.incr.aof", appendfilename, seq)
+}
+
+impl Manifest {
+
+    // file appendonly.aof.1.base.rdb seq 1 type b
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut manifest = Manifest::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let pairs = words.chunks_exact(2);
+            if !pairs.remainder().is_empty() {
+                bail!("Invalid AOF manifest line: {}", line);
+            }
+            let (mut name, mut seq, mut file_type) = (None, None, None);
+            for pair in pairs {
+                match pair[0] {
+                    "file" => name = Some(pair[1].to_string()),
+                    "seq" => seq = pair[1].parse::<u64>().ok(),
+                    "type" => file_type = Some(pair[1]),
+                    // fields from newer versions are ignored, like redis does
+                    _ => {},
+                }
+            }
+            let entry = |file_type| -> Result<ManifestEntry> {
+                Ok(ManifestEntry {
+                    name: name.clone().ok_or_else(|| anyhow!("Missing file name in AOF manifest line: {}", line))?,
+                    seq: seq.ok_or_else(|| anyhow!("Missing seq in AOF manifest line: {}", line))?,
+                    file_type,
+                })
+            };
+            match file_type {
+                Some("b") if manifest.base.is_none() => manifest.base = Some(entry(FileType::Base)?),
+                Some("b") => bail!("Found duplicate base file in AOF manifest"),
+                Some("i") => manifest.incrs.push(entry(FileType::Incr)?),
+                // history files are leftovers waiting to be deleted
+                Some("h") => {},
+                _ => bail!("Invalid AOF manifest line: {}", line),
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn encode(&self) -> String {
+        self.base.iter()
+            .chain(self.incrs.iter())
+            .map(|entry| format!("file {} seq {} type {}\n", entry.name, entry.seq, entry.file_type.as_str()))
+            .collect()
+    }
+
+    /// Reads the manifest in `dir`, `None` when there is none yet.
+    pub fn load(dir: &Path, appendfilename: &str) -> Result<Option<Self>> {
+        match std::fs::read_to_string(dir.join(manifest_name(appendfilename))) {
+            Ok(text) => Ok(Some(Manifest::parse(&text)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, dir: &Path, appendfilename: &str) -> std::io::Result<()> {
+        write_file(&dir.join(manifest_name(appendfilename)), self.encode().as_bytes())
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.base.iter()
+            .chain(self.incrs.iter())
+            .map(|entry| entry.seq)
+            .max()
+            .unwrap_or(0) + 1
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.base.iter().chain(self.incrs.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_what_it_encodes() {
+        let manifest = Manifest {
+            base: Some(ManifestEntry { name: base_name("a.aof", 3), seq: 3, file_type: FileType::Base }),
+            incrs: vec![
+                ManifestEntry { name: incr_name("a.aof", 4), seq: 4, file_type: FileType::Incr },
+                ManifestEntry { name: incr_name("a.aof", 5), seq: 5, file_type: FileType::Incr },
+            ],
+        };
+        let text = manifest.encode();
+        assert_eq!(text, "file a.aof.3.base.rdb seq 3 type b\nfile a.aof.4.incr.aof seq 4 type i\nfile a.aof.5.incr.aof seq 5 type i\n");
+        let parsed = Manifest::parse(&text).unwrap();
+        assert_eq!(parsed.encode(), text);
+        assert_eq!(parsed.next_seq(), 6);
+        assert_eq!(parsed.files().map(|entry| entry.name.as_str()).collect::<Vec<_>>(), ["a.aof.3.base.rdb", "a.aof.4.incr.aof", "a.aof.5.incr.aof"]);
+    }
+
+    #[test]
+    fn skips_history_comments_and_unknown_fields() {
+        let manifest = Manifest::parse(
+            "# written by a newer version\n\
+             file a.aof.1.base.rdb seq 1 type h\n\
+             type i seq 3 file a.aof.3.incr.aof\n\
+             \n\
+             file a.aof.2.base.rdb seq 2 type b startoffset 10\n"
+        ).unwrap();
+        assert_eq!(manifest.base.unwrap().name, "a.aof.2.base.rdb");
+        assert_eq!(manifest.incrs.len(), 1);
+        assert_eq!(manifest.incrs[0].seq, 3);
+        assert_eq!(Manifest::default().next_seq(), 1);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for text in [
+            "file a.aof.1.base.rdb seq 1",
+            "file a.aof.1.base.rdb seq 1 type x",
+            "file a.aof.1.base.rdb seq type b",
+            "seq 1 type i",
+            "file a seq x type i",
+            "file a seq 1 type b\nfile b seq 2 type b",
+        ] {
+            assert!(Manifest::parse(text).is_err(), "{:?}", text);
+        }
+    }
+}
@@ -0,0 +1,319 @@
+pub mod manifest;
+pub mod rewrite;
+
+use std::{fs::{File, OpenOptions}, io::Write, path::Path, sync::Arc};
+
+use anyhow::{bail, Result};
+use tokio::{sync::RwLock, time::Duration};
+
+use crate::{broadcast::Broadcaster, db::Db, handler::execute, protocol::{RObject, RespCodec}, rdb, State};
+
+use manifest::{FileType, Manifest, ManifestEntry};
+
+pub use rewrite::{bgrewrite, create, open};
+
+// how often appendfsync everysec flushes the file to disk
+const FSYNC_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    Always,
+    EverySec,
+    No,
+}
+
+impl std::str::FromStr for FsyncPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsyncPolicy::Always => write!(f, "always"),
+            FsyncPolicy::EverySec => write!(f, "everysec"),
+            FsyncPolicy::No => write!(f, "no"),
+        }
+    }
+}
+
+/// The open incremental file of the AOF, fed with the same bytes the replicas receive.
+pub struct AofWriter {
+    file: File,
+    pub policy: FsyncPolicy,
+    // written since the last fsync, only tracked for everysec
+    pending_fsync: bool,
+    /// Bytes across the base and every incremental file.
+    pub size: u64,
+    /// `size` right after the last rewrite or load, what the automatic rewrite growth is measured against.
+    pub base_size: u64,
+}
+
+impl AofWriter {
+
+    /// Opens an incremental file for appending, `preceding` is the size of the files before it.
+    pub fn open(path: &Path, policy: FsyncPolicy, preceding: u64) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = preceding + file.metadata()?.len();
+        Ok(AofWriter { file, policy, pending_fsync: false, size, base_size: size })
+    }
+
+    pub fn append(&mut self, message: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(message)?;
+        self.size += message.len() as u64;
+        match self.policy {
+            FsyncPolicy::Always => self.file.sync_data()?,
+            FsyncPolicy::EverySec => self.pending_fsync = true,
+            FsyncPolicy::No => {},
+        }
+        Ok(())
+    }
+
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+
+    // a second handle on the file for fsyncing without holding the broadcaster
+    fn take_pending_fsync(&mut self) -> Option<std::io::Result<File>> {
+        if !std::mem::take(&mut self.pending_fsync) {
+            return None;
+        }
+        Some(self.file.try_clone())
+    }
+}
+
+/// Flushes the AOF once a second under appendfsync everysec.
+pub async fn fsync_cycle(broadcaster: Arc<RwLock<Broadcaster>>) {
+    let mut interval = tokio::time::interval(FSYNC_PERIOD);
+    loop {
+        interval.tick().await;
+        let file = match broadcaster.write().await.aof.as_mut().and_then(AofWriter::take_pending_fsync) {
+            Some(Ok(file)) => file,
+            Some(Err(e)) => {
+                eprintln!("Failed to fsync the AOF: {}", e);
+                continue;
+            },
+            None => continue,
+        };
+        match tokio::task::spawn_blocking(move || file.sync_data()).await {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => eprintln!("Failed to fsync the AOF: {}", e),
+            Err(e) => eprintln!("Failed to fsync the AOF: {}", e),
+        }
+    }
+}
+
+/// Reads the AOF manifest, upgrading a single file AOF into the base of a new one if that is all there is.
+pub fn find_manifest(state: &State) -> Result<Option<Manifest>> {
+    let dir = state.aof_dir();
+    if let Some(manifest) = Manifest::load(&dir, &state.appendfilename)? {
+        return Ok(Some(manifest));
+    }
+    let legacy = state.legacy_aof_path();
+    if !legacy.exists() {
+        return Ok(None);
+    }
+    std::fs::create_dir_all(&dir)?;
+    let name = format!("{}.1.base.aof", state.appendfilename);
+    std::fs::rename(&legacy, dir.join(&name))?;
+    let manifest = Manifest {
+        base: Some(ManifestEntry { name, seq: 1, file_type: FileType::Base }),
+        incrs: vec![],
+    };
+    manifest.save(&dir, &state.appendfilename)?;
+    eprintln!("Upgraded {} to the multi part AOF in {}", legacy.display(), dir.display());
+    Ok(Some(manifest))
+}
+
+/// Replays every file the manifest lists into the keyspace, returns how many commands ran.
+///
+/// A command cut short at the end of the last file, usually from a crash mid write, is dropped
+/// and the file truncated when `load_truncated` is set, otherwise loading fails.
+pub async fn load(dir: &Path, manifest: &Manifest, storage: &Arc<RwLock<Db>>, state: &Arc<RwLock<State>>, load_truncated: bool) -> Result<usize> {
+    let files: Vec<_> = manifest.files().collect();
+    let mut commands = 0;
+    for (i, entry) in files.iter().enumerate() {
+        let path = dir.join(&entry.name);
+        let data = std::fs::read(&path)?;
+        // the base is an RDB unless it was upgraded from a plain single file AOF
+        if data.starts_with(rdb::MAGIC) {
+            rdb::load(&data, &mut *storage.write().await)?;
+            continue;
+        }
+        let last = i + 1 == files.len();
+        commands += replay(&path, &data, storage, state, last, load_truncated).await?;
+    }
+    Ok(commands)
+}
+
+async fn replay(path: &Path, data: &[u8], storage: &Arc<RwLock<Db>>, state: &Arc<RwLock<State>>, last: bool, load_truncated: bool) -> Result<usize> {
+    let mut codec = {
+        let state = state.read().await;
+        RespCodec::new(state.proto_max_bulk_len, state.proto_max_multibulk_len)
+    };
+    codec.extend(data);
+    // replayed commands must not reach the real replicas or the AOF being loaded
+    let scratch = Arc::new(RwLock::new(Broadcaster::default()));
+    let mut valid_len = 0;
+    let mut commands = 0;
+    loop {
+        let (request, raw) = match codec.next_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => bail!("Bad file format reading the append only file at offset {}: {}", valid_len, e),
+        };
+        let args = match request {
+            RObject::Array(args) if !args.is_empty() => args,
+            _ => bail!("Bad file format reading the append only file at offset {}", valid_len),
+        };
+        if let Err(e) = execute(args, Arc::clone(storage), Arc::clone(state), Arc::clone(&scratch)).await {
+            bail!("Error replaying the append only file at offset {}: {}", valid_len, e);
+        }
+        valid_len += raw.len();
+        commands += 1;
+    }
+
+    if valid_len < data.len() {
+        // only the file being appended to when the crash happened can end early
+        if !last {
+            bail!("{} ends in the middle of a command at offset {}", path.display(), valid_len);
+        }
+        if !load_truncated {
+            bail!(
+                "Unexpected end of {} at offset {}, restart with --aof-load-truncated yes to drop the incomplete command",
+                path.display(),
+                valid_len
+            );
+        }
+        eprintln!("{} ends with an incomplete command, truncating it from {} to {} bytes", path.display(), data.len(), valid_len);
+        OpenOptions::new().write(true).open(path)?.set_len(valid_len as u64)?;
+    }
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::value::Value;
+
+    fn command(args: &[&str]) -> Vec<u8> {
+        RObject::Array(args.iter().map(|arg| RObject::BulkString(Bytes::copy_from_slice(arg.as_bytes()))).collect()).encode()
+    }
+
+    // a fresh directory per test, the tests run in parallel
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aof-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn incr(name: &str, seq: u64) -> ManifestEntry {
+        ManifestEntry { name: name.to_string(), seq, file_type: FileType::Incr }
+    }
+
+    async fn load_into_new_db(dir: &Path, manifest: &Manifest, load_truncated: bool) -> (Result<usize>, Arc<RwLock<Db>>) {
+        let storage = Arc::new(RwLock::new(Db::new()));
+        let state = Arc::new(RwLock::new(State::for_tests()));
+        let loaded = load(dir, manifest, &storage, &state, load_truncated).await;
+        (loaded, storage)
+    }
+
+    fn string(storage: &Db, key: &[u8]) -> Option<Bytes> {
+        match storage.get(key) {
+            Some(Value::String(value)) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_every_file_in_order() {
+        let dir = scratch_dir("in-order");
+        std::fs::write(dir.join("a.1.incr.aof"), [command(&["SET", "k", "1"]), command(&["RPUSH", "l", "x", "y"])].concat()).unwrap();
+        std::fs::write(dir.join("a.2.incr.aof"), [command(&["SET", "k", "2"]), command(&["LPOP", "l"])].concat()).unwrap();
+        let manifest = Manifest { base: None, incrs: vec![incr("a.1.incr.aof", 1), incr("a.2.incr.aof", 2)] };
+
+        let (loaded, storage) = load_into_new_db(&dir, &manifest, false).await;
+        assert_eq!(loaded.unwrap(), 4);
+        let storage = storage.read().await;
+        assert_eq!(string(&storage, b"k"), Some(Bytes::from("2")));
+        assert!(matches!(storage.get(b"l"), Some(Value::List(list)) if list.len() == 1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_truncated_tail_fails_unless_allowed() {
+        let dir = scratch_dir("truncated-tail");
+        let complete = command(&["SET", "k", "v"]);
+        let partial = command(&["SET", "other", "value"]);
+        let data = [complete.clone(), partial[..partial.len() - 3].to_vec()].concat();
+        let path = dir.join("a.1.incr.aof");
+        std::fs::write(&path, &data).unwrap();
+        let manifest = Manifest { base: None, incrs: vec![incr("a.1.incr.aof", 1)] };
+
+        let (loaded, _) = load_into_new_db(&dir, &manifest, false).await;
+        let err = loaded.unwrap_err().to_string();
+        assert!(err.contains(&format!("at offset {}", complete.len())), "{}", err);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        let (loaded, storage) = load_into_new_db(&dir, &manifest, true).await;
+        assert_eq!(loaded.unwrap(), 1);
+        assert_eq!(string(&*storage.read().await, b"k"), Some(Bytes::from("v")));
+        assert!(!storage.read().await.contains_key(b"other"));
+        // the incomplete command is cut off so appending continues after the last whole one
+        assert_eq!(std::fs::read(&path).unwrap(), complete);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_the_last_file_may_end_early() {
+        let dir = scratch_dir("truncated-middle");
+        let partial = command(&["SET", "k", "v"]);
+        std::fs::write(dir.join("a.1.incr.aof"), &partial[..partial.len() - 1]).unwrap();
+        std::fs::write(dir.join("a.2.incr.aof"), command(&["SET", "k", "w"])).unwrap();
+        let manifest = Manifest { base: None, incrs: vec![incr("a.1.incr.aof", 1), incr("a.2.incr.aof", 2)] };
+
+        let (loaded, _) = load_into_new_db(&dir, &manifest, true).await;
+        assert!(loaded.unwrap_err().to_string().contains("ends in the middle of a command"));
+        assert_eq!(std::fs::read(dir.join("a.1.incr.aof")).unwrap().len(), partial.len() - 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_garbage() {
+        let dir = scratch_dir("garbage");
+        std::fs::write(dir.join("a.1.incr.aof"), [command(&["SET", "k", "v"]), b"hello\r\n".to_vec()].concat()).unwrap();
+        let manifest = Manifest { base: None, incrs: vec![incr("a.1.incr.aof", 1)] };
+
+        let (loaded, _) = load_into_new_db(&dir, &manifest, true).await;
+        assert!(loaded.unwrap_err().to_string().starts_with("Bad file format"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn appends_count_towards_the_size() {
+        let dir = scratch_dir("writer");
+        let path = dir.join("a.1.incr.aof");
+        std::fs::write(&path, b"12345").unwrap();
+        let mut writer = AofWriter::open(&path, FsyncPolicy::EverySec, 100).unwrap();
+        assert_eq!((writer.size, writer.base_size), (105, 105));
+        writer.append(b"678").unwrap();
+        assert_eq!(writer.size, 108);
+        assert!(writer.take_pending_fsync().is_some());
+        assert!(writer.take_pending_fsync().is_none());
+        assert_eq!(std::fs::read(&path).unwrap(), b"12345678");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use std::{path::Path, sync::Arc};
+
+use bytes::Bytes;
+use tokio::{sync::RwLock, time::Duration};
+
+use crate::{broadcast::Broadcaster, db::{Db, Entry}, error::RedisError, rdb::{self, save::write_file}, state::ServerRole, State};
+
+use super::{manifest::{base_name, incr_name, FileType, Manifest, ManifestEntry}, AofWriter, FsyncPolicy};
+
+// how often the automatic rewrite triggers are checked
+const CRON_PERIOD: Duration = Duration::from_millis(100);
+
+fn remove_unlisted(dir: &Path, old: &Manifest, new: &Manifest) {
+    for entry in old.files() {
+        if new.files().all(|kept| kept.name != entry.name) {
+            if let Err(e) = std::fs::remove_file(dir.join(&entry.name)) {
+                eprintln!("Failed to remove {}: {}", entry.name, e);
+            }
+        }
+    }
+}
+
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0)
+}
+
+/// Replaces whatever AOF is in `dir` with a base holding `snapshot` and an empty incremental file,
+/// for when the log has to start over: turning appendonly on, or after a full resync.
+pub fn create(dir: &Path, appendfilename: &str, policy: FsyncPolicy, snapshot: &[(Bytes, Entry)]) -> anyhow::Result<AofWriter> {
+    std::fs::create_dir_all(dir)?;
+    let old = Manifest::load(dir, appendfilename)?.unwrap_or_default();
+    let seq = old.next_seq();
+
+    let base = base_name(appendfilename, seq);
+    write_file(&dir.join(&base), &rdb::dump(snapshot))?;
+    let incr = incr_name(appendfilename, seq);
+    let writer = AofWriter::open(&dir.join(&incr), policy, file_size(&dir.join(&base)))?;
+
+    let manifest = Manifest {
+        base: Some(ManifestEntry { name: base, seq, file_type: FileType::Base }),
+        incrs: vec![ManifestEntry { name: incr, seq, file_type: FileType::Incr }],
+    };
+    manifest.save(dir, appendfilename)?;
+    remove_unlisted(dir, &old, &manifest);
+    Ok(writer)
+}
+
+/// Opens the AOF the manifest describes for appending, adding an incremental file if it has none.
+pub fn open(dir: &Path, appendfilename: &str, policy: FsyncPolicy, manifest: &mut Manifest) -> anyhow::Result<AofWriter> {
+    if manifest.incrs.is_empty() {
+        let seq = manifest.next_seq();
+        manifest.incrs.push(ManifestEntry { name: incr_name(appendfilename, seq), seq, file_type: FileType::Incr });
+        manifest.save(dir, appendfilename)?;
+    }
+    let (last, preceding) = manifest.incrs.split_last().expect("an incremental file was just added");
+    let preceding_size = manifest.base.iter()
+        .chain(preceding.iter())
+        .map(|entry| file_size(&dir.join(&entry.name)))
+        .sum();
+    Ok(AofWriter::open(&dir.join(&last.name), policy, preceding_size)?)
+}
+
+/// BGREWRITEAOF, compacts the AOF into a new base snapshot of the keyspace.
+///
+/// Writes from the moment of the snapshot go to a new incremental file that is listed in the
+/// manifest right away, so a crash halfway leaves the old files complete and loadable.
+/// Once the base is written, the manifest drops everything that came before it.
+pub async fn bgrewrite(storage: &Arc<RwLock<Db>>, state: &Arc<RwLock<State>>, broadcaster: &Arc<RwLock<Broadcaster>>) -> Result<(), RedisError> {
+    let (dir, appendfilename, policy, replica) = {
+        let mut state = state.write().await;
+        if state.aof_rewrite_in_progress {
+            return Err(RedisError::Err("Background append only file rewriting already in progress".to_string()));
+        }
+        state.aof_rewrite_in_progress = true;
+        (state.aof_dir(), state.appendfilename.clone(), state.appendfsync, state.role == ServerRole::Slave)
+    };
+
+    let started = {
+        let mut broadcaster = broadcaster.write().await;
+        start_rewrite(&dir, &appendfilename, policy, replica, storage, &mut broadcaster).await
+    };
+    let (snapshot, seq) = match started {
+        Ok(started) => started,
+        Err(e) => {
+            let mut state = state.write().await;
+            state.aof_rewrite_in_progress = false;
+            state.aof_last_rewrite_ok = false;
+            return Err(e.into());
+        },
+    };
+
+    let state = Arc::clone(state);
+    let broadcaster = Arc::clone(broadcaster);
+    tokio::spawn(async move {
+        let base = base_name(&appendfilename, seq);
+        let base_path = dir.join(&base);
+        let written = tokio::task::spawn_blocking(move || write_file(&base_path, &rdb::dump(&snapshot))).await;
+        let result = match written {
+            Ok(Ok(())) => {
+                let mut broadcaster = broadcaster.write().await;
+                finish_rewrite(&dir, &appendfilename, base, seq, &mut broadcaster)
+            },
+            Ok(Err(e)) => Err(e.into()),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = &result {
+            eprintln!("Background AOF rewrite failed: {}", e);
+        }
+        let mut state = state.write().await;
+        state.aof_rewrite_in_progress = false;
+        state.aof_last_rewrite_ok = result.is_ok();
+    });
+    Ok(())
+}
+
+// snapshots the keyspace and, with appendonly on, moves the writes to a new incremental file.
+// Both happen under the keyspace lock with the queued writes flushed to the old file first,
+// so every write lands in exactly one of the snapshot and the new file.
+async fn start_rewrite(
+    dir: &Path,
+    appendfilename: &str,
+    policy: FsyncPolicy,
+    replica: bool,
+    storage: &Arc<RwLock<Db>>,
+    broadcaster: &mut Broadcaster,
+) -> anyhow::Result<(Vec<(Bytes, Entry)>, u64)> {
+    std::fs::create_dir_all(dir)?;
+    let mut storage = storage.write().await;
+    broadcaster.propagate(&mut storage, replica);
+    let mut manifest = Manifest::load(dir, appendfilename)?.unwrap_or_default();
+    let seq = manifest.next_seq();
+    if let Some(aof) = broadcaster.aof.as_mut() {
+        let incr = incr_name(appendfilename, seq);
+        let mut writer = AofWriter::open(&dir.join(&incr), policy, aof.size)?;
+        writer.base_size = aof.base_size;
+        manifest.incrs.push(ManifestEntry { name: incr, seq, file_type: FileType::Incr });
+        manifest.save(dir, appendfilename)?;
+        if let Err(e) = aof.sync() {
+            eprintln!("Failed to fsync the AOF: {}", e);
+        }
+        *aof = writer;
+    }
+    Ok((storage.snapshot(), seq))
+}
+
+// points the manifest at the new base and whatever was appended since the snapshot
+fn finish_rewrite(dir: &Path, appendfilename: &str, base: String, seq: u64, broadcaster: &mut Broadcaster) -> anyhow::Result<()> {
+    let old = Manifest::load(dir, appendfilename)?.unwrap_or_default();
+    let manifest = Manifest {
+        base: Some(ManifestEntry { name: base, seq, file_type: FileType::Base }),
+        incrs: old.incrs.iter().filter(|entry| entry.seq >= seq).cloned().collect(),
+    };
+    manifest.save(dir, appendfilename)?;
+    remove_unlisted(dir, &old, &manifest);
+
+    if let Some(aof) = broadcaster.aof.as_mut() {
+        aof.size = manifest.files().map(|entry| file_size(&dir.join(&entry.name))).sum();
+        aof.base_size = aof.size;
+    }
+    Ok(())
+}
+
+/// Starts a rewrite once the AOF grew by auto-aof-rewrite-percentage since the last one
+/// and is larger than auto-aof-rewrite-min-size.
+pub async fn rewrite_cron(storage: Arc<RwLock<Db>>, state: Arc<RwLock<State>>, broadcaster: Arc<RwLock<Broadcaster>>) {
+    let mut interval = tokio::time::interval(CRON_PERIOD);
+    loop {
+        interval.tick().await;
+        let (percentage, min_size, in_progress) = {
+            let state = state.read().await;
+            (state.auto_aof_rewrite_percentage, state.auto_aof_rewrite_min_size, state.aof_rewrite_in_progress)
+        };
+        if percentage == 0 || in_progress {
+            continue;
+        }
+        let (size, base_size) = match broadcaster.read().await.aof.as_ref() {
+            Some(aof) => (aof.size, aof.base_size),
+            None => continue,
+        };
+        let growth = size.saturating_sub(base_size) * 100 / base_size.max(1);
+        if size > min_size && growth >= percentage {
+            eprintln!("Starting automatic rewriting of AOF on {}% growth", growth);
+            if let Err(e) = bgrewrite(&storage, &state, &broadcaster).await {
+                eprintln!("Failed to start the AOF rewrite: {}", e);
+            }
+        }
+    }
+}
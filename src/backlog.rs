@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+
+pub const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+
+/// The tail of the replication stream, kept so a replica that lost its link for a moment
+/// can continue from its offset instead of transferring the whole dataset again.
+///
+/// Offsets count every byte ever fed, the first one being offset 1 like in PSYNC.
+pub struct Backlog {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    /// Offset of the last byte fed, the master_repl_offset.
+    offset: usize,
+}
+
+impl Default for Backlog {
+    fn default() -> Self {
+        Self::new(DEFAULT_BACKLOG_SIZE)
+    }
+}
+
+impl Backlog {
+
+    pub fn new(capacity: usize) -> Self {
+        Backlog { buf: VecDeque::new(), capacity, offset: 0 }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.offset += data.len();
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let excess = (self.buf.len() + data.len()).saturating_sub(self.capacity);
+        self.buf.drain(..excess);
+        self.buf.extend(data);
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Bytes actually held, at most the capacity.
+    pub fn histlen(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Offset of the oldest byte still held.
+    pub fn first_offset(&self) -> usize {
+        self.offset - self.buf.len() + 1
+    }
+
+    /// The stream from offset `from` to the end, `None` once part of it left the backlog.
+    pub fn range_from(&self, from: usize) -> Option<Vec<u8>> {
+        if from < self.first_offset() || from > self.offset + 1 {
+            return None;
+        }
+        Some(self.buf.iter().skip(from - self.first_offset()).copied().collect())
+    }
+
+    /// Drops the history and continues counting from `offset`.
+    pub fn restart_at(&mut self, offset: usize) {
+        self.buf.clear();
+        self.offset = offset;
+    }
+
+    pub fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        let excess = self.buf.len().saturating_sub(capacity);
+        self.buf.drain(..excess);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_within_the_window() {
+        let mut backlog = Backlog::new(16);
+        backlog.feed(b"hello ");
+        backlog.feed(b"world");
+        assert_eq!(backlog.offset(), 11);
+        assert_eq!(backlog.first_offset(), 1);
+        assert_eq!(backlog.range_from(1).unwrap(), b"hello world");
+        assert_eq!(backlog.range_from(7).unwrap(), b"world");
+        // right after the last byte fed, nothing is missing
+        assert_eq!(backlog.range_from(12).unwrap(), b"");
+        assert_eq!(backlog.range_from(13), None);
+    }
+
+    #[test]
+    fn drops_what_exceeds_the_capacity() {
+        let mut backlog = Backlog::new(8);
+        backlog.feed(b"0123456");
+        backlog.feed(b"789");
+        assert_eq!(backlog.histlen(), 8);
+        assert_eq!(backlog.first_offset(), 3);
+        assert_eq!(backlog.range_from(2), None);
+        assert_eq!(backlog.range_from(3).unwrap(), b"23456789");
+
+        backlog.feed(b"abcdefghijkl");
+        assert_eq!(backlog.offset(), 22);
+        assert_eq!(backlog.range_from(15).unwrap(), b"efghijkl");
+
+        backlog.resize(4);
+        assert_eq!(backlog.range_from(19).unwrap(), b"ijkl");
+        assert_eq!(backlog.range_from(18), None);
+    }
+
+    #[test]
+    fn restarts_at_an_offset() {
+        let mut backlog = Backlog::new(16);
+        backlog.feed(b"abc");
+        backlog.restart_at(100);
+        assert_eq!(backlog.histlen(), 0);
+        assert_eq!(backlog.range_from(101).unwrap(), b"");
+        assert_eq!(backlog.range_from(100), None);
+        backlog.feed(b"xyz");
+        assert_eq!(backlog.offset(), 103);
+        assert_eq!(backlog.range_from(102).unwrap(), b"yz");
+    }
+}
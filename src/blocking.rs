@@ -0,0 +1,158 @@
+use std::collections::{HashMap, VecDeque};
+
+use bytes::Bytes;
+use tokio::sync::oneshot;
+
+use crate::{error::RedisError, protocol::RObject, quicklist::End};
+
+/// What a blocked client does once one of its keys has elements.
+pub enum BlockedOp {
+    /// BLPOP and BRPOP, one element.
+    Pop(End),
+    /// BLMPOP, up to `count` elements.
+    MultiPop { end: End, count: usize },
+    /// BLMOVE into `destination`.
+    Move { destination: Bytes, from: End, to: End },
+}
+
+pub struct Waiter {
+    pub keys: Vec<Bytes>,
+    pub op: BlockedOp,
+    reply: oneshot::Sender<Result<RObject, RedisError>>,
+}
+
+impl Waiter {
+
+    /// Whether the client stopped waiting, serving it would lose the elements.
+    pub fn is_gone(&self) -> bool {
+        self.reply.is_closed()
+    }
+
+    pub fn send(self, reply: Result<RObject, RedisError>) {
+        let _ = self.reply.send(reply);
+    }
+}
+
+/// The clients blocked on list keys, redis' `blocking_keys` and `ready_keys`.
+///
+/// Each key has its waiters in the order they blocked, and a push to a waited key
+/// queues it as ready so the pushing command serves them before releasing the keyspace.
+#[derive(Default)]
+pub struct Blocked {
+    waiters: HashMap<u64, Waiter>,
+    by_key: HashMap<Bytes, VecDeque<u64>>,
+    ready: VecDeque<Bytes>,
+    next_id: u64,
+}
+
+impl Blocked {
+
+    /// Registers a client, returns its id and where its reply arrives once served.
+    pub fn block(&mut self, keys: Vec<Bytes>, op: BlockedOp) -> (u64, oneshot::Receiver<Result<RObject, RedisError>>) {
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_id;
+        self.next_id += 1;
+        for key in &keys {
+            self.by_key.entry(key.clone()).or_default().push_back(id);
+        }
+        self.waiters.insert(id, Waiter { keys, op, reply: tx });
+        (id, rx)
+    }
+
+    /// Removes a waiter from every key it waits on, `None` if it was already served.
+    pub fn unblock(&mut self, id: u64) -> Option<Waiter> {
+        let waiter = self.waiters.remove(&id)?;
+        for key in &waiter.keys {
+            if let Some(queue) = self.by_key.get_mut(key) {
+                queue.retain(|waiting| *waiting != id);
+                if queue.is_empty() {
+                    self.by_key.remove(key);
+                }
+            }
+        }
+        Some(waiter)
+    }
+
+    /// The client that blocked first on `key`.
+    pub fn first(&self, key: &[u8]) -> Option<u64> {
+        self.by_key.get(key).and_then(|queue| queue.front().copied())
+    }
+
+    /// Called after a push, remembers the key if somebody waits on it.
+    pub fn signal(&mut self, key: &Bytes) {
+        if self.by_key.contains_key(key) && !self.ready.contains(key) {
+            self.ready.push_back(key.clone());
+        }
+    }
+
+    pub fn next_ready(&mut self) -> Option<Bytes> {
+        self.ready.pop_front()
+    }
+
+    /// Fails every blocked client with `-UNBLOCKED`, for when the instance stops being a
+    /// master and the keys they wait on only change through its new master.
+    pub fn unblock_all(&mut self) {
+        for (_, waiter) in self.waiters.drain() {
+            waiter.send(Err(RedisError::Unblocked));
+        }
+        self.by_key.clear();
+        self.ready.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(names: &[&'static str]) -> Vec<Bytes> {
+        names.iter().map(|name| Bytes::from_static(name.as_bytes())).collect()
+    }
+
+    #[test]
+    fn keeps_waiters_in_the_order_they_blocked() {
+        let mut blocked = Blocked::default();
+        let (first, _first_rx) = blocked.block(keys(&["a", "b"]), BlockedOp::Pop(End::Left));
+        let (second, _second_rx) = blocked.block(keys(&["b"]), BlockedOp::Pop(End::Left));
+        assert_eq!(blocked.first(b"a"), Some(first));
+        assert_eq!(blocked.first(b"b"), Some(first));
+
+        // leaving takes a client off every key it waited on
+        assert!(blocked.unblock(first).is_some());
+        assert!(blocked.unblock(first).is_none());
+        assert_eq!(blocked.first(b"a"), None);
+        assert_eq!(blocked.first(b"b"), Some(second));
+    }
+
+    #[test]
+    fn signals_waited_keys_once() {
+        let mut blocked = Blocked::default();
+        let (_, _rx) = blocked.block(keys(&["a", "b"]), BlockedOp::Pop(End::Right));
+        for key in keys(&["b", "unwatched", "a", "b"]) {
+            blocked.signal(&key);
+        }
+        assert_eq!(blocked.next_ready(), Some(Bytes::from("b")));
+        assert_eq!(blocked.next_ready(), Some(Bytes::from("a")));
+        assert_eq!(blocked.next_ready(), None);
+    }
+
+    #[test]
+    fn unblock_all_fails_every_waiter() {
+        let mut blocked = Blocked::default();
+        let (_, mut first) = blocked.block(keys(&["a"]), BlockedOp::Pop(End::Left));
+        let (_, mut second) = blocked.block(keys(&["b"]), BlockedOp::MultiPop { end: End::Left, count: 2 });
+        blocked.signal(&Bytes::from("a"));
+        blocked.unblock_all();
+        assert!(matches!(first.try_recv(), Ok(Err(RedisError::Unblocked))));
+        assert!(matches!(second.try_recv(), Ok(Err(RedisError::Unblocked))));
+        assert_eq!(blocked.first(b"a"), None);
+        assert_eq!(blocked.next_ready(), None);
+    }
+
+    #[test]
+    fn notices_clients_that_left() {
+        let mut blocked = Blocked::default();
+        let (id, rx) = blocked.block(keys(&["a"]), BlockedOp::Pop(End::Left));
+        drop(rx);
+        assert!(blocked.unblock(id).unwrap().is_gone());
+    }
+}
@@ -1,123 +1,456 @@
-use std::{sync::Arc, usize};
+use std::{io::ErrorKind, net::IpAddr, sync::{atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering}, Arc}};
 
-use anyhow::Error;
-use futures::future::join_all;
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, time::timeout, time::Duration};
-use futures::lock::Mutex;
+use bytes::Bytes;
+use futures::future::{BoxFuture, Shared};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpStream}, sync::{mpsc, Notify, RwLock}, task::JoinHandle, time::{interval, timeout, Duration}};
 
-use crate::{protocol::RObject, BUFFER_SIZE};
+use crate::{aof::AofWriter, backlog::Backlog, clock::now_ms, db::Db, protocol::{RObject, RespCodec}, state::ServerRole, State, BUFFER_SIZE};
+
+pub const DEFAULT_REPL_TIMEOUT: Duration = Duration::from_secs(60);
+/// Stream a replica may have queued and not written yet before it is dropped,
+/// redis' hard client-output-buffer-limit for replicas.
+pub const REPLICA_OUTPUT_LIMIT: usize = 256 * 1024 * 1024;
+// the most written to a replica at once, each piece gets the whole repl-timeout
+const WRITE_CHUNK: usize = 64 * 1024;
+// how often a replica waiting for its RDB is sent a newline, so it knows we are still there
+const KEEPALIVE_PERIOD: Duration = Duration::from_secs(1);
+
+/// An RDB being dumped in the background, shared by the replicas it is for.
+pub type PendingRdb = Shared<BoxFuture<'static, Result<Arc<Vec<u8>>, String>>>;
+
+/// What a replica is sent ahead of the stream from the offset it subscribed at.
+pub enum Preamble {
+    /// Sent as is, like +CONTINUE and the part of the stream the replica missed.
+    Ready(Vec<u8>),
+    /// A full resync: the +FULLRESYNC reply, then the RDB once it is dumped.
+    /// Diskless ones end the payload with a mark instead of announcing its length.
+    FullResync { reply: Vec<u8>, rdb: PendingRdb, eof_mark: Option<String> },
+}
+
+/// Where a replica stands in its sync, what INFO reports for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncState {
+    /// Waiting for the RDB of its full resync to be dumped.
+    WaitBgsave,
+    /// Being sent the RDB.
+    SendBulk,
+    /// Streamed the writes as they happen.
+    Online,
+}
+
+impl std::fmt::Display for SyncState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncState::WaitBgsave => write!(f, "wait_bgsave"),
+            SyncState::SendBulk => write!(f, "send_bulk"),
+            SyncState::Online => write!(f, "online"),
+        }
+    }
+}
+
+/// A replica attached with PSYNC.
+///
+/// The stream is queued for a task of its own to write, so a slow replica never holds up the
+/// writes of the master, it is dropped once it falls too far behind.
+pub struct Subscriber {
+    outgoing: mpsc::UnboundedSender<Bytes>,
+    /// Bytes queued for the replica and not written yet.
+    queued: Arc<AtomicUsize>,
+    writer: JoinHandle<()>,
+    pub ip: IpAddr,
+    /// The port the replica listens on, 0 if it never announced one.
+    pub port: u16,
+    /// Last offset the replica acknowledged with REPLCONF ACK.
+    pub acked_offset: Arc<AtomicUsize>,
+    /// Unix milliseconds of the last acknowledgement.
+    pub acked_at: Arc<AtomicU64>,
+    /// Set once the connection failed either way.
+    closed: Arc<AtomicBool>,
+    /// A `SyncState` as its index, the writer moves it along.
+    sync_state: Arc<AtomicU8>,
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        // whatever is still queued is lost anyway, the replica resyncs once it reconnects
+        self.writer.abort();
+    }
+}
+
+impl Subscriber {
+    /// Seconds since the replica last acknowledged anything.
+    pub fn lag(&self) -> u64 {
+        now_ms().saturating_sub(self.acked_at.load(Ordering::SeqCst)) / 1000
+    }
+
+    pub fn sync_state(&self) -> SyncState {
+        match self.sync_state.load(Ordering::SeqCst) {
+            0 => SyncState::WaitBgsave,
+            1 => SyncState::SendBulk,
+            _ => SyncState::Online,
+        }
+    }
+}
 
 pub struct Broadcaster {
-    pub subscribers: Vec<Arc<Mutex<TcpStream>>>,
-    pub broadcasted: usize,
+    pub subscribers: Vec<Subscriber>,
+    /// Everything broadcast recently, its offset is the master_repl_offset.
+    pub backlog: Backlog,
+    /// Every propagated write is also appended here when appendonly is on.
+    pub aof: Option<AofWriter>,
+    /// Woken whenever a replica acknowledges an offset, what WAIT sleeps on.
+    pub acks: Arc<Notify>,
+    /// Replicas waiting for the next diskless full resync, with their listening ports.
+    pub diskless_waiting: Vec<(TcpStream, Option<u16>)>,
+    /// How long a write to a replica may stall before the replica is dropped, the repl-timeout.
+    pub repl_timeout: Duration,
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        Broadcaster {
+            subscribers: vec![],
+            backlog: Backlog::default(),
+            aof: None,
+            acks: Arc::new(Notify::new()),
+            diskless_waiting: vec![],
+            repl_timeout: DEFAULT_REPL_TIMEOUT,
+        }
+    }
 }
 
 impl Broadcaster {
 
-    pub fn subscribe(&mut self, target: TcpStream) {
-        self.subscribers.push(Arc::new(Mutex::new(target)));
-    }
-
-    pub async fn broadcast(&mut self, message: &[u8]) -> Result<(), Error>{
-        let futures = self.subscribers.iter().map(|subscriber| {
-            let subscriber = Arc::clone(subscriber);
-            let message = message.to_vec();
-            tokio::spawn(async move {
-                let mut subscriber = subscriber.lock().await;
-                subscriber.flush().await.expect("Failed to flush subscriber");
-                subscriber.write_all(&message).await.expect(
-                    "Failed to write to subscriber"
-                );
-            })
-        }).collect::<Vec<_>>();
-
-        self.broadcasted += message.len();
-
-        join_all(futures).await;
-
-        Ok(())
-    }
-
-    pub fn ask_ack(&mut self, wait_time: Duration) -> Vec<tokio::task::JoinHandle<Option<usize>>> {
-        // sends replconf GETACK * to all subscribers
-        let futures = self.subscribers.iter().map(|subscriber| {
-            let subscriber = Arc::clone(subscriber);
-            tokio::spawn(async move {
-                let mut subscriber = subscriber.lock().await;
-
-                // subscriber.write_all(
-                //     RObject::Array(
-                //         vec![
-                //             RObject::BulkString("replconf".to_string()),
-                //             RObject::BulkString("GETACK".to_string()),
-                //             RObject::BulkString("*".to_string()),
-                //         ]
-                //     ).to_string().as_bytes()
-                // ).await.expect("Failed to write to subscriber");
-
-                let timer = timeout(wait_time, async {
-                    subscriber.write_all(
-                        RObject::Array(
-                            vec![
-                                RObject::BulkString("replconf".to_string()),
-                                RObject::BulkString("GETACK".to_string()),
-                                RObject::BulkString("*".to_string()),
-                            ]
-                        ).to_string().as_bytes()
-                    ).await.expect("Failed to write to subscriber");
-                });
-
-                match timer.await {
-                    Ok(_) => {},
-                    Err(_) => {
-                        eprintln!("Failed to write to subscriber");
-                        return None;
-                    }
-                }
+    pub fn new(backlog_size: usize, repl_timeout: Duration) -> Self {
+        Broadcaster { backlog: Backlog::new(backlog_size), repl_timeout, ..Default::default() }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.backlog.offset()
+    }
 
-                let mut buffer = [0; BUFFER_SIZE];
-                let s = subscriber.read(&mut buffer).await.unwrap_or(0);
+    /// Starts streaming to a replica, its acknowledgements are read in the background.
+    ///
+    /// Everything broadcast from now on is queued behind the preamble, which may take a while
+    /// to send, so nothing is missed however long a full resync takes.
+    pub fn subscribe(&mut self, target: TcpStream, listening_port: Option<u16>, preamble: Preamble) {
+        let ip = target.peer_addr().map(|addr| addr.ip()).unwrap_or(IpAddr::from([0, 0, 0, 0]));
+        let (reader, writer) = target.into_split();
+        let acked_offset = Arc::new(AtomicUsize::new(0));
+        let acked_at = Arc::new(AtomicU64::new(now_ms()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let queued = Arc::new(AtomicUsize::new(0));
+        let sync_state = Arc::new(AtomicU8::new(match preamble {
+            Preamble::Ready(_) => SyncState::Online,
+            Preamble::FullResync { .. } => SyncState::WaitBgsave,
+        } as u8));
+        let (outgoing, pending) = mpsc::unbounded_channel();
+        tokio::spawn(read_acks(reader, Arc::clone(&acked_offset), Arc::clone(&acked_at), Arc::clone(&closed), Arc::clone(&self.acks)));
+        let writer = tokio::spawn(write_stream(writer, preamble, pending, Arc::clone(&queued), Arc::clone(&closed), Arc::clone(&sync_state), self.repl_timeout));
+        self.subscribers.push(Subscriber {
+            outgoing,
+            queued,
+            writer,
+            ip,
+            port: listening_port.unwrap_or(0),
+            acked_offset,
+            acked_at,
+            closed,
+            sync_state,
+        });
+    }
 
-                if s == 0 {
-                    eprintln!("No content read from subscriber");
-                    return None;
-                }
+    /// How many replicas acknowledged at least `offset`.
+    pub fn acked_replicas(&self, offset: usize) -> usize {
+        self.subscribers.iter()
+            .filter(|subscriber| subscriber.acked_offset.load(Ordering::SeqCst) >= offset)
+            .count()
+    }
 
-                let (parsed, consumed) = RObject::decode(
-                    &String::from_utf8_lossy(&buffer[..s]).to_string(),
-                    0
-                ).expect("Failed to parse response");
-
-                if consumed != s {
-                    eprintln!("Failed to consume all bytes");
-                    eprintln!(
-                        "Consumed: {}, Buffer: {}",
-                        consumed,
-                        s
-                    );
-                }
+    /// How many replicas acknowledged something in the last `max_lag` seconds.
+    pub fn good_replicas(&self, max_lag: u64) -> usize {
+        self.subscribers.iter()
+            .filter(|subscriber| subscriber.lag() <= max_lag)
+            .count()
+    }
+
+    /// Appends a write to the AOF and streams it to the replicas.
+    pub fn broadcast(&mut self, message: &[u8]) {
+        self.append_aof(message);
+        self.feed_replicas(message);
+    }
+
+    /// Sends out the writes queued in the keyspace, in the order they were made. A replica only
+    /// logs them, its own replicas get the stream of its master instead.
+    pub fn propagate(&mut self, storage: &mut Db, replica: bool) {
+        for command in storage.take_propagated() {
+            let message = RObject::Array(command).encode();
+            if replica {
+                self.append_aof(&message);
+            } else {
+                self.broadcast(&message);
+            }
+        }
+    }
+
+    pub fn append_aof(&mut self, message: &[u8]) {
+        if let Some(aof) = self.aof.as_mut() {
+            if let Err(e) = aof.append(message) {
+                eprintln!("Failed to write to the AOF: {}", e);
+            }
+        }
+    }
+
+    /// Streams to the replicas and the backlog only. A replica passes the stream of its
+    /// own master through here untouched, so offsets agree all along a chain.
+    pub fn feed_replicas(&mut self, message: &[u8]) {
+        self.backlog.feed(message);
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let message = Bytes::copy_from_slice(message);
+        // a replica that can't keep up is dropped, it resyncs once it reconnects
+        self.subscribers.retain(|subscriber| {
+            if subscriber.closed.load(Ordering::SeqCst) {
+                return false;
+            }
+            let queued = subscriber.queued.fetch_add(message.len(), Ordering::SeqCst) + message.len();
+            if queued > REPLICA_OUTPUT_LIMIT {
+                eprintln!("Dropping replica {}:{}: {} bytes of output buffer over the limit", subscriber.ip, subscriber.port, queued);
+                return false;
+            }
+            subscriber.outgoing.send(message.clone()).is_ok()
+        });
+    }
 
-                
-                if let RObject::Array(a) = parsed {
-                    match {
-                        a.get(2).expect("Failed to get second element")
-                    } {
-                        RObject::BulkString(s) => {
-                            return Some(s.parse::<usize>().expect("Failed to parse integer"))
-                        },
-                        _ => {
-                            eprintln!("Failed to parse integer");
-                            return None
-                        }
-                    }
-                } else {
-                    eprintln!("Failed to parse integer");
-                    return None
+    /// Drops the replicas that hung up or stayed silent for longer than the repl-timeout.
+    pub fn remove_timed_out(&mut self) {
+        let limit = self.repl_timeout.as_secs();
+        self.subscribers.retain(|subscriber| {
+            if subscriber.closed.load(Ordering::SeqCst) {
+                eprintln!("Replica {}:{} disconnected", subscriber.ip, subscriber.port);
+                false
+            } else if subscriber.lag() > limit {
+                eprintln!("Replica {}:{} timed out", subscriber.ip, subscriber.port);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Drops every replica, they reconnect and resync on their own.
+    pub fn disconnect_replicas(&mut self) {
+        self.subscribers.clear();
+    }
+}
+
+// writes what is queued for a replica, giving up on it once a write stalls for the repl-timeout
+async fn write_stream(
+    mut writer: OwnedWriteHalf,
+    preamble: Preamble,
+    mut pending: mpsc::UnboundedReceiver<Bytes>,
+    queued: Arc<AtomicUsize>,
+    closed: Arc<AtomicBool>,
+    sync_state: Arc<AtomicU8>,
+    limit: Duration,
+) {
+    if let Err(e) = write_preamble(&mut writer, preamble, &sync_state, limit).await {
+        eprintln!("Failed to sync a replica: {}", e);
+    } else {
+        sync_state.store(SyncState::Online as u8, Ordering::SeqCst);
+        while let Some(message) = pending.recv().await {
+            if let Err(e) = write_within(&mut writer, &message, limit).await {
+                eprintln!("Failed to write to a replica: {}", e);
+                break;
+            }
+            queued.fetch_sub(message.len(), Ordering::SeqCst);
+        }
+    }
+    closed.store(true, Ordering::SeqCst);
+}
+
+async fn write_preamble(writer: &mut OwnedWriteHalf, preamble: Preamble, sync_state: &AtomicU8, limit: Duration) -> std::io::Result<()> {
+    let (reply, mut rdb, eof_mark) = match preamble {
+        Preamble::Ready(bytes) => return write_within(writer, &bytes, limit).await,
+        Preamble::FullResync { reply, rdb, eof_mark } => (reply, rdb, eof_mark),
+    };
+    write_within(writer, &reply, limit).await?;
+    let mut keepalive = interval(KEEPALIVE_PERIOD);
+    keepalive.tick().await;
+    let rdb = loop {
+        tokio::select! {
+            rdb = &mut rdb => break rdb.map_err(std::io::Error::other)?,
+            _ = keepalive.tick() => write_within(writer, b"\n", limit).await?,
+        }
+    };
+    sync_state.store(SyncState::SendBulk as u8, Ordering::SeqCst);
+    match eof_mark {
+        // $EOF:<mark>\r\n<rdb><mark>
+        Some(mark) => {
+            write_within(writer, format!("$EOF:{}\r\n", mark).as_bytes(), limit).await?;
+            write_within(writer, &rdb, limit).await?;
+            write_within(writer, mark.as_bytes(), limit).await
+        },
+        // $<length>\r\n<rdb>, without the trailing \r\n
+        None => {
+            write_within(writer, format!("${}\r\n", rdb.len()).as_bytes(), limit).await?;
+            write_within(writer, &rdb, limit).await
+        },
+    }
+}
+
+// a large payload may take long on a slow link, only a write that makes no progress for the
+// whole limit counts as stalled
+async fn write_within(writer: &mut OwnedWriteHalf, data: &[u8], limit: Duration) -> std::io::Result<()> {
+    for chunk in data.chunks(WRITE_CHUNK) {
+        timeout(limit, writer.write_all(chunk)).await.map_err(|_| std::io::Error::from(ErrorKind::TimedOut))??;
+    }
+    Ok(())
+}
+
+// the only thing a replica sends back is REPLCONF ACK <offset>
+async fn read_acks(reader: OwnedReadHalf, acked_offset: Arc<AtomicUsize>, acked_at: Arc<AtomicU64>, closed: Arc<AtomicBool>, acks: Arc<Notify>) {
+    read_acks_until_closed(reader, &acked_offset, &acked_at, &acks).await;
+    closed.store(true, Ordering::SeqCst);
+}
+
+async fn read_acks_until_closed(mut reader: OwnedReadHalf, acked_offset: &AtomicUsize, acked_at: &AtomicU64, acks: &Notify) {
+    let mut codec = RespCodec::new(BUFFER_SIZE, 16);
+    let mut buf = [0; BUFFER_SIZE];
+    loop {
+        let s = match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(s) => s,
+        };
+        codec.extend(&buf[..s]);
+        loop {
+            let args = match codec.next_frame() {
+                Ok(Some((RObject::Array(args), _))) => args,
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Invalid data from replica: {}", e);
+                    return;
                 }
-            })
-        }).collect::<Vec<_>>();
-        
-        futures
+            };
+            let is_ack = args.len() == 3
+                && args[0].as_str().is_some_and(|s| s.eq_ignore_ascii_case("replconf"))
+                && args[1].as_str().is_some_and(|s| s.eq_ignore_ascii_case("ack"));
+            if let Some(offset) = args.get(2).and_then(RObject::as_str).and_then(|s| s.parse::<usize>().ok()).filter(|_| is_ack) {
+                acked_offset.fetch_max(offset, Ordering::SeqCst);
+                acked_at.store(now_ms(), Ordering::SeqCst);
+                acks.notify_waiters();
+            }
+        }
+    }
+}
+
+/// Once a second, drops dead replicas and, on a master, pings the replicas every
+/// repl-ping-replica-period seconds so they can tell a quiet master from a dead one.
+pub async fn replication_cron(state: Arc<RwLock<State>>, broadcaster: Arc<RwLock<Broadcaster>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut ticks: u64 = 0;
+    loop {
+        interval.tick().await;
+        ticks += 1;
+        let (master, ping_period) = {
+            let state = state.read().await;
+            (state.role == ServerRole::Master, state.repl_ping_replica_period.max(1))
+        };
+
+        let mut broadcaster = broadcaster.write().await;
+        broadcaster.remove_timed_out();
+        // replicas pass on the pings of their own master instead
+        if master && !broadcaster.subscribers.is_empty() && ticks.is_multiple_of(ping_period) {
+            let ping = RObject::Array(vec![RObject::BulkString("PING".into())]);
+            broadcaster.feed_replicas(&ping.encode());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use tokio::{net::TcpListener, sync::oneshot};
+
+    use super::*;
+
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (server, client)
+    }
+
+    async fn wait_for(subscriber: &Subscriber, state: SyncState) {
+        timeout(Duration::from_secs(5), async {
+            while subscriber.sync_state() != state {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap_or_else(|_| panic!("replica never got to {}", state));
+    }
+
+    #[tokio::test]
+    async fn moves_a_full_resync_along_to_online() {
+        let mut broadcaster = Broadcaster::default();
+        let (server, mut client) = connected_pair().await;
+        let (dumped, rdb) = oneshot::channel::<Arc<Vec<u8>>>();
+        let rdb: PendingRdb = async move { rdb.await.map_err(|e| e.to_string()) }.boxed().shared();
+        broadcaster.subscribe(server, Some(6380), Preamble::FullResync { reply: b"+FULLRESYNC\r\n".to_vec(), rdb, eof_mark: None });
+        assert_eq!(broadcaster.subscribers[0].sync_state(), SyncState::WaitBgsave);
+
+        dumped.send(Arc::new(b"REDIS".to_vec())).unwrap();
+        wait_for(&broadcaster.subscribers[0], SyncState::Online).await;
+        let mut received = vec![0; b"+FULLRESYNC\r\n$5\r\nREDIS".len()];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, b"+FULLRESYNC\r\n$5\r\nREDIS");
+    }
+
+    #[tokio::test]
+    async fn a_partial_resync_is_online_right_away() {
+        let mut broadcaster = Broadcaster::default();
+        let (server, _client) = connected_pair().await;
+        broadcaster.subscribe(server, None, Preamble::Ready(b"+CONTINUE\r\n".to_vec()));
+        assert_eq!(broadcaster.subscribers[0].sync_state(), SyncState::Online);
+        assert_eq!(broadcaster.subscribers[0].port, 0);
+    }
+
+    #[tokio::test]
+    async fn ends_a_diskless_payload_with_its_mark() {
+        let mut broadcaster = Broadcaster::default();
+        let (server, mut client) = connected_pair().await;
+        let rdb: PendingRdb = async { Ok(Arc::new(b"REDIS".to_vec())) }.boxed().shared();
+        let mark = "m".repeat(40);
+        broadcaster.subscribe(server, None, Preamble::FullResync { reply: b"+FULLRESYNC\r\n".to_vec(), rdb, eof_mark: Some(mark.clone()) });
+        broadcaster.feed_replicas(b"*1\r\n$4\r\nPING\r\n");
+
+        let expected = format!("+FULLRESYNC\r\n$EOF:{}\r\nREDIS{}*1\r\n$4\r\nPING\r\n", mark, mark);
+        let mut received = vec![0; expected.len()];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(String::from_utf8(received).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn drops_silent_and_disconnected_replicas() {
+        let mut broadcaster = Broadcaster::new(1024, Duration::from_secs(60));
+        let (silent, _silent_client) = connected_pair().await;
+        let (gone, gone_client) = connected_pair().await;
+        let (healthy, _healthy_client) = connected_pair().await;
+        broadcaster.subscribe(silent, Some(1), Preamble::Ready(vec![]));
+        broadcaster.subscribe(gone, Some(2), Preamble::Ready(vec![]));
+        broadcaster.subscribe(healthy, Some(3), Preamble::Ready(vec![]));
+
+        broadcaster.subscribers[0].acked_at.store(now_ms() - 61_000, Ordering::SeqCst);
+        drop(gone_client);
+        timeout(Duration::from_secs(5), async {
+            while !broadcaster.subscribers[1].closed.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+
+        broadcaster.remove_timed_out();
+        assert_eq!(broadcaster.subscribers.iter().map(|subscriber| subscriber.port).collect::<Vec<_>>(), [3]);
     }
 }
 
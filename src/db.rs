@@ -0,0 +1,326 @@
+use std::{collections::HashMap, sync::atomic::{AtomicU64, AtomicU8, Ordering}};
+
+use bytes::Bytes;
+
+use crate::{blocking::Blocked, clock::now_ms, protocol::RObject, value::Value};
+
+// redis' LFU defaults: new keys start at 5, lfu-log-factor 10, lfu-decay-time 1 minute
+const LFU_INIT_VAL: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+const LFU_DECAY_MS: u64 = 60 * 1000;
+
+#[derive(Clone)]
+pub struct Entry {
+    pub value: Value,
+    /// Absolute deadline in unix milliseconds.
+    pub expires_at: Option<u64>,
+    pub access: Access,
+}
+
+impl Entry {
+    pub fn new(value: Value, expires_at: Option<u64>) -> Self {
+        Entry { value, expires_at, access: Access::new() }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|deadline| deadline <= now)
+    }
+}
+
+/// When a key was last used and how often, what OBJECT IDLETIME and OBJECT FREQ report.
+///
+/// Reads only hold the keyspace shared, so these are atomics.
+pub struct Access {
+    /// Unix milliseconds of the last access.
+    last: AtomicU64,
+    /// Logarithmic access counter, decaying while the key is idle.
+    counter: AtomicU8,
+}
+
+impl Clone for Access {
+    fn clone(&self) -> Self {
+        Access {
+            last: AtomicU64::new(self.last.load(Ordering::Relaxed)),
+            counter: AtomicU8::new(self.counter.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Access {
+
+    fn new() -> Self {
+        Access { last: AtomicU64::new(now_ms()), counter: AtomicU8::new(LFU_INIT_VAL) }
+    }
+
+    pub fn idle_seconds(&self) -> u64 {
+        now_ms().saturating_sub(self.last.load(Ordering::Relaxed)) / 1000
+    }
+
+    /// The counter after the decay for the time the key sat idle.
+    pub fn frequency(&self) -> u8 {
+        let idle = now_ms().saturating_sub(self.last.load(Ordering::Relaxed));
+        let periods = (idle / LFU_DECAY_MS).min(u8::MAX as u64) as u8;
+        self.counter.load(Ordering::Relaxed).saturating_sub(periods)
+    }
+
+    fn touch(&self) {
+        let mut counter = self.frequency();
+        // the more accesses a key had, the less likely another one counts
+        if counter < u8::MAX {
+            let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+            if lfu_random() < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+                counter += 1;
+            }
+        }
+        self.counter.store(counter, Ordering::Relaxed);
+        self.last.store(now_ms(), Ordering::Relaxed);
+    }
+}
+
+fn lfu_random() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// xorshift64 shared by the whole server, for everything picked at random: HRANDFIELD, the
+/// keys the expire cycle samples and the LFU counter. Concurrent updates losing a step don't
+/// matter.
+pub fn random_u64() -> u64 {
+    static STATE: AtomicU64 = AtomicU64::new(0x2545_f491_4f6c_dd1d);
+    let mut x = STATE.load(Ordering::Relaxed);
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    STATE.store(x, Ordering::Relaxed);
+    x
+}
+
+/// The keyspace. Keys with a deadline are additionally indexed in `volatile`
+/// so the active expire cycle can sample them without walking every key.
+pub struct Db {
+    entries: HashMap<Bytes, Entry>,
+    volatile: Vec<Bytes>,
+    volatile_index: HashMap<Bytes, usize>,
+    /// Changes since the last successful save, what the `save` triggers count.
+    pub dirty: u64,
+    /// Clients waiting for elements in a list.
+    pub blocked: Blocked,
+    // commands replicating the changes above, queued under the same lock as the changes so
+    // they reach the replicas and the AOF in the order the keyspace saw them
+    propagated: Vec<Vec<RObject>>,
+}
+
+impl Default for Db {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Db {
+
+    pub fn new() -> Self {
+        Db {
+            entries: HashMap::new(),
+            volatile: vec![],
+            volatile_index: HashMap::new(),
+            dirty: 0,
+            blocked: Blocked::default(),
+            propagated: vec![],
+        }
+    }
+
+    /// Number of keys, including the ones that expired but were not collected yet.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Looks a key up, treating logically expired keys as missing.
+    /// Doesn't count as an access, like redis' lookups with NOTOUCH.
+    pub fn get_entry(&self, key: &[u8]) -> Option<&Entry> {
+        self.entries.get(key).filter(|entry| !entry.is_expired(now_ms()))
+    }
+
+    /// The value of a key, recording the access.
+    pub fn get(&self, key: &[u8]) -> Option<&Value> {
+        let entry = self.get_entry(key)?;
+        entry.access.touch();
+        Some(&entry.value)
+    }
+
+    /// The value of a key for a command changing it in place, recording the access.
+    /// The caller counts its changes in `dirty`.
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut Value> {
+        let entry = self.entries.get_mut(key).filter(|entry| !entry.is_expired(now_ms()))?;
+        entry.access.touch();
+        Some(&mut entry.value)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get_entry(key).is_some()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Bytes> {
+        let now = now_ms();
+        self.entries.iter()
+            .filter(move |(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key)
+    }
+
+    /// Copies the live keys out, the elements themselves share their buffers.
+    pub fn snapshot(&self) -> Vec<(Bytes, Entry)> {
+        let now = now_ms();
+        self.entries.iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect()
+    }
+
+    /// Stores a value and drops any deadline the key had, like a plain SET.
+    pub fn insert(&mut self, key: Bytes, value: Value) {
+        self.insert_with_expiry(key, value, None);
+    }
+
+    pub fn insert_with_expiry(&mut self, key: Bytes, value: Value, expires_at: Option<u64>) {
+        self.set_volatile(&key, expires_at.is_some());
+        self.entries.insert(key, Entry::new(value, expires_at));
+        self.dirty += 1;
+    }
+
+    /// Stores a value and keeps the deadline of the previous one, for SET KEEPTTL.
+    pub fn insert_keep_ttl(&mut self, key: Bytes, value: Value) {
+        let expires_at = self.get_entry(&key).and_then(|entry| entry.expires_at);
+        self.insert_with_expiry(key, value, expires_at);
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.set_volatile(key, false);
+        self.dirty += 1;
+        Some(entry)
+    }
+
+    /// Changes the deadline of an existing key, returns false if there is no such key.
+    pub fn set_expiry(&mut self, key: &[u8], expires_at: Option<u64>) -> bool {
+        let now = now_ms();
+        match self.entries.get_mut(key) {
+            Some(entry) if !entry.is_expired(now) => {
+                entry.expires_at = expires_at;
+            },
+            _ => return false,
+        }
+        self.set_volatile(key, expires_at.is_some());
+        self.dirty += 1;
+        true
+    }
+
+    /// Deletes the key if its deadline has passed, returns whether it did.
+    pub fn remove_if_expired(&mut self, key: &[u8]) -> bool {
+        match self.entries.get(key) {
+            Some(entry) if entry.is_expired(now_ms()) => {
+                self.remove(key);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Checks up to `count` random keys with a deadline and deletes the expired ones.
+    /// Returns the deleted keys and how many keys were checked.
+    pub fn expire_sample(&mut self, count: usize) -> (Vec<Bytes>, usize) {
+        let now = now_ms();
+        let mut expired = vec![];
+        let checks = count.min(self.volatile.len());
+        for _ in 0..checks {
+            if self.volatile.is_empty() {
+                break;
+            }
+            let index = (random_u64() % self.volatile.len() as u64) as usize;
+            let key = self.volatile[index].clone();
+            if self.entries.get(&key).is_some_and(|entry| entry.is_expired(now)) {
+                self.remove(&key);
+                expired.push(key);
+            }
+        }
+        (expired, checks)
+    }
+
+    fn set_volatile(&mut self, key: &[u8], volatile: bool) {
+        match (self.volatile_index.get(key).copied(), volatile) {
+            (None, true) => {
+                let key = Bytes::copy_from_slice(key);
+                self.volatile_index.insert(key.clone(), self.volatile.len());
+                self.volatile.push(key);
+            },
+            (Some(index), false) => {
+                self.volatile_index.remove(key);
+                self.volatile.swap_remove(index);
+                if let Some(moved) = self.volatile.get(index) {
+                    self.volatile_index.insert(moved.clone(), index);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Queues the command that replicates a change just made.
+    pub fn propagate(&mut self, command: Vec<RObject>) {
+        self.propagated.push(command);
+    }
+
+    /// The commands queued since the last call, oldest first.
+    pub fn take_propagated(&mut self) -> Vec<Vec<RObject>> {
+        std::mem::take(&mut self.propagated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value() -> Value {
+        Value::String(Bytes::from("v"))
+    }
+
+    #[test]
+    fn expired_keys_read_as_missing() {
+        let mut db = Db::new();
+        db.insert_with_expiry(Bytes::from("gone"), value(), Some(1));
+        db.insert_with_expiry(Bytes::from("live"), value(), Some(now_ms() + 100_000));
+        assert!(db.get(b"gone").is_none());
+        assert!(!db.set_expiry(b"gone", None));
+        assert!(db.get(b"live").is_some());
+        assert!(db.remove_if_expired(b"gone"));
+        assert!(!db.remove_if_expired(b"live"));
+        assert_eq!(db.len(), 1);
+    }
+
+    #[test]
+    fn the_expire_cycle_only_samples_volatile_keys() {
+        let mut db = Db::new();
+        for n in 0..100 {
+            db.insert(Bytes::from(format!("plain:{}", n)), value());
+            db.insert_with_expiry(Bytes::from(format!("gone:{}", n)), value(), Some(1));
+        }
+        let mut expired = vec![];
+        while expired.len() < 100 {
+            let (keys, checked) = db.expire_sample(20);
+            assert!(checked <= 20 && checked > 0);
+            expired.extend(keys);
+        }
+        assert!(expired.iter().all(|key| key.starts_with(b"gone:")));
+        assert_eq!(db.expire_sample(20), (vec![], 0));
+        assert_eq!(db.len(), 100);
+    }
+
+    #[test]
+    fn plain_writes_drop_the_deadline() {
+        let mut db = Db::new();
+        db.insert_with_expiry(Bytes::from("k"), value(), Some(1));
+        db.insert(Bytes::from("k"), value());
+        assert_eq!(db.get_entry(b"k").unwrap().expires_at, None);
+        assert_eq!(db.expire_sample(20), (vec![], 0));
+    }
+}
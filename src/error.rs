@@ -0,0 +1,47 @@
+use thiserror::Error;
+
+use crate::protocol::RObject;
+
+/// Errors a command can fail with. Everything but `Io` is reported to the client
+/// as a simple error and the connection stays open.
+#[derive(Debug, Error)]
+pub enum RedisError {
+    #[error("ERR {0}")]
+    Err(String),
+    #[error("ERR unknown command '{0}', with args beginning with: {1}")]
+    UnknownCommand(String, String),
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR value is not a valid float")]
+    NotFloat,
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("NOAUTH Authentication required.")]
+    NoAuth,
+    #[error("WRONGPASS invalid username-password pair or user is disabled.")]
+    WrongPass,
+    #[error("NOPROTO unsupported protocol version")]
+    NoProto,
+    #[error("READONLY You can't write against a read only replica.")]
+    ReadOnly,
+    #[error("NOREPLICAS Not enough good replicas to write.")]
+    NoReplicas,
+    #[error("NOMASTERLINK Can't SYNC while not connected with my master")]
+    NoMasterLink,
+    #[error("UNBLOCKED force unblock from blocking operation, instance state changed (master -> replica?)")]
+    Unblocked,
+    #[error("ERR {0}")]
+    Internal(#[from] anyhow::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl RedisError {
+    pub fn to_robject(&self) -> RObject {
+        RObject::SimpleError(self.to_string())
+    }
+}
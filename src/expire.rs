@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use tokio::{sync::RwLock, time::{Duration, Instant}};
+
+use crate::{broadcast::Broadcaster, db::Db, protocol::RObject, state::ServerRole, State};
+
+// how often the cycle runs, redis' default hz of 10
+const CYCLE_PERIOD: Duration = Duration::from_millis(100);
+// keys checked per round
+const KEYS_PER_LOOP: usize = 20;
+// keep sampling while more than this share of the checked keys had expired
+const ACCEPTABLE_STALE_PERCENT: usize = 10;
+// never hold the keyspace for longer than this in one cycle
+const TIME_LIMIT: Duration = Duration::from_millis(25);
+
+/// Periodically deletes expired keys that nobody reads, redis' active expire cycle.
+///
+/// Only a master expires keys, replicas wait for the DELs it replicates.
+pub async fn active_expire_cycle(
+    storage: Arc<RwLock<Db>>,
+    state: Arc<RwLock<State>>,
+    broadcaster: Arc<RwLock<Broadcaster>>,
+) {
+    let mut interval = tokio::time::interval(CYCLE_PERIOD);
+    loop {
+        interval.tick().await;
+        // the DELs go out before anything else can touch the keyspace
+        let mut broadcaster = broadcaster.write().await;
+        if state.read().await.role != ServerRole::Master {
+            continue;
+        }
+        let mut storage = storage.write().await;
+        let start = Instant::now();
+        loop {
+            let (deleted, checked) = storage.expire_sample(KEYS_PER_LOOP);
+            let stale = deleted.len();
+            for key in deleted {
+                storage.propagate(vec![RObject::BulkString("DEL".into()), RObject::BulkString(key)]);
+            }
+            if checked == 0
+                || stale * 100 <= checked * ACCEPTABLE_STALE_PERCENT
+                || start.elapsed() > TIME_LIMIT {
+                break;
+            }
+        }
+        broadcaster.propagate(&mut storage, false);
+    }
+}
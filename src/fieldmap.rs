@@ -0,0 +1,126 @@
+use std::{collections::{btree_map, hash_map::DefaultHasher, BTreeMap}, hash::{Hash, Hasher}};
+
+use bytes::Bytes;
+
+/// The fields of a hash, ordered by a fixed hash of their name.
+///
+/// That order never changes while fields come and go, so HSCAN can use the position of a
+/// field as its cursor and continue from there without looking at the fields before it.
+#[derive(Clone, Default)]
+pub struct FieldMap {
+    fields: BTreeMap<(u64, Bytes), Bytes>,
+}
+
+/// Where a field falls in the scan order, never 0 since that cursor ends a scan.
+pub fn scan_position(field: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    field.hash(&mut hasher);
+    (hasher.finish() >> 1) + 1
+}
+
+impl FieldMap {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    // the stored key and value of `field`, fields sharing a position are told apart by name
+    fn entry(&self, field: &[u8]) -> Option<(&(u64, Bytes), &Bytes)> {
+        let position = scan_position(field);
+        self.range_from(position)
+            .take_while(|((at, _), _)| *at == position)
+            .find(|((_, name), _)| name.as_ref() == field)
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&Bytes> {
+        self.entry(field).map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, field: &[u8]) -> bool {
+        self.entry(field).is_some()
+    }
+
+    /// Sets `field`, returns the value it replaced.
+    pub fn insert(&mut self, field: Bytes, value: Bytes) -> Option<Bytes> {
+        self.fields.insert((scan_position(&field), field), value)
+    }
+
+    pub fn remove(&mut self, field: &[u8]) -> Option<Bytes> {
+        let (key, _) = self.entry(field)?;
+        let key = key.clone();
+        self.fields.remove(&key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Bytes)> {
+        self.fields.iter().map(|((_, field), value)| (field, value))
+    }
+
+    fn range_from(&self, position: u64) -> btree_map::Range<'_, (u64, Bytes), Bytes> {
+        self.fields.range((position, Bytes::new())..)
+    }
+
+    /// The fields from scan position `position` on, each with its position.
+    pub fn scan_from(&self, position: u64) -> impl Iterator<Item = (u64, &Bytes, &Bytes)> {
+        self.range_from(position).map(|((position, field), value)| (*position, field, value))
+    }
+}
+
+impl FromIterator<(Bytes, Bytes)> for FieldMap {
+    fn from_iter<I: IntoIterator<Item = (Bytes, Bytes)>>(iter: I) -> Self {
+        let mut fields = FieldMap::new();
+        iter.into_iter().for_each(|(field, value)| {
+            fields.insert(field, value);
+        });
+        fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(n: usize) -> Bytes {
+        Bytes::from(format!("field:{}", n))
+    }
+
+    #[test]
+    fn sets_gets_and_removes() {
+        let mut fields = FieldMap::new();
+        assert_eq!(fields.insert(field(1), Bytes::from("a")), None);
+        assert_eq!(fields.insert(field(1), Bytes::from("b")), Some(Bytes::from("a")));
+        assert_eq!(fields.insert(field(2), Bytes::from("c")), None);
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields.get(b"field:1"), Some(&Bytes::from("b")));
+        assert!(fields.contains_key(b"field:2"));
+        assert!(!fields.contains_key(b"field:3"));
+        assert_eq!(fields.remove(b"field:1"), Some(Bytes::from("b")));
+        assert_eq!(fields.remove(b"field:1"), None);
+        assert_eq!(fields.iter().collect::<Vec<_>>(), [(&field(2), &Bytes::from("c"))]);
+    }
+
+    #[test]
+    fn scans_in_a_stable_order() {
+        let mut fields: FieldMap = (0..100).map(|n| (field(n), Bytes::new())).collect();
+        let positions: Vec<u64> = fields.scan_from(0).map(|(position, _, _)| position).collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // what changes elsewhere doesn't move the fields after a cursor
+        let cursor = positions[50];
+        let after: Vec<Bytes> = fields.scan_from(cursor).map(|(_, field, _)| field.clone()).collect();
+        for (_, field, _) in fields.clone().scan_from(0).take(50) {
+            fields.remove(field);
+        }
+        (100..200).filter(|n| scan_position(&field(*n)) < cursor).for_each(|n| {
+            fields.insert(field(n), Bytes::new());
+        });
+        assert_eq!(fields.scan_from(cursor).map(|(_, field, _)| field.clone()).collect::<Vec<_>>(), after);
+    }
+}
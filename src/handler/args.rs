@@ -0,0 +1,31 @@
+use std::str::FromStr;
+
+use bytes::Bytes;
+
+use crate::{error::RedisError, protocol::RObject};
+
+pub(crate) fn command_name(args: &[RObject]) -> String {
+    args.first()
+        .and_then(RObject::as_str)
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+pub(crate) fn bytes_arg(args: &[RObject], index: usize) -> Result<&Bytes, RedisError> {
+    match args.get(index) {
+        Some(RObject::BulkString(s)) => Ok(s),
+        Some(_) => Err(RedisError::Err("Protocol error: expected bulk string argument".to_string())),
+        None => Err(RedisError::WrongArity(command_name(args))),
+    }
+}
+
+pub(crate) fn str_arg(args: &[RObject], index: usize) -> Result<&str, RedisError> {
+    std::str::from_utf8(bytes_arg(args, index)?).map_err(|_| RedisError::Syntax)
+}
+
+pub(crate) fn int_arg<T: FromStr>(args: &[RObject], index: usize) -> Result<T, RedisError> {
+    std::str::from_utf8(bytes_arg(args, index)?)
+        .ok()
+        .and_then(|s| s.parse::<T>().ok())
+        .ok_or(RedisError::NotInteger)
+}
@@ -0,0 +1,11 @@
+use std::sync::Arc;
+
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{aof::bgrewrite, broadcast::Broadcaster, db::Db, error::RedisError, handler::ReplyStream, protocol::RObject, State};
+
+pub async fn handle_bgrewriteaof(stream: &mut ReplyStream, storage: &Arc<RwLock<Db>>, state: &Arc<RwLock<State>>, broadcaster: &Arc<RwLock<Broadcaster>>) -> Result<(), RedisError> {
+    bgrewrite(storage, state, broadcaster).await?;
+    stream.write_all(&RObject::SimpleString("Background append only file rewriting started".to_string()).encode()).await?;
+    Ok(())
+}
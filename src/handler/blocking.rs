@@ -0,0 +1,372 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::{io::AsyncWriteExt, net::tcp::OwnedReadHalf, sync::RwLock, time::{sleep_until, Duration, Instant}};
+
+use crate::{blocking::BlockedOp, db::Db, error::RedisError, handler::{args::{bytes_arg, command_name, int_arg, str_arg}, list::{move_element, parse_end, pop_elements}, ReplyStream}, protocol::RObject, quicklist::End, value::Value};
+
+fn end_name(end: End) -> &'static str {
+    match end {
+        End::Left => "LEFT",
+        End::Right => "RIGHT",
+    }
+}
+
+fn pop_name(end: End) -> &'static str {
+    match end {
+        End::Left => "LPOP",
+        End::Right => "RPOP",
+    }
+}
+
+/// Runs a blocking operation on `key`, `None` while its list is empty.
+/// Returns the reply and the non blocking command replicated in its place.
+fn pop_from(storage: &mut Db, key: &Bytes, op: &BlockedOp) -> Result<Option<(RObject, Vec<RObject>)>, RedisError> {
+    let bulk = |s: &str| RObject::BulkString(Bytes::copy_from_slice(s.as_bytes()));
+    Ok(match op {
+        BlockedOp::Pop(end) => {
+            let element = pop_elements(storage, key, *end, 1)?.and_then(|mut popped| popped.pop());
+            element.map(|element| (
+                RObject::Array(vec![RObject::BulkString(key.clone()), RObject::BulkString(element)]),
+                vec![bulk(pop_name(*end)), RObject::BulkString(key.clone())],
+            ))
+        },
+        BlockedOp::MultiPop { end, count } => {
+            let popped = pop_elements(storage, key, *end, *count)?.filter(|popped| !popped.is_empty());
+            popped.map(|popped| (
+                RObject::Array(vec![
+                    RObject::BulkString(key.clone()),
+                    RObject::Array(popped.iter().cloned().map(RObject::BulkString).collect()),
+                ]),
+                vec![bulk(pop_name(*end)), RObject::BulkString(key.clone()), bulk(&popped.len().to_string())],
+            ))
+        },
+        BlockedOp::Move { destination, from, to } => {
+            let element = move_element(storage, key, destination, *from, *to)?;
+            element.map(|element| (
+                RObject::BulkString(element),
+                vec![
+                    bulk("LMOVE"),
+                    RObject::BulkString(key.clone()),
+                    RObject::BulkString(destination.clone()),
+                    bulk(end_name(*from)),
+                    bulk(end_name(*to)),
+                ],
+            ))
+        },
+    })
+}
+
+/// Serves the clients blocked on the keys that pushes made ready, oldest first, while the
+/// pushing command still holds the keyspace so nobody else gets the elements in between.
+/// The pops are replicated right after that command.
+pub(crate) fn serve_blocked(storage: &mut Db) {
+    while let Some(key) = storage.blocked.next_ready() {
+        while let Some(id) = storage.blocked.first(&key) {
+            let has_elements = matches!(
+                storage.get_entry(&key).map(|entry| &entry.value),
+                Some(Value::List(list)) if !list.is_empty()
+            );
+            if !has_elements {
+                break;
+            }
+            let waiter = storage.blocked.unblock(id).expect("queued clients are registered");
+            if waiter.is_gone() {
+                continue;
+            }
+            match pop_from(storage, &key, &waiter.op) {
+                Ok(Some((reply, command))) => {
+                    storage.propagate(command);
+                    waiter.send(Ok(reply));
+                },
+                // dropping the waiter wakes it with a null reply
+                Ok(None) => {},
+                Err(e) => waiter.send(Err(e)),
+            }
+        }
+    }
+}
+
+// seconds as a float, 0 blocks forever
+fn parse_timeout(args: &[RObject], index: usize) -> Result<Option<Duration>, RedisError> {
+    let timeout = str_arg(args, index)?
+        .parse::<f64>()
+        .ok()
+        .filter(|timeout| timeout.is_finite())
+        .ok_or_else(|| RedisError::Err("timeout is not a float or out of range".to_string()))?;
+    if timeout < 0.0 {
+        return Err(RedisError::Err("timeout is negative".to_string()));
+    }
+    Ok((timeout > 0.0).then(|| Duration::from_secs_f64(timeout)))
+}
+
+// numkeys key [key ...] LEFT | RIGHT [COUNT count]
+fn parse_mpop(args: &[RObject], first: usize) -> Result<(Vec<Bytes>, BlockedOp), RedisError> {
+    let numkeys = int_arg::<i64>(args, first)?;
+    if numkeys <= 0 {
+        return Err(RedisError::Err("numkeys should be greater than 0".to_string()));
+    }
+    let numkeys = numkeys as usize;
+    let end_index = first + 1 + numkeys;
+    if end_index >= args.len() {
+        return Err(RedisError::Syntax);
+    }
+    let keys = (first + 1..end_index)
+        .map(|i| bytes_arg(args, i).cloned())
+        .collect::<Result<Vec<_>, _>>()?;
+    let end = parse_end(args, end_index)?;
+    let count = match args.len() - end_index - 1 {
+        0 => 1,
+        2 if str_arg(args, end_index + 1)?.eq_ignore_ascii_case("COUNT") => {
+            let count = int_arg::<i64>(args, end_index + 2)?;
+            if count <= 0 {
+                return Err(RedisError::Err("count should be greater than 0".to_string()));
+            }
+            count as usize
+        },
+        _ => return Err(RedisError::Syntax),
+    };
+    Ok((keys, BlockedOp::MultiPop { end, count }))
+}
+
+enum Outcome {
+    Served(Result<RObject, RedisError>),
+    TimedOut,
+    Disconnected,
+}
+
+// BLPOP | BRPOP key [key ...] timeout
+// BLMOVE source destination LEFT | RIGHT LEFT | RIGHT timeout
+// BLMPOP timeout numkeys key [key ...] LEFT | RIGHT [COUNT count]
+// LMPOP numkeys key [key ...] LEFT | RIGHT [COUNT count]
+//
+// Without a connection to watch, how the master link and the AOF run them, nothing blocks
+// and an empty list answers like a timeout.
+pub async fn handle_blocking_pop(
+    args: &[RObject],
+    stream: &mut ReplyStream,
+    conn: Option<&mut OwnedReadHalf>,
+    storage: Arc<RwLock<Db>>,
+) -> Result<(), RedisError> {
+    let name = command_name(args);
+    let (keys, op, timeout) = match name.as_str() {
+        "blpop" | "brpop" => {
+            let end = if name == "blpop" { End::Left } else { End::Right };
+            let keys = (1..args.len() - 1)
+                .map(|i| bytes_arg(args, i).cloned())
+                .collect::<Result<Vec<_>, _>>()?;
+            (keys, BlockedOp::Pop(end), parse_timeout(args, args.len() - 1)?)
+        },
+        "blmove" => {
+            let op = BlockedOp::Move { destination: bytes_arg(args, 2)?.clone(), from: parse_end(args, 3)?, to: parse_end(args, 4)? };
+            (vec![bytes_arg(args, 1)?.clone()], op, parse_timeout(args, 5)?)
+        },
+        "blmpop" => {
+            let timeout = parse_timeout(args, 1)?;
+            let (keys, op) = parse_mpop(args, 2)?;
+            (keys, op, timeout)
+        },
+        _ => {
+            let (keys, op) = parse_mpop(args, 1)?;
+            (keys, op, None)
+        },
+    };
+
+    let mut guard = storage.write().await;
+    for key in &keys {
+        if let Some((reply, command)) = pop_from(&mut guard, key, &op)? {
+            guard.propagate(command);
+            // a BLMOVE may have pushed to a list somebody waits on
+            serve_blocked(&mut guard);
+            drop(guard);
+            stream.write_all(&reply.encode()).await?;
+            return Ok(());
+        }
+    }
+    let Some(conn) = conn else {
+        drop(guard);
+        stream.write_all(&RObject::NullArray.encode()).await?;
+        return Ok(());
+    };
+    let (id, mut rx) = guard.blocked.block(keys, op);
+    drop(guard);
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut watch = true;
+    let mut outcome = loop {
+        let mut byte = [0; 1];
+        tokio::select! {
+            served = &mut rx => break served.map(Outcome::Served).unwrap_or(Outcome::TimedOut),
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => break Outcome::TimedOut,
+            peeked = conn.peek(&mut byte), if watch => match peeked {
+                // commands pipelined behind this one wait their turn
+                Ok(n) if n > 0 => watch = false,
+                _ => break Outcome::Disconnected,
+            },
+        }
+    };
+    if !matches!(outcome, Outcome::Served(_)) && storage.write().await.blocked.unblock(id).is_none() {
+        // served while giving up, the elements are ours now
+        if let Ok(served) = rx.try_recv() {
+            outcome = Outcome::Served(served);
+        }
+    }
+
+    match outcome {
+        Outcome::Served(reply) => stream.write_all(&reply?.encode()).await?,
+        Outcome::TimedOut => stream.write_all(&RObject::NullArray.encode()).await?,
+        Outcome::Disconnected => {},
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::handler::handle_push;
+
+    fn command(args: &[&str]) -> Vec<RObject> {
+        args.iter().map(|arg| RObject::BulkString(Bytes::copy_from_slice(arg.as_bytes()))).collect()
+    }
+
+    fn bulk(s: &str) -> RObject {
+        RObject::BulkString(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    // the client end and the server end of a fresh connection
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    async fn push(args: &[&str], storage: &Arc<RwLock<Db>>) {
+        handle_push(&command(args), &mut vec![], Arc::clone(storage)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_waiters_first_come_first_served() {
+        let storage = Arc::new(RwLock::new(Db::new()));
+        let (mut first, mut second, mut third) = {
+            let mut storage = storage.write().await;
+            let first = storage.blocked.block(vec![Bytes::from("l")], BlockedOp::Pop(End::Left)).1;
+            let second = storage.blocked.block(vec![Bytes::from("other"), Bytes::from("l")], BlockedOp::Pop(End::Left)).1;
+            let third = storage.blocked.block(vec![Bytes::from("l")], BlockedOp::Pop(End::Right)).1;
+            (first, second, third)
+        };
+
+        push(&["RPUSH", "l", "a", "b"], &storage).await;
+        assert_eq!(first.try_recv().unwrap().unwrap(), RObject::Array(vec![bulk("l"), bulk("a")]));
+        assert_eq!(second.try_recv().unwrap().unwrap(), RObject::Array(vec![bulk("l"), bulk("b")]));
+        assert!(third.try_recv().is_err());
+
+        push(&["RPUSH", "l", "c"], &storage).await;
+        assert_eq!(third.try_recv().unwrap().unwrap(), RObject::Array(vec![bulk("l"), bulk("c")]));
+
+        // the pops are replicated after the pushes that fed them
+        let propagated = storage.write().await.take_propagated();
+        assert_eq!(propagated, [
+            command(&["RPUSH", "l", "a", "b"]),
+            command(&["LPOP", "l"]),
+            command(&["LPOP", "l"]),
+            command(&["RPUSH", "l", "c"]),
+            command(&["RPOP", "l"]),
+        ]);
+        assert!(!storage.read().await.contains_key(b"l"));
+    }
+
+    #[tokio::test]
+    async fn skips_waiters_that_left() {
+        let storage = Arc::new(RwLock::new(Db::new()));
+        let mut served = {
+            let mut storage = storage.write().await;
+            drop(storage.blocked.block(vec![Bytes::from("l")], BlockedOp::Pop(End::Left)).1);
+            storage.blocked.block(vec![Bytes::from("l")], BlockedOp::Pop(End::Left)).1
+        };
+        push(&["LPUSH", "l", "a"], &storage).await;
+        assert_eq!(served.try_recv().unwrap().unwrap(), RObject::Array(vec![bulk("l"), bulk("a")]));
+    }
+
+    #[tokio::test]
+    async fn blocks_until_a_push() {
+        let storage = Arc::new(RwLock::new(Db::new()));
+        let (_client, server) = connection().await;
+        let (mut reader, _writer) = server.into_split();
+        let waiting = {
+            let storage = Arc::clone(&storage);
+            tokio::spawn(async move {
+                let mut reply = vec![];
+                handle_blocking_pop(&command(&["BLPOP", "a", "b", "0"]), &mut reply, Some(&mut reader), storage).await.unwrap();
+                reply
+            })
+        };
+        while storage.read().await.blocked.first(b"b").is_none() {
+            tokio::task::yield_now().await;
+        }
+
+        push(&["RPUSH", "b", "x"], &storage).await;
+        assert_eq!(waiting.await.unwrap(), RObject::Array(vec![bulk("b"), bulk("x")]).encode());
+        assert_eq!(storage.read().await.blocked.first(b"a"), None);
+    }
+
+    #[tokio::test]
+    async fn times_out_with_a_null_reply() {
+        let storage = Arc::new(RwLock::new(Db::new()));
+        let (_client, server) = connection().await;
+        let (mut reader, _writer) = server.into_split();
+        let started = Instant::now();
+        let mut reply = vec![];
+        handle_blocking_pop(&command(&["BRPOP", "l", "0.05"]), &mut reply, Some(&mut reader), Arc::clone(&storage)).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(reply, RObject::NullArray.encode());
+
+        // a later push stays in the list
+        assert_eq!(storage.read().await.blocked.first(b"l"), None);
+        push(&["RPUSH", "l", "x"], &storage).await;
+        assert!(storage.read().await.contains_key(b"l"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_when_the_client_disconnects() {
+        let storage = Arc::new(RwLock::new(Db::new()));
+        let (client, server) = connection().await;
+        let (mut reader, _writer) = server.into_split();
+        drop(client);
+        let mut reply = vec![];
+        handle_blocking_pop(&command(&["BLMOVE", "l", "d", "LEFT", "RIGHT", "0"]), &mut reply, Some(&mut reader), Arc::clone(&storage)).await.unwrap();
+        assert!(reply.is_empty());
+        assert_eq!(storage.read().await.blocked.first(b"l"), None);
+    }
+
+    #[tokio::test]
+    async fn answers_at_once_without_a_connection() {
+        let storage = Arc::new(RwLock::new(Db::new()));
+        let mut reply = vec![];
+        handle_blocking_pop(&command(&["BLPOP", "l", "0"]), &mut reply, None, Arc::clone(&storage)).await.unwrap();
+        assert_eq!(reply, RObject::NullArray.encode());
+
+        push(&["RPUSH", "l", "a", "b", "c"], &storage).await;
+        let mut reply = vec![];
+        handle_blocking_pop(&command(&["LMPOP", "1", "l", "RIGHT", "COUNT", "2"]), &mut reply, None, Arc::clone(&storage)).await.unwrap();
+        assert_eq!(reply, RObject::Array(vec![bulk("l"), RObject::Array(vec![bulk("c"), bulk("b")])]).encode());
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_timeouts_and_counts() {
+        let storage = Arc::new(RwLock::new(Db::new()));
+        for (args, message) in [
+            (["BLPOP", "l", "-1"].as_slice(), "ERR timeout is negative"),
+            (&["BLPOP", "l", "soon"], "ERR timeout is not a float or out of range"),
+            (&["BLPOP", "l", "inf"], "ERR timeout is not a float or out of range"),
+            (&["BLMPOP", "0", "0", "l", "LEFT"], "ERR numkeys should be greater than 0"),
+            (&["LMPOP", "1", "l", "LEFT", "COUNT", "0"], "ERR count should be greater than 0"),
+            (&["LMPOP", "2", "l", "LEFT"], "ERR syntax error"),
+        ] {
+            let err = handle_blocking_pop(&command(args), &mut vec![], None, Arc::clone(&storage)).await.unwrap_err();
+            assert_eq!(err.to_string(), message, "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use tokio::io::AsyncWriteExt;
+
+use crate::{error::RedisError, handler::{args::str_arg, ReplyStream, command_table::{lookup, COMMAND_TABLE}}, protocol::RObject};
+
+pub async fn handle_command(args: &[RObject], stream: &mut ReplyStream) -> Result<(), RedisError> {
+    if args.len() == 1 {
+        stream.write_all(
+            &RObject::Array(COMMAND_TABLE.iter().map(|spec| spec.info()).collect()).encode()
+        ).await?;
+        return Ok(());
+    }
+
+    let subcommand = str_arg(args, 1)?.to_lowercase();
+    let reply = match subcommand.as_str() {
+        "count" => {
+            if args.len() != 2 {
+                return Err(RedisError::WrongArity("command|count".to_string()));
+            }
+            RObject::Integer(COMMAND_TABLE.len() as i64)
+        },
+        "info" => {
+            if args.len() == 2 {
+                RObject::Array(COMMAND_TABLE.iter().map(|spec| spec.info()).collect())
+            } else {
+                RObject::Array(
+                    args[2..].iter()
+                        .map(|name| match name.as_str().and_then(lookup) {
+                            Some(spec) => spec.info(),
+                            None => RObject::NullArray,
+                        })
+                        .collect()
+                )
+            }
+        },
+        "docs" => {
+            let specs: Vec<_> = if args.len() == 2 {
+                COMMAND_TABLE.iter().collect()
+            } else {
+                args[2..].iter().filter_map(|name| name.as_str().and_then(lookup)).collect()
+            };
+            RObject::Array(
+                specs.into_iter()
+                    .flat_map(|spec| [RObject::BulkString(spec.name.into()), spec.docs()])
+                    .collect()
+            )
+        },
+        "getkeys" => {
+            if args.len() < 3 {
+                return Err(RedisError::WrongArity("command|getkeys".to_string()));
+            }
+            let spec = args[2].as_str()
+                .and_then(lookup)
+                .ok_or_else(|| RedisError::Err("Invalid command specified".to_string()))?;
+            let argc = args.len() - 2;
+            if !spec.check_arity(argc) {
+                return Err(RedisError::Err("Invalid number of arguments specified for command".to_string()));
+            }
+            let keys = spec.key_positions(&args[2..]);
+            if keys.is_empty() {
+                return Err(RedisError::Err("The command has no key arguments".to_string()));
+            }
+            RObject::Array(keys.into_iter().map(|i| args[i + 2].clone()).collect())
+        },
+        _ => return Err(RedisError::Err(format!("unknown subcommand '{}'. Try COMMAND HELP.", str_arg(args, 1)?))),
+    };
+
+    stream.write_all(&reply.encode()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<RObject> {
+        args.iter().map(|arg| RObject::BulkString(Bytes::copy_from_slice(arg.as_bytes()))).collect()
+    }
+
+    async fn getkeys(args: &[&str]) -> Result<RObject, RedisError> {
+        let mut args_with_subcommand = vec!["COMMAND", "GETKEYS"];
+        args_with_subcommand.extend(args);
+        let mut reply = vec![];
+        handle_command(&command(&args_with_subcommand), &mut reply).await?;
+        Ok(RObject::decode(&reply, 0).unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn getkeys_finds_the_key_arguments() {
+        for (args, keys) in [
+            (["SET", "k", "v", "EX", "10"].as_slice(), ["k"].as_slice()),
+            (&["get", "k"], &["k"]),
+            (&["HSET", "h", "f", "v"], &["h"]),
+            (&["DEL", "a", "b", "c"], &["a", "b", "c"]),
+            (&["BLPOP", "a", "b", "0"], &["a", "b"]),
+            (&["LMOVE", "src", "dst", "LEFT", "RIGHT"], &["src", "dst"]),
+            (&["LMPOP", "2", "a", "b", "LEFT", "COUNT", "3"], &["a", "b"]),
+            (&["BLMPOP", "0", "1", "a", "RIGHT"], &["a"]),
+        ] {
+            assert_eq!(getkeys(args).await.unwrap(), RObject::Array(command(keys)), "{:?}", args);
+        }
+    }
+
+    #[tokio::test]
+    async fn getkeys_rejects_what_it_cannot_answer() {
+        for (args, message) in [
+            (["NOSUCH", "k"].as_slice(), "ERR Invalid command specified"),
+            (&["GET"], "ERR Invalid number of arguments specified for command"),
+            (&["GET", "a", "b"], "ERR Invalid number of arguments specified for command"),
+            (&["PING"], "ERR The command has no key arguments"),
+        ] {
+            assert_eq!(getkeys(args).await.unwrap_err().to_string(), message, "{:?}", args);
+        }
+        let err = handle_command(&command(&["COMMAND", "GETKEYS"]), &mut vec![]).await.unwrap_err();
+        assert_eq!(err.to_string(), "ERR wrong number of arguments for 'command|getkeys' command");
+    }
+
+    #[tokio::test]
+    async fn count_matches_the_table() {
+        let mut reply = vec![];
+        handle_command(&command(&["COMMAND", "COUNT"]), &mut reply).await.unwrap();
+        assert_eq!(reply, RObject::Integer(COMMAND_TABLE.len() as i64).encode());
+
+        let mut reply = vec![];
+        handle_command(&command(&["COMMAND", "INFO", "get", "nosuch"]), &mut reply).await.unwrap();
+        match RObject::decode(&reply, 0).unwrap().0 {
+            RObject::Array(items) => {
+                assert_eq!(items[0], lookup("get").unwrap().info());
+                assert_eq!(items[1], RObject::NullArray);
+            },
+            other => panic!("not an array: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use crate::protocol::RObject;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Write,
+    ReadOnly,
+    DenyOom,
+    Admin,
+    NoScript,
+    Blocking,
+    Loading,
+    Stale,
+    Fast,
+}
+
+impl Flag {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Flag::Write => "write",
+            Flag::ReadOnly => "readonly",
+            Flag::DenyOom => "denyoom",
+            Flag::Admin => "admin",
+            Flag::NoScript => "noscript",
+            Flag::Blocking => "blocking",
+            Flag::Loading => "loading",
+            Flag::Stale => "stale",
+            Flag::Fast => "fast",
+        }
+    }
+}
+
+/// Static description of a command, the same information redis keeps in its command table.
+pub struct CommandSpec {
+    /// Lowercase name, matched case-insensitively.
+    pub name: &'static str,
+    /// Exact number of arguments including the name when positive, minimum when negative.
+    pub arity: i64,
+    pub flags: &'static [Flag],
+    /// Position of the first key argument, 0 for commands without keys.
+    pub first_key: i64,
+    /// Position of the last key argument, negative values count from the end.
+    pub last_key: i64,
+    pub step: i64,
+    /// Position of the argument counting the keys right after it, for commands like LMPOP
+    /// whose keys can't be described by the range above, 0 for all others.
+    pub numkeys: i64,
+    pub group: &'static str,
+    pub since: &'static str,
+    pub summary: &'static str,
+}
+
+use Flag::*;
+
+pub static COMMAND_TABLE: &[CommandSpec] = &[
+    CommandSpec { name: "ping", arity: -1, flags: &[Fast, Stale], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "connection", since: "1.0.0", summary: "Returns the server's liveliness response." },
+    CommandSpec { name: "echo", arity: 2, flags: &[Fast, Stale, Loading], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "connection", since: "1.0.0", summary: "Returns the given string." },
+    CommandSpec { name: "hello", arity: -1, flags: &[NoScript, Loading, Stale, Fast], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "connection", since: "6.0.0", summary: "Handshakes with the Redis server." },
+    CommandSpec { name: "command", arity: -1, flags: &[Loading, Stale], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "server", since: "2.8.13", summary: "Returns detailed information about all commands." },
+    CommandSpec { name: "set", arity: -3, flags: &[Write, DenyOom], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "string", since: "1.0.0", summary: "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist." },
+    CommandSpec { name: "get", arity: 2, flags: &[ReadOnly, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "string", since: "1.0.0", summary: "Returns the string value of a key." },
+    CommandSpec { name: "lpush", arity: -3, flags: &[Write, DenyOom, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "1.0.0", summary: "Prepends one or more elements to a list. Creates the key if it doesn't exist." },
+    CommandSpec { name: "rpush", arity: -3, flags: &[Write, DenyOom, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "1.0.0", summary: "Appends one or more elements to a list. Creates the key if it doesn't exist." },
+    CommandSpec { name: "lpushx", arity: -3, flags: &[Write, DenyOom, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "2.2.0", summary: "Prepends one or more elements to a list only when the list exists." },
+    CommandSpec { name: "rpushx", arity: -3, flags: &[Write, DenyOom, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "2.2.0", summary: "Appends an element to a list only when the list exists." },
+    CommandSpec { name: "lpop", arity: -2, flags: &[Write, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "1.0.0", summary: "Returns the first elements in a list after removing it. Deletes the list if the last element was popped." },
+    CommandSpec { name: "rpop", arity: -2, flags: &[Write, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "1.0.0", summary: "Returns and removes the last elements of the list. Deletes the list if the last element was popped." },
+    CommandSpec { name: "llen", arity: 2, flags: &[ReadOnly, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "1.0.0", summary: "Returns the length of a list." },
+    CommandSpec { name: "lrange", arity: 4, flags: &[ReadOnly], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "1.0.0", summary: "Returns a range of elements from a list." },
+    CommandSpec { name: "lindex", arity: 3, flags: &[ReadOnly], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "1.0.0", summary: "Returns an element from a list by its index." },
+    CommandSpec { name: "lset", arity: 4, flags: &[Write, DenyOom], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "1.0.0", summary: "Sets the value of an element in a list by its index." },
+    CommandSpec { name: "lrem", arity: 4, flags: &[Write], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "1.0.0", summary: "Removes elements from a list. Deletes the list if the last element was removed." },
+    CommandSpec { name: "ltrim", arity: 4, flags: &[Write], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "1.0.0", summary: "Removes elements from both ends a list. Deletes the list if all elements were trimmed." },
+    CommandSpec { name: "linsert", arity: 5, flags: &[Write, DenyOom], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "2.2.0", summary: "Inserts an element before or after another element in a list." },
+    CommandSpec { name: "lpos", arity: -3, flags: &[ReadOnly], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "list", since: "6.0.6", summary: "Returns the index of matching elements in a list." },
+    CommandSpec { name: "lmove", arity: 5, flags: &[Write, DenyOom], first_key: 1, last_key: 2, step: 1, numkeys: 0, group: "list", since: "6.2.0", summary: "Returns an element after popping it from one list and pushing it to another. Deletes the list if the last element was moved." },
+    CommandSpec { name: "rpoplpush", arity: 3, flags: &[Write, DenyOom], first_key: 1, last_key: 2, step: 1, numkeys: 0, group: "list", since: "1.2.0", summary: "Returns the last element of a list after removing and pushing it to another list. Deletes the list if the last element was popped." },
+    CommandSpec { name: "lmpop", arity: -4, flags: &[Write], first_key: 0, last_key: 0, step: 0, numkeys: 1, group: "list", since: "7.0.0", summary: "Returns multiple elements from a list after removing them. Deletes the list if the last element was popped." },
+    CommandSpec { name: "blpop", arity: -3, flags: &[Write, Blocking], first_key: 1, last_key: -2, step: 1, numkeys: 0, group: "list", since: "2.0.0", summary: "Removes and returns the first element in a list. Blocks until an element is available otherwise. Deletes the list if the last element was popped." },
+    CommandSpec { name: "brpop", arity: -3, flags: &[Write, Blocking], first_key: 1, last_key: -2, step: 1, numkeys: 0, group: "list", since: "2.0.0", summary: "Removes and returns the last element in a list. Blocks until an element is available otherwise. Deletes the list if the last element was popped." },
+    CommandSpec { name: "blmove", arity: 6, flags: &[Write, DenyOom, Blocking], first_key: 1, last_key: 2, step: 1, numkeys: 0, group: "list", since: "6.2.0", summary: "Pops an element from a list, pushes it to another list and returns it. Blocks until an element is available otherwise. Deletes the list if the last element was moved." },
+    CommandSpec { name: "blmpop", arity: -5, flags: &[Write, Blocking], first_key: 0, last_key: 0, step: 0, numkeys: 2, group: "list", since: "7.0.0", summary: "Pops the first element from one of multiple lists. Blocks until an element is available otherwise. Deletes the list if the last element was popped." },
+    CommandSpec { name: "hset", arity: -4, flags: &[Write, DenyOom, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.0.0", summary: "Creates or modifies the value of a field in a hash." },
+    CommandSpec { name: "hmset", arity: -4, flags: &[Write, DenyOom, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.0.0", summary: "Sets the values of multiple fields." },
+    CommandSpec { name: "hsetnx", arity: 4, flags: &[Write, DenyOom, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.0.0", summary: "Sets the value of a field in a hash only when the field doesn't exist." },
+    CommandSpec { name: "hget", arity: 3, flags: &[ReadOnly, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.0.0", summary: "Returns the value of a field in a hash." },
+    CommandSpec { name: "hmget", arity: -3, flags: &[ReadOnly, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.0.0", summary: "Returns the values of all fields in a hash." },
+    CommandSpec { name: "hdel", arity: -3, flags: &[Write, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.0.0", summary: "Deletes one or more fields and their values from a hash. Deletes the hash if no fields remain." },
+    CommandSpec { name: "hexists", arity: 3, flags: &[ReadOnly, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.0.0", summary: "Determines whether a field exists in a hash." },
+    CommandSpec { name: "hlen", arity: 2, flags: &[ReadOnly, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.0.0", summary: "Returns the number of fields in a hash." },
+    CommandSpec { name: "hstrlen", arity: 3, flags: &[ReadOnly, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "3.2.0", summary: "Returns the length of the value of a field." },
+    CommandSpec { name: "hgetall", arity: 2, flags: &[ReadOnly], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.0.0", summary: "Returns all fields and values in a hash." },
+    CommandSpec { name: "hkeys", arity: 2, flags: &[ReadOnly], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.0.0", summary: "Returns all fields in a hash." },
+    CommandSpec { name: "hvals", arity: 2, flags: &[ReadOnly], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.0.0", summary: "Returns all values in a hash." },
+    CommandSpec { name: "hincrby", arity: 4, flags: &[Write, DenyOom, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.0.0", summary: "Increments the integer value of a field in a hash by a number. Uses 0 as initial value if the field doesn't exist." },
+    CommandSpec { name: "hincrbyfloat", arity: 4, flags: &[Write, DenyOom, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.6.0", summary: "Increments the floating point value of a field by a number. Uses 0 as initial value if the field doesn't exist." },
+    CommandSpec { name: "hrandfield", arity: -2, flags: &[ReadOnly], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "6.2.0", summary: "Returns one or more random fields from a hash." },
+    CommandSpec { name: "hscan", arity: -3, flags: &[ReadOnly], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "hash", since: "2.8.0", summary: "Iterates over fields and values of a hash." },
+    CommandSpec { name: "del", arity: -2, flags: &[Write], first_key: 1, last_key: -1, step: 1, numkeys: 0, group: "generic", since: "1.0.0", summary: "Deletes one or more keys." },
+    CommandSpec { name: "expire", arity: -3, flags: &[Write, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "generic", since: "1.0.0", summary: "Sets the expiration time of a key in seconds." },
+    CommandSpec { name: "pexpire", arity: -3, flags: &[Write, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "generic", since: "2.6.0", summary: "Sets the expiration time of a key in milliseconds." },
+    CommandSpec { name: "expireat", arity: -3, flags: &[Write, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "generic", since: "1.2.0", summary: "Sets the expiration time of a key to a Unix timestamp." },
+    CommandSpec { name: "pexpireat", arity: -3, flags: &[Write, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "generic", since: "2.6.0", summary: "Sets the expiration time of a key to a Unix milliseconds timestamp." },
+    CommandSpec { name: "ttl", arity: 2, flags: &[ReadOnly, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "generic", since: "1.0.0", summary: "Returns the expiration time in seconds of a key." },
+    CommandSpec { name: "pttl", arity: 2, flags: &[ReadOnly, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "generic", since: "2.6.0", summary: "Returns the expiration time in milliseconds of a key." },
+    CommandSpec { name: "expiretime", arity: 2, flags: &[ReadOnly, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "generic", since: "7.0.0", summary: "Returns the expiration time of a key as a Unix timestamp." },
+    CommandSpec { name: "pexpiretime", arity: 2, flags: &[ReadOnly, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "generic", since: "7.0.0", summary: "Returns the expiration time of a key as a Unix milliseconds timestamp." },
+    CommandSpec { name: "persist", arity: 2, flags: &[Write, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "generic", since: "2.2.0", summary: "Removes the expiration time of a key." },
+    CommandSpec { name: "keys", arity: 2, flags: &[ReadOnly], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "generic", since: "1.0.0", summary: "Returns all key names that match a pattern." },
+    CommandSpec { name: "type", arity: 2, flags: &[ReadOnly, Fast], first_key: 1, last_key: 1, step: 1, numkeys: 0, group: "generic", since: "1.0.0", summary: "Determines the type of value stored at a key." },
+    CommandSpec { name: "object", arity: -2, flags: &[ReadOnly], first_key: 2, last_key: 2, step: 1, numkeys: 0, group: "generic", since: "2.2.3", summary: "A container for object introspection commands." },
+    CommandSpec { name: "info", arity: -1, flags: &[Loading, Stale], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "server", since: "1.0.0", summary: "Returns information and statistics about the server." },
+    CommandSpec { name: "save", arity: 1, flags: &[Admin, NoScript], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "server", since: "1.0.0", summary: "Synchronously saves the database(s) to disk." },
+    CommandSpec { name: "bgsave", arity: -1, flags: &[Admin, NoScript], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "server", since: "1.0.0", summary: "Asynchronously saves the database(s) to disk." },
+    CommandSpec { name: "lastsave", arity: 1, flags: &[Fast, Loading, Stale], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "server", since: "1.0.0", summary: "Returns the Unix timestamp of the last successful save to disk." },
+    CommandSpec { name: "bgrewriteaof", arity: 1, flags: &[Admin, NoScript], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "server", since: "1.0.0", summary: "Asynchronously rewrites the append-only file to disk." },
+    CommandSpec { name: "config", arity: -2, flags: &[Admin, NoScript, Loading, Stale], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "server", since: "2.0.0", summary: "A container for server configuration commands." },
+    CommandSpec { name: "replconf", arity: -1, flags: &[Admin, NoScript, Loading, Stale], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "server", since: "3.0.0", summary: "An internal command for configuring the replication stream." },
+    CommandSpec { name: "replicaof", arity: 3, flags: &[Admin, NoScript, Stale], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "server", since: "5.0.0", summary: "Configures a server as replica of another, or promotes it to a master." },
+    CommandSpec { name: "slaveof", arity: 3, flags: &[Admin, NoScript, Stale], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "server", since: "1.0.0", summary: "Sets a Redis server as a replica of another, or promotes it to being a master." },
+    CommandSpec { name: "role", arity: 1, flags: &[NoScript, Loading, Stale, Fast], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "server", since: "2.8.12", summary: "Returns the replication role." },
+    CommandSpec { name: "psync", arity: -3, flags: &[Admin, NoScript], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "server", since: "2.8.0", summary: "An internal command used in replication." },
+    CommandSpec { name: "wait", arity: 3, flags: &[NoScript, Blocking], first_key: 0, last_key: 0, step: 0, numkeys: 0, group: "generic", since: "3.0.0", summary: "Blocks until the asynchronous replication of all preceding write commands sent by the connection is completed." },
+];
+
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMAND_TABLE.iter().find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+impl CommandSpec {
+
+    pub fn check_arity(&self, argc: usize) -> bool {
+        let argc = argc as i64;
+        if self.arity >= 0 {
+            argc == self.arity
+        } else {
+            argc >= -self.arity
+        }
+    }
+
+    pub fn has_flag(&self, flag: Flag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.has_flag(Flag::Write)
+    }
+
+    /// Indices of the key arguments in a full command line, name included.
+    pub fn key_positions(&self, args: &[RObject]) -> Vec<usize> {
+        let argc = args.len();
+        if self.numkeys > 0 {
+            let first = self.numkeys as usize + 1;
+            let count = args.get(self.numkeys as usize)
+                .and_then(RObject::as_str)
+                .and_then(|count| count.parse::<usize>().ok())
+                .unwrap_or(0);
+            return (first..first.saturating_add(count).min(argc)).collect();
+        }
+        if self.first_key <= 0 {
+            return vec![];
+        }
+        let last = if self.last_key < 0 { argc as i64 + self.last_key } else { self.last_key };
+        let mut positions = vec![];
+        let mut i = self.first_key;
+        while i <= last && (i as usize) < argc {
+            positions.push(i as usize);
+            i += self.step.max(1);
+        }
+        positions
+    }
+
+    fn acl_categories(&self) -> Vec<RObject> {
+        let mut categories = vec![];
+        if self.has_flag(Flag::Write) {
+            categories.push("@write");
+        }
+        if self.has_flag(Flag::ReadOnly) {
+            categories.push("@read");
+        }
+        if self.has_flag(Flag::Admin) {
+            categories.push("@admin");
+            categories.push("@dangerous");
+        }
+        if self.has_flag(Flag::Fast) {
+            categories.push("@fast");
+        } else {
+            categories.push("@slow");
+        }
+        if self.has_flag(Flag::Blocking) {
+            categories.push("@blocking");
+        }
+        match self.group {
+            "generic" => categories.push("@keyspace"),
+            "server" => {},
+            group => categories.push(match group {
+                "string" => "@string",
+                "list" => "@list",
+                "hash" => "@hash",
+                "set" => "@set",
+                "sorted-set" => "@sortedset",
+                "stream" => "@stream",
+                "connection" => "@connection",
+                "transactions" => "@transaction",
+                _ => "@slow",
+            }),
+        }
+        categories.dedup();
+        categories.into_iter().map(|c| RObject::SimpleString(c.to_string())).collect()
+    }
+
+    // like redis, commands whose keys are found by looking at the arguments have movablekeys
+    fn flag_names(&self) -> Vec<RObject> {
+        let mut names: Vec<&str> = self.flags.iter().map(Flag::as_str).collect();
+        if self.numkeys > 0 {
+            names.push("movablekeys");
+        }
+        names.into_iter().map(|name| RObject::SimpleString(name.to_string())).collect()
+    }
+
+    fn key_specs(&self) -> Vec<RObject> {
+        let bulk = |s: &'static str| RObject::BulkString(s.into());
+        let access = if self.is_write() { "RW" } else { "RO" };
+        if self.numkeys > 0 {
+            return vec![RObject::Array(vec![
+                bulk("flags"),
+                RObject::Array(vec![RObject::SimpleString(access.to_string())]),
+                bulk("begin_search"),
+                RObject::Array(vec![
+                    bulk("type"), bulk("index"),
+                    bulk("spec"), RObject::Array(vec![bulk("index"), RObject::Integer(self.numkeys)]),
+                ]),
+                bulk("find_keys"),
+                RObject::Array(vec![
+                    bulk("type"), bulk("keynum"),
+                    bulk("spec"), RObject::Array(vec![
+                        bulk("keynumidx"), RObject::Integer(0),
+                        bulk("firstkey"), RObject::Integer(1),
+                        bulk("keystep"), RObject::Integer(1),
+                    ]),
+                ]),
+            ])];
+        }
+        if self.first_key <= 0 {
+            return vec![];
+        }
+        // the range is relative to the first key, a negative last key counts from the end
+        let last_key = if self.last_key < 0 { self.last_key } else { self.last_key - self.first_key };
+        vec![RObject::Array(vec![
+            bulk("flags"),
+            RObject::Array(vec![RObject::SimpleString(access.to_string())]),
+            bulk("begin_search"),
+            RObject::Array(vec![
+                bulk("type"), bulk("index"),
+                bulk("spec"), RObject::Array(vec![bulk("index"), RObject::Integer(self.first_key)]),
+            ]),
+            bulk("find_keys"),
+            RObject::Array(vec![
+                bulk("type"), bulk("range"),
+                bulk("spec"), RObject::Array(vec![
+                    bulk("lastkey"), RObject::Integer(last_key),
+                    bulk("keystep"), RObject::Integer(self.step),
+                    bulk("limit"), RObject::Integer(0),
+                ]),
+            ]),
+        ])]
+    }
+
+    /// The reply entry of COMMAND and COMMAND INFO.
+    pub fn info(&self) -> RObject {
+        RObject::Array(vec![
+            RObject::BulkString(self.name.into()),
+            RObject::Integer(self.arity),
+            RObject::Array(self.flag_names()),
+            RObject::Integer(self.first_key),
+            RObject::Integer(self.last_key),
+            RObject::Integer(self.step),
+            RObject::Array(self.acl_categories()),
+            RObject::Array(vec![]),
+            RObject::Array(self.key_specs()),
+            RObject::Array(vec![]),
+        ])
+    }
+
+    /// The reply entry of COMMAND DOCS.
+    pub fn docs(&self) -> RObject {
+        RObject::Array(vec![
+            RObject::BulkString("summary".into()),
+            RObject::BulkString(self.summary.into()),
+            RObject::BulkString("since".into()),
+            RObject::BulkString(self.since.into()),
+            RObject::BulkString("group".into()),
+            RObject::BulkString(self.group.into()),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<RObject> {
+        args.iter().map(|arg| RObject::BulkString(arg.to_string().into())).collect()
+    }
+
+    fn keys(args: &[&str]) -> Vec<usize> {
+        lookup(args[0]).unwrap().key_positions(&command(args))
+    }
+
+    #[test]
+    fn finds_keys_in_a_range() {
+        assert_eq!(keys(&["GET", "k"]), [1]);
+        assert_eq!(keys(&["LMOVE", "a", "b", "LEFT", "RIGHT"]), [1, 2]);
+        assert_eq!(keys(&["BLPOP", "a", "b", "c", "0"]), [1, 2, 3]);
+        assert_eq!(keys(&["DEL", "a", "b"]), [1, 2]);
+        assert!(keys(&["PING"]).is_empty());
+    }
+
+    #[test]
+    fn finds_keys_after_numkeys() {
+        assert_eq!(keys(&["LMPOP", "2", "a", "b", "LEFT"]), [2, 3]);
+        assert_eq!(keys(&["LMPOP", "1", "a", "LEFT", "COUNT", "2"]), [2]);
+        assert_eq!(keys(&["BLMPOP", "0.5", "2", "a", "b", "RIGHT"]), [3, 4]);
+        // a count past the end or that is no count at all finds what is there
+        assert_eq!(keys(&["LMPOP", "5", "a", "LEFT"]), [2, 3]);
+        assert!(keys(&["LMPOP", "x", "a", "LEFT"]).is_empty());
+    }
+}
@@ -1,53 +1,187 @@
 use std::sync::Arc;
 
-use anyhow::Error;
-use tokio::{io::AsyncWriteExt, net::TcpStream, sync::RwLock};
+use tokio::{io::AsyncWriteExt, sync::RwLock, time::Duration};
 
-use crate::{protocol::RObject, State};
+use crate::{aof::{self, FsyncPolicy}, broadcast::Broadcaster, db::Db, error::RedisError, handler::{args::str_arg, ReplyStream}, protocol::RObject, rdb::save::{format_save_params, parse_save_params}, State};
 
 pub async fn handle_config(
-    args: &Vec<RObject>,
-    stream: &mut TcpStream,
+    args: &[RObject],
+    stream: &mut ReplyStream,
+    storage: Arc<RwLock<Db>>,
     state: Arc<RwLock<State>>,
-) -> Result<(), Error> {
+    broadcaster: Arc<RwLock<Broadcaster>>,
+) -> Result<(), RedisError> {
     // CONFIG GET dir
-    let command = match args.get(1) {
-        Some(RObject::BulkString(s)) => s,
-        _ => return Err(anyhow::anyhow!("Expected BulkString as key")),
-    };
-    let target = match args.get(2) {
-        Some(RObject::BulkString(s)) => s,
-        _ => return Err(anyhow::anyhow!("Expected BulkString as key")),
-    };
-
-    match command.as_str() {
-        "GET" => {
-            match target.as_str() {
-                "dir" => {
-                    stream.write_all(
-                        RObject::Array(
-                            vec![
-                                RObject::BulkString("dir".to_string()),
-                                RObject::BulkString(state.read().await.dir.clone().unwrap_or("".to_string()))
-                            ]
-                        ).to_string().as_bytes()
-                    ).await.expect("Failed to write to stream handling config GET dir.")
-                },
-                "dbfilename" => {
-                    stream.write_all(
-                        RObject::Array(
-                            vec![
-                                RObject::BulkString("dbfilename".to_string()),
-                                RObject::BulkString(state.read().await.dbfilename.clone().unwrap_or("".to_string()))
-                            ]
-                        ).to_string().as_bytes()
-                    ).await.expect("Failed to write to stream handling config GET dbfilename.")
-                },
-                _ => return Err(anyhow::anyhow!("Target not allowed")),
+    let command = str_arg(args, 1)?;
+
+    match command.to_lowercase().as_str() {
+        "get" => {
+            let target = str_arg(args, 2)?.to_lowercase();
+            // unknown parameters are left out of the reply
+            let reply = match config_value(&*state.read().await, &target) {
+                Some(value) => vec![
+                    RObject::BulkString(target.into()),
+                    RObject::BulkString(value.into())
+                ],
+                None => vec![],
+            };
+            stream.write_all(
+                &RObject::Array(reply).encode()
+            ).await?;
+        }
+        // CONFIG SET parameter value [parameter value ...]
+        "set" => {
+            let pairs = args[2..].chunks_exact(2);
+            if pairs.len() == 0 || !pairs.remainder().is_empty() {
+                return Err(RedisError::WrongArity("config|set".to_string()));
+            }
+            for pair in pairs {
+                let name = str_arg(pair, 0)?.to_lowercase();
+                let value = str_arg(pair, 1)?;
+                set_config_value(&name, value, &storage, &state, &broadcaster).await?;
             }
+            stream.write_all(
+                &RObject::SimpleString("OK".to_string()).encode()
+            ).await?;
         }
-        _ => return Err(anyhow::anyhow!("Command not allowed")),
+        _ => return Err(RedisError::Err(format!("unknown subcommand '{}'. Try CONFIG HELP.", command))),
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+fn config_value(state: &State, name: &str) -> Option<String> {
+    let yes_no = |value: bool| if value { "yes" } else { "no" }.to_string();
+    match name {
+        "dir" => Some(state.dir.clone().unwrap_or_default()),
+        "dbfilename" => Some(state.dbfilename.clone().unwrap_or_default()),
+        "save" => Some(format_save_params(&state.save_params)),
+        "appendonly" => Some(yes_no(state.appendonly)),
+        "appendfilename" => Some(state.appendfilename.clone()),
+        "appendfsync" => Some(state.appendfsync.to_string()),
+        "appenddirname" => Some(state.appenddirname.clone()),
+        "aof-load-truncated" => Some(yes_no(state.aof_load_truncated)),
+        "auto-aof-rewrite-percentage" => Some(state.auto_aof_rewrite_percentage.to_string()),
+        "auto-aof-rewrite-min-size" => Some(state.auto_aof_rewrite_min_size.to_string()),
+        "repl-backlog-size" => Some(state.repl_backlog_size.to_string()),
+        "replica-read-only" | "slave-read-only" => Some(yes_no(state.replica_read_only)),
+        "repl-diskless-sync" => Some(yes_no(state.repl_diskless_sync)),
+        "repl-diskless-sync-delay" => Some(state.repl_diskless_sync_delay.to_string()),
+        "repl-ping-replica-period" | "repl-ping-slave-period" => Some(state.repl_ping_replica_period.to_string()),
+        "repl-timeout" => Some(state.repl_timeout.to_string()),
+        "min-replicas-to-write" | "min-slaves-to-write" => Some(state.min_replicas_to_write.to_string()),
+        "min-replicas-max-lag" | "min-slaves-max-lag" => Some(state.min_replicas_max_lag.to_string()),
+        "proto-max-bulk-len" => Some(state.proto_max_bulk_len.to_string()),
+        _ => None,
+    }
+}
+
+fn set_failed(name: &str, reason: &str) -> RedisError {
+    RedisError::Err(format!("CONFIG SET failed (possibly related to argument '{}') - {}", name, reason))
+}
+
+fn parse_yes_no(name: &str, value: &str) -> Result<bool, RedisError> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(set_failed(name, "argument must be 'yes' or 'no'")),
+    }
+}
+
+async fn set_config_value(
+    name: &str,
+    value: &str,
+    storage: &Arc<RwLock<Db>>,
+    state: &Arc<RwLock<State>>,
+    broadcaster: &Arc<RwLock<Broadcaster>>,
+) -> Result<(), RedisError> {
+    match name {
+        "save" => {
+            let params = parse_save_params(value).ok_or_else(|| set_failed(name, "Invalid save parameters"))?;
+            state.write().await.save_params = params;
+        },
+        "appendfsync" => {
+            let policy: FsyncPolicy = value.parse().map_err(|_| set_failed(name, "argument(s) must be one of the following: always, everysec, no"))?;
+            state.write().await.appendfsync = policy;
+            if let Some(aof) = broadcaster.write().await.aof.as_mut() {
+                aof.policy = policy;
+            }
+        },
+        "aof-load-truncated" => {
+            state.write().await.aof_load_truncated = parse_yes_no(name, value)?;
+        },
+        "auto-aof-rewrite-percentage" => {
+            state.write().await.auto_aof_rewrite_percentage = value.parse().map_err(|_| set_failed(name, "argument couldn't be parsed into an integer"))?;
+        },
+        "auto-aof-rewrite-min-size" => {
+            state.write().await.auto_aof_rewrite_min_size = value.parse().map_err(|_| set_failed(name, "argument couldn't be parsed into an integer"))?;
+        },
+        "appendonly" => {
+            let enable = parse_yes_no(name, value)?;
+            let (dir, appendfilename, policy) = {
+                let state = state.read().await;
+                if state.aof_rewrite_in_progress {
+                    return Err(set_failed(name, "Background append only file rewriting already in progress"));
+                }
+                (state.aof_dir(), state.appendfilename.clone(), state.appendfsync)
+            };
+            // holding the broadcaster keeps writes from slipping between the snapshot and the log
+            let mut broadcaster = broadcaster.write().await;
+            match (enable, broadcaster.aof.is_some()) {
+                (true, false) => {
+                    let snapshot = storage.read().await.snapshot();
+                    let writer = aof::create(&dir, &appendfilename, policy, &snapshot)?;
+                    broadcaster.aof = Some(writer);
+                },
+                (false, true) => {
+                    if let Some(Err(e)) = broadcaster.aof.take().map(|aof| aof.sync()) {
+                        eprintln!("Failed to fsync the AOF: {}", e);
+                    }
+                },
+                _ => {},
+            }
+            drop(broadcaster);
+            state.write().await.appendonly = enable;
+        },
+        "replica-read-only" | "slave-read-only" => {
+            state.write().await.replica_read_only = parse_yes_no(name, value)?;
+        },
+        "repl-diskless-sync" => {
+            state.write().await.repl_diskless_sync = parse_yes_no(name, value)?;
+        },
+        "repl-diskless-sync-delay" => {
+            state.write().await.repl_diskless_sync_delay = value.parse().map_err(|_| set_failed(name, "argument couldn't be parsed into an integer"))?;
+        },
+        "repl-ping-replica-period" | "repl-ping-slave-period" => {
+            let period: u64 = value.parse().map_err(|_| set_failed(name, "argument couldn't be parsed into an integer"))?;
+            if period == 0 {
+                return Err(set_failed(name, "argument must be between 1 and 2147483647 inclusive"));
+            }
+            state.write().await.repl_ping_replica_period = period;
+        },
+        "repl-timeout" => {
+            let timeout: u64 = value.parse().map_err(|_| set_failed(name, "argument couldn't be parsed into an integer"))?;
+            if timeout == 0 {
+                return Err(set_failed(name, "argument must be between 1 and 2147483647 inclusive"));
+            }
+            state.write().await.repl_timeout = timeout;
+            broadcaster.write().await.repl_timeout = Duration::from_secs(timeout);
+        },
+        "min-replicas-to-write" | "min-slaves-to-write" => {
+            state.write().await.min_replicas_to_write = value.parse().map_err(|_| set_failed(name, "argument couldn't be parsed into an integer"))?;
+        },
+        "min-replicas-max-lag" | "min-slaves-max-lag" => {
+            state.write().await.min_replicas_max_lag = value.parse().map_err(|_| set_failed(name, "argument couldn't be parsed into an integer"))?;
+        },
+        "repl-backlog-size" => {
+            let size: usize = value.parse().map_err(|_| set_failed(name, "argument couldn't be parsed into an integer"))?;
+            // redis keeps at least 16kb of backlog
+            let size = size.max(16 * 1024);
+            state.write().await.repl_backlog_size = size;
+            broadcaster.write().await.backlog.resize(size);
+        },
+        "dir" | "dbfilename" | "appendfilename" | "appenddirname" => return Err(set_failed(name, "can't set immutable config")),
+        _ => return Err(RedisError::Err(format!("Unknown option or number of arguments for CONFIG SET - '{}'", name))),
+    }
+    Ok(())
+}
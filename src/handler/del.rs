@@ -0,0 +1,22 @@
+use std::sync::Arc;
+
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{db::Db, error::RedisError, handler::{args::bytes_arg, ReplyStream}, protocol::RObject};
+
+pub async fn handle_del(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let mut storage = storage.write().await;
+    let mut deleted = 0;
+    for i in 1..args.len() {
+        if storage.remove(bytes_arg(args, i)?).is_some() {
+            deleted += 1;
+        }
+    }
+    if deleted > 0 {
+        storage.propagate(args.to_vec());
+    }
+    drop(storage);
+
+    stream.write_all(&RObject::Integer(deleted).encode()).await?;
+    Ok(())
+}
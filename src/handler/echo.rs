@@ -1,17 +1,11 @@
-use anyhow::Error;
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio::io::AsyncWriteExt;
 
-use crate::protocol::{self, RObject};
+use crate::{error::RedisError, handler::{args::bytes_arg, ReplyStream}, protocol::RObject};
 
-pub async fn handle_echo(args: &Vec<RObject>, stream: &mut TcpStream) -> Result<(), Error> {
-    if let protocol::RObject::BulkString(s) = args.get(1).ok_or_else(|| anyhow::anyhow!("Missing argument for ECHO"))? {
-        stream.write(
-            RObject::BulkString(s.clone()).to_string().as_bytes()
-        ).await.expect(
-            "error writing response to stream when responding to ECHO"
-        );
-    } else {
-        anyhow::bail!("Expected bulk string as argument for ECHO");
-    }
+pub async fn handle_echo(args: &[RObject], stream: &mut ReplyStream) -> Result<(), RedisError> {
+    let message = bytes_arg(args, 1)?;
+    stream.write_all(
+        &RObject::BulkString(message.clone()).encode()
+    ).await?;
     Ok(())
-}
\ No newline at end of file
+}
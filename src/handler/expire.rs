@@ -0,0 +1,194 @@
+use std::sync::Arc;
+
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{clock::now_ms, db::Db, error::RedisError, handler::{args::{bytes_arg, command_name, int_arg, str_arg}, ReplyStream}, protocol::RObject};
+
+#[derive(PartialEq, Eq)]
+enum Condition {
+    Nx,
+    Xx,
+    Gt,
+    Lt,
+}
+
+// [NX | XX | GT | LT], where GT and LT may be combined with XX
+fn parse_condition(args: &[RObject]) -> Result<Option<Condition>, RedisError> {
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    for i in 3..args.len() {
+        match str_arg(args, i)?.to_uppercase().as_str() {
+            "NX" => nx = true,
+            "XX" => xx = true,
+            "GT" => gt = true,
+            "LT" => lt = true,
+            option => return Err(RedisError::Err(format!("Unsupported option {}", option))),
+        }
+    }
+    if nx && (xx || gt || lt) {
+        return Err(RedisError::Err("NX and XX, GT or LT options at the same time are not compatible".to_string()));
+    }
+    if gt && lt {
+        return Err(RedisError::Err("GT and LT options at the same time are not compatible".to_string()));
+    }
+    Ok(match (nx, xx, gt, lt) {
+        (true, _, _, _) => Some(Condition::Nx),
+        (_, _, true, _) => Some(Condition::Gt),
+        (_, _, _, true) => Some(Condition::Lt),
+        (_, true, _, _) => Some(Condition::Xx),
+        _ => None,
+    })
+}
+
+/// EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT.
+///
+/// Replicates the new deadline as `PEXPIREAT`, or a `DEL` when it already passed.
+pub async fn handle_expire(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let name = command_name(args);
+    let key = bytes_arg(args, 1)?;
+    let amount = int_arg::<i64>(args, 2)?;
+    let condition = parse_condition(args)?;
+
+    let invalid = || RedisError::Err(format!("invalid expire time in '{}' command", name));
+    let amount_ms = match name.as_str() {
+        "expire" | "expireat" => amount.checked_mul(1000).ok_or_else(invalid)?,
+        _ => amount,
+    };
+    let deadline = match name.as_str() {
+        "expire" | "pexpire" => amount_ms.checked_add(now_ms() as i64).ok_or_else(invalid)?,
+        _ => amount_ms,
+    };
+
+    let mut storage = storage.write().await;
+    let current = match storage.get_entry(key) {
+        Some(entry) => entry.expires_at,
+        None => {
+            stream.write_all(&RObject::Integer(0).encode()).await?;
+            return Ok(());
+        },
+    };
+
+    // a missing deadline counts as an infinite ttl
+    let allowed = match condition {
+        Some(Condition::Nx) => current.is_none(),
+        Some(Condition::Xx) => current.is_some(),
+        Some(Condition::Gt) => current.is_some_and(|current| deadline > current as i64),
+        Some(Condition::Lt) => match current {
+            Some(current) => deadline < current as i64,
+            None => true,
+        },
+        None => true,
+    };
+    if !allowed {
+        stream.write_all(&RObject::Integer(0).encode()).await?;
+        return Ok(());
+    }
+
+    let command = if deadline <= now_ms() as i64 {
+        storage.remove(key);
+        vec![RObject::BulkString("DEL".into()), RObject::BulkString(key.clone())]
+    } else {
+        storage.set_expiry(key, Some(deadline as u64));
+        vec![
+            RObject::BulkString("PEXPIREAT".into()),
+            RObject::BulkString(key.clone()),
+            RObject::BulkString(deadline.to_string().into()),
+        ]
+    };
+    storage.propagate(command);
+    drop(storage);
+
+    stream.write_all(&RObject::Integer(1).encode()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::value::Value;
+
+    fn command(args: &[&str]) -> Vec<RObject> {
+        args.iter().map(|arg| RObject::BulkString(Bytes::copy_from_slice(arg.as_bytes()))).collect()
+    }
+
+    async fn expire(args: &[&str], storage: &Arc<RwLock<Db>>) -> Result<Vec<u8>, RedisError> {
+        let mut reply = vec![];
+        handle_expire(&command(args), &mut reply, Arc::clone(storage)).await?;
+        Ok(reply)
+    }
+
+    fn storage_with(key: &str, expires_at: Option<u64>) -> Arc<RwLock<Db>> {
+        let mut db = Db::new();
+        db.insert_with_expiry(Bytes::copy_from_slice(key.as_bytes()), Value::String(Bytes::from("v")), expires_at);
+        Arc::new(RwLock::new(db))
+    }
+
+    async fn deadline(storage: &Arc<RwLock<Db>>, key: &[u8]) -> Option<u64> {
+        storage.read().await.get_entry(key).unwrap().expires_at
+    }
+
+    #[tokio::test]
+    async fn rejects_incompatible_conditions() {
+        let storage = storage_with("k", None);
+        for (args, message) in [
+            (["EXPIRE", "k", "10", "NX", "XX"].as_slice(), "ERR NX and XX, GT or LT options at the same time are not compatible"),
+            (&["EXPIRE", "k", "10", "NX", "GT"], "ERR NX and XX, GT or LT options at the same time are not compatible"),
+            (&["EXPIRE", "k", "10", "GT", "LT"], "ERR GT and LT options at the same time are not compatible"),
+            (&["EXPIRE", "k", "10", "SOON"], "ERR Unsupported option SOON"),
+        ] {
+            assert_eq!(expire(args, &storage).await.unwrap_err().to_string(), message);
+        }
+        assert_eq!(deadline(&storage, b"k").await, None);
+    }
+
+    #[tokio::test]
+    async fn nx_and_xx_depend_on_an_existing_deadline() {
+        let storage = storage_with("k", None);
+        assert_eq!(expire(&["EXPIRE", "k", "100", "XX"], &storage).await.unwrap(), b":0\r\n");
+        assert_eq!(deadline(&storage, b"k").await, None);
+        assert_eq!(expire(&["EXPIRE", "k", "100", "NX"], &storage).await.unwrap(), b":1\r\n");
+        let first = deadline(&storage, b"k").await.unwrap();
+        assert_eq!(expire(&["EXPIRE", "k", "200", "NX"], &storage).await.unwrap(), b":0\r\n");
+        assert_eq!(deadline(&storage, b"k").await, Some(first));
+        assert_eq!(expire(&["EXPIRE", "k", "200", "XX"], &storage).await.unwrap(), b":1\r\n");
+        assert!(deadline(&storage, b"k").await.unwrap() > first);
+    }
+
+    #[tokio::test]
+    async fn gt_and_lt_compare_with_the_current_deadline() {
+        let at = now_ms() + 100_000;
+        let storage = storage_with("k", Some(at));
+        let earlier = (at - 1).to_string();
+        let later = (at + 1).to_string();
+        assert_eq!(expire(&["PEXPIREAT", "k", &earlier, "GT"], &storage).await.unwrap(), b":0\r\n");
+        assert_eq!(expire(&["PEXPIREAT", "k", &later, "LT"], &storage).await.unwrap(), b":0\r\n");
+        assert_eq!(deadline(&storage, b"k").await, Some(at));
+        assert_eq!(expire(&["PEXPIREAT", "k", &later, "GT"], &storage).await.unwrap(), b":1\r\n");
+        assert_eq!(deadline(&storage, b"k").await, Some(at + 1));
+        assert_eq!(expire(&["PEXPIREAT", "k", &earlier, "LT", "XX"], &storage).await.unwrap(), b":1\r\n");
+        assert_eq!(deadline(&storage, b"k").await, Some(at - 1));
+    }
+
+    #[tokio::test]
+    async fn a_missing_deadline_counts_as_infinite() {
+        let storage = storage_with("k", None);
+        assert_eq!(expire(&["EXPIRE", "k", "100", "GT"], &storage).await.unwrap(), b":0\r\n");
+        assert_eq!(deadline(&storage, b"k").await, None);
+        assert_eq!(expire(&["EXPIRE", "k", "100", "LT"], &storage).await.unwrap(), b":1\r\n");
+        assert!(deadline(&storage, b"k").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn replicates_the_absolute_deadline_or_a_del() {
+        let storage = storage_with("k", None);
+        expire(&["PEXPIRE", "k", "100000"], &storage).await.unwrap();
+        let at = deadline(&storage, b"k").await.unwrap();
+        assert_eq!(storage.write().await.take_propagated(), [command(&["PEXPIREAT", "k", &at.to_string()])]);
+
+        assert_eq!(expire(&["EXPIREAT", "k", "1"], &storage).await.unwrap(), b":1\r\n");
+        assert!(!storage.read().await.contains_key(b"k"));
+        assert_eq!(storage.write().await.take_propagated(), [command(&["DEL", "k"])]);
+        assert_eq!(expire(&["EXPIRE", "k", "100"], &storage).await.unwrap(), b":0\r\n");
+    }
+}
@@ -1,27 +1,21 @@
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 
-use anyhow::Error;
-use tokio::{io::AsyncWriteExt, net::TcpStream, sync::RwLock};
+use tokio::{io::AsyncWriteExt, sync::RwLock};
 
-use crate::protocol::RObject;
+use crate::{db::Db, error::RedisError, handler::{args::bytes_arg, ReplyStream}, protocol::RObject, value::Value};
 
-pub async fn handle_get(args: &Vec<RObject>, stream: &mut TcpStream, storage: Arc<RwLock<HashMap<String, RObject>>>) -> Result<(), Error> {
-    if args.len() < 2 {
-        return Err(anyhow::anyhow!("GET requires at least 1 argument"));
-    }
+pub async fn handle_get(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
 
-    let key = match &args[1] {
-        RObject::BulkString(s) => s,
-        _ => return Err(anyhow::anyhow!("Expected BulkString as key")),
+    let value = match storage.read().await.get(key) {
+        Some(Value::String(s)) => RObject::BulkString(s.clone()),
+        Some(_) => return Err(RedisError::WrongType),
+        None => RObject::NullBulkString,
     };
 
-    let value = storage.read().await.get(key).cloned().unwrap_or(RObject::NullBulkString);
-
-    stream.write(
-        value.to_string().as_bytes()
-    ).await.expect(
-        "failed to write response to stream handling GET"
-    );
+    stream.write_all(
+        &value.encode()
+    ).await?;
 
     Ok(())
-}
\ No newline at end of file
+}
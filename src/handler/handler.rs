@@ -1,77 +1,520 @@
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 
-use anyhow::{bail, Error};
-use tokio::{io::AsyncWriteExt, net::TcpStream, sync::RwLock};
+use bytes::Bytes;
 
-use crate::{broadcast::Broadcaster, handler::{handle_echo, handle_config, handle_get, handle_info, handle_ping, handle_psync, handle_replconf, handle_set, handle_wait}, protocol::{self, RObject}, State};
+use tokio::{io::{AsyncWrite, AsyncWriteExt}, net::TcpStream, sync::RwLock};
+
+use crate::{broadcast::Broadcaster, db::Db, error::RedisError, handler::{args::bytes_arg, pattern::glob_match, command_table::{lookup, CommandSpec}, handle_command, handle_del, handle_echo, handle_config, handle_expire, handle_get, handle_info, handle_persist, handle_ping, handle_psync, handle_replconf, queue_diskless_sync, PsyncReply, handle_replicaof, handle_role, handle_object, handle_type, handle_push, handle_pop, handle_llen, handle_lrange, handle_lindex, handle_lset, handle_lrem, handle_ltrim, handle_linsert, handle_lpos, handle_lmove, handle_blocking_pop, handle_hello, handle_hset, handle_hsetnx, handle_hget, handle_hmget, handle_hdel, handle_hexists, handle_hlen, handle_hstrlen, handle_hgetall, handle_hincrby, handle_hincrbyfloat, handle_hrandfield, handle_hscan, handle_save, handle_bgsave, handle_lastsave, handle_bgrewriteaof, handle_set, handle_ttl, handle_wait}, protocol::RObject, session::Session, state::ServerRole, State};
 
 pub enum HandleResult {
     Subscribed,
     Normal(TcpStream),
 }
 
-pub async fn handle(request: &[u8], mut stream: TcpStream, storage: Arc<RwLock<HashMap<String, RObject>>>, state: Arc<RwLock<State>>, broadcaster: Arc<RwLock<Broadcaster>>) -> Result<HandleResult, Error> {
-    
-    let str_req = String::from_utf8_lossy(request).to_string();
+/// Where handlers write their replies.
+pub type ReplyStream = dyn AsyncWrite + Unpin + Send;
 
-    let mut start = 0;
+pub async fn handle(request: RObject, raw: &[u8], mut stream: TcpStream, session: &mut Session, storage: Arc<RwLock<Db>>, state: Arc<RwLock<State>>, broadcaster: Arc<RwLock<Broadcaster>>) -> Result<HandleResult, std::io::Error> {
+    let args = match request {
+        RObject::Array(a) => a,
+        _ => vec![],
+    };
+    let spec = args.first()
+        .and_then(RObject::as_str)
+        .and_then(lookup);
 
-    while start < str_req.len() {
-        let (parsed, consumed) = protocol::RObject::decode(&str_req, start)?;
+    // only the master link may change the dataset of a read only replica
+    let read_only = !session.master_link && {
+        let state = state.read().await;
+        state.role == ServerRole::Slave && state.replica_read_only
+    };
 
-        if let protocol::RObject::Array(a) = parsed {
-            let command = match a.get(0)
-                .ok_or_else(|| anyhow::anyhow!("Empty array"))? {
-                    protocol::RObject::SimpleString(s) => s,
-                    protocol::RObject::BulkString(s) => s,
-                    _ => bail!("Expected string as command"),
-                };
-            match command.as_str() {
-                "PING" => {
-                    handle_ping(&mut stream, Arc::clone(&state)).await?;
-                },
-                "ECHO" => {
-                    handle_echo(&a, &mut stream).await?;
-                },
-                "SET" => {
-                    let future = handle_set(&a, &mut stream, Arc::clone(&storage), Arc::clone(&state));
-                    broadcaster.write().await.broadcast(&request[start..consumed]).await?;
-                    let _ = future.await;
-                },
-                "GET" => {
-                    handle_get(&a, &mut stream, Arc::clone(&storage)).await?;
-                },
-                "INFO" => {
-                    handle_info(&a, Arc::clone(&state), &mut stream).await?;
-                },
-                "REPLCONF" => {
-                    handle_replconf(&a, &mut stream, Arc::clone(&state)).await?;
-                },
-                "PSYNC" => {
-                    handle_psync(&a, stream, Arc::clone(&state), Arc::clone(&broadcaster)).await?;
+    let result = match spec {
+        Some(spec) if !spec.check_arity(args.len()) => Err(RedisError::WrongArity(spec.name.to_string())),
+        Some(spec) if read_only && spec.is_write() => Err(RedisError::ReadOnly),
+        Some(spec) if spec.is_write() && !session.master_link && !enough_good_replicas(&state, &broadcaster).await => Err(RedisError::NoReplicas),
+        Some(spec) if spec.name == "psync" => {
+            // nothing may be propagated between the snapshot and the subscription,
+            // or the replica would miss it
+            let mut guard = broadcaster.write().await;
+            match handle_psync(&args, session.capa_eof, Arc::clone(&storage), Arc::clone(&state), &mut guard).await {
+                Ok(PsyncReply::Subscribe(preamble)) => {
+                    guard.subscribe(stream, session.listening_port, preamble);
                     return Ok(HandleResult::Subscribed);
                 },
-                "WAIT" => {
-                    handle_wait(&a, &mut stream, Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster)).await?;
+                Ok(PsyncReply::Diskless) => {
+                    drop(guard);
+                    queue_diskless_sync(stream, session.listening_port, storage, state, broadcaster).await;
+                    return Ok(HandleResult::Subscribed);
                 },
-                "CONFIG" => {
-                    handle_config(&a, &mut stream, Arc::clone(&state)).await?;
+                Err(e) => Err(e),
+            }
+        },
+        Some(spec) if session.master_link && spec.is_write() => {
+            // applied and passed on in one step, so the snapshot a replica of ours gets never
+            // holds a write the stream then delivers to it once more
+            let mut guard = broadcaster.write().await;
+            let result = dispatch(spec, &args, &mut tokio::io::sink(), session, Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster)).await;
+            guard.propagate(&mut *storage.write().await, true);
+            guard.feed_replicas(raw);
+            state.write().await.consumed += raw.len();
+            result
+        },
+        Some(spec) if matches!(spec.name, "blpop" | "brpop" | "blmove" | "blmpop") && !session.master_link => {
+            // a blocked client is watched for disconnects, which takes the socket itself
+            match expire_keys(spec, &args, &storage, &state, &broadcaster).await {
+                Ok(()) => {
+                    let (mut reader, writer) = stream.into_split();
+                    let mut replies = vec![];
+                    let result = handle_blocking_pop(&args, &mut replies, Some(&mut reader), Arc::clone(&storage)).await;
+                    stream = reader.reunite(writer).expect("halves of the same stream");
+                    propagate(&state, &storage, &broadcaster).await;
+                    stream.write_all(&replies).await?;
+                    result
                 },
-                "KEYS" => {
-                    stream.write_all(
-                        RObject::Array(
-                            storage.read().await.keys().map(|k| RObject::BulkString(k.clone())).collect()
-                        ).to_string().as_bytes()
-                    ).await?;
-                }
-                _ => bail!("Unknown command: {}", command),
+                Err(e) => Err(e),
             }
-        } else {
-            bail!("Expected array as request");
-        }
-        state.write().await.consumed += consumed - start;    
-        start = consumed;
+        },
+        Some(spec) => match expire_keys(spec, &args, &storage, &state, &broadcaster).await {
+            Ok(()) if spec.is_write() => {
+                // a write is only acknowledged once the AOF has it, fsynced with appendfsync always
+                let mut replies = vec![];
+                let result = dispatch(spec, &args, &mut replies, session, Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster)).await;
+                propagate(&state, &storage, &broadcaster).await;
+                stream.write_all(&replies).await?;
+                result
+            },
+            Ok(()) => {
+                // the master only expects answers to the commands that ask for one
+                let mut sink = tokio::io::sink();
+                let replies: &mut ReplyStream = if session.master_link && spec.name != "replconf" {
+                    &mut sink
+                } else {
+                    &mut stream
+                };
+                dispatch(spec, &args, replies, session, Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster)).await
+            },
+            Err(e) => Err(e),
+        },
+        None => Err(unknown_command(&args)),
+    };
+
+    match result {
+        Ok(()) if spec.is_some_and(CommandSpec::is_write) => {
+            session.write_offset = broadcaster.read().await.offset();
+        },
+        Ok(()) => {},
+        Err(RedisError::Io(e)) => return Err(e),
+        Err(e) => {
+            if !session.master_link {
+                stream.write_all(&e.to_robject().encode()).await?;
+            } else {
+                eprintln!("Error applying command from master: {}", e);
+            }
+        },
+    }
+    if session.master_link && !spec.is_some_and(CommandSpec::is_write) {
+        broadcaster.write().await.feed_replicas(raw);
+        state.write().await.consumed += raw.len();
     }
 
     Ok(HandleResult::Normal(stream))
-}
\ No newline at end of file
+}
+
+/// Runs a command without a client to reply to, how the AOF is replayed.
+pub async fn execute(args: Vec<RObject>, storage: Arc<RwLock<Db>>, state: Arc<RwLock<State>>, broadcaster: Arc<RwLock<Broadcaster>>) -> Result<(), RedisError> {
+    let spec = args.first()
+        .and_then(RObject::as_str)
+        .and_then(lookup)
+        .ok_or_else(|| unknown_command(&args))?;
+    if !spec.check_arity(args.len()) {
+        return Err(RedisError::WrongArity(spec.name.to_string()));
+    }
+    // trusted like the master link: no replies, writes always allowed
+    let mut session = Session::new(true);
+    let result = dispatch(spec, &args, &mut tokio::io::sink(), &mut session, Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster)).await;
+    propagate(&state, &storage, &broadcaster).await;
+    result
+}
+
+/// Whether min-replicas-to-write lets a master accept writes right now.
+async fn enough_good_replicas(state: &Arc<RwLock<State>>, broadcaster: &Arc<RwLock<Broadcaster>>) -> bool {
+    let (min_replicas, max_lag) = {
+        let state = state.read().await;
+        if state.role != ServerRole::Master || state.min_replicas_to_write == 0 {
+            return true;
+        }
+        (state.min_replicas_to_write, state.min_replicas_max_lag)
+    };
+    broadcaster.read().await.good_replicas(max_lag) >= min_replicas
+}
+
+/// Lazily deletes the expired keys a command is about to touch and replicates the deletions,
+/// so the command itself only ever sees live keys.
+async fn expire_keys(spec: &CommandSpec, args: &[RObject], storage: &Arc<RwLock<Db>>, state: &Arc<RwLock<State>>, broadcaster: &Arc<RwLock<Broadcaster>>) -> Result<(), RedisError> {
+    // replicas keep expired keys around until the master says otherwise
+    if state.read().await.role != ServerRole::Master {
+        return Ok(());
+    }
+    let keys: Vec<Bytes> = spec.key_positions(args)
+        .into_iter()
+        .filter_map(|i| match &args[i] {
+            RObject::BulkString(key) => Some(key.clone()),
+            _ => None,
+        })
+        .collect();
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let expired = {
+        // queued with the deletion itself, so no write to the same key can overtake the DEL
+        let mut storage = storage.write().await;
+        let mut expired = false;
+        for key in keys {
+            if storage.remove_if_expired(&key) {
+                storage.propagate(vec![RObject::BulkString("DEL".into()), RObject::BulkString(key)]);
+                expired = true;
+            }
+        }
+        expired
+    };
+    if expired {
+        propagate(state, storage, broadcaster).await;
+    }
+    Ok(())
+}
+
+async fn dispatch(spec: &CommandSpec, args: &[RObject], stream: &mut ReplyStream, session: &mut Session, storage: Arc<RwLock<Db>>, state: Arc<RwLock<State>>, broadcaster: Arc<RwLock<Broadcaster>>) -> Result<(), RedisError> {
+    match spec.name {
+        "ping" => {
+            handle_ping(args, stream).await?;
+        },
+        "echo" => {
+            handle_echo(args, stream).await?;
+        },
+        "hello" => {
+            handle_hello(args, stream, session, Arc::clone(&state)).await?;
+        },
+        "set" => {
+            handle_set(args, stream, Arc::clone(&storage)).await?;
+        },
+        "del" => {
+            handle_del(args, stream, Arc::clone(&storage)).await?;
+        },
+        "expire" | "pexpire" | "expireat" | "pexpireat" => {
+            handle_expire(args, stream, Arc::clone(&storage)).await?;
+        },
+        "ttl" | "pttl" | "expiretime" | "pexpiretime" => {
+            handle_ttl(args, stream, Arc::clone(&storage)).await?;
+        },
+        "persist" => {
+            handle_persist(args, stream, Arc::clone(&storage)).await?;
+        },
+        "get" => {
+            handle_get(args, stream, Arc::clone(&storage)).await?;
+        },
+        "type" => {
+            handle_type(args, stream, Arc::clone(&storage)).await?;
+        },
+        "object" => {
+            handle_object(args, stream, Arc::clone(&storage)).await?;
+        },
+        "lpush" | "rpush" | "lpushx" | "rpushx" => {
+            handle_push(args, stream, Arc::clone(&storage)).await?;
+        },
+        "lpop" | "rpop" => {
+            handle_pop(args, stream, Arc::clone(&storage)).await?;
+        },
+        "blpop" | "brpop" | "blmove" | "blmpop" | "lmpop" => {
+            handle_blocking_pop(args, stream, None, Arc::clone(&storage)).await?;
+        },
+        "llen" => {
+            handle_llen(args, stream, Arc::clone(&storage)).await?;
+        },
+        "lrange" => {
+            handle_lrange(args, stream, Arc::clone(&storage)).await?;
+        },
+        "lindex" => {
+            handle_lindex(args, stream, Arc::clone(&storage)).await?;
+        },
+        "lpos" => {
+            handle_lpos(args, stream, Arc::clone(&storage)).await?;
+        },
+        "lset" => {
+            handle_lset(args, stream, Arc::clone(&storage)).await?;
+        },
+        "lrem" => {
+            handle_lrem(args, stream, Arc::clone(&storage)).await?;
+        },
+        "ltrim" => {
+            handle_ltrim(args, stream, Arc::clone(&storage)).await?;
+        },
+        "linsert" => {
+            handle_linsert(args, stream, Arc::clone(&storage)).await?;
+        },
+        "lmove" | "rpoplpush" => {
+            handle_lmove(args, stream, Arc::clone(&storage)).await?;
+        },
+        "hset" | "hmset" => {
+            handle_hset(args, stream, Arc::clone(&storage)).await?;
+        },
+        "hsetnx" => {
+            handle_hsetnx(args, stream, Arc::clone(&storage)).await?;
+        },
+        "hdel" => {
+            handle_hdel(args, stream, Arc::clone(&storage)).await?;
+        },
+        "hincrby" => {
+            handle_hincrby(args, stream, Arc::clone(&storage)).await?;
+        },
+        "hincrbyfloat" => {
+            handle_hincrbyfloat(args, stream, Arc::clone(&storage)).await?;
+        },
+        "hget" => {
+            handle_hget(args, stream, Arc::clone(&storage)).await?;
+        },
+        "hmget" => {
+            handle_hmget(args, stream, Arc::clone(&storage)).await?;
+        },
+        "hexists" => {
+            handle_hexists(args, stream, Arc::clone(&storage)).await?;
+        },
+        "hlen" => {
+            handle_hlen(args, stream, Arc::clone(&storage)).await?;
+        },
+        "hstrlen" => {
+            handle_hstrlen(args, stream, Arc::clone(&storage)).await?;
+        },
+        "hgetall" | "hkeys" | "hvals" => {
+            handle_hgetall(args, stream, session, Arc::clone(&storage)).await?;
+        },
+        "hrandfield" => {
+            handle_hrandfield(args, stream, session, Arc::clone(&storage)).await?;
+        },
+        "hscan" => {
+            handle_hscan(args, stream, Arc::clone(&storage)).await?;
+        },
+        "info" => {
+            handle_info(args, Arc::clone(&state), Arc::clone(&storage), Arc::clone(&broadcaster), stream).await?;
+        },
+        "replconf" => {
+            handle_replconf(args, stream, session, Arc::clone(&state)).await?;
+        },
+        "replicaof" | "slaveof" => {
+            handle_replicaof(args, stream, Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster)).await?;
+        },
+        "role" => {
+            handle_role(stream, Arc::clone(&state), Arc::clone(&broadcaster)).await?;
+        },
+        "wait" => {
+            handle_wait(args, stream, session, Arc::clone(&state), Arc::clone(&broadcaster)).await?;
+        },
+        "config" => {
+            handle_config(args, stream, Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster)).await?;
+        },
+        "save" => {
+            handle_save(stream, &storage, &state).await?;
+        },
+        "bgsave" => {
+            handle_bgsave(args, stream, &storage, &state).await?;
+        },
+        "lastsave" => {
+            handle_lastsave(stream, &state).await?;
+        },
+        "bgrewriteaof" => {
+            handle_bgrewriteaof(stream, &storage, &state, &broadcaster).await?;
+        },
+        "keys" => {
+            let pattern = bytes_arg(args, 1)?;
+            stream.write_all(
+                &RObject::Array(
+                    storage.read().await.keys()
+                        .filter(|k| glob_match(pattern, k))
+                        .map(|k| RObject::BulkString(k.clone()))
+                        .collect()
+                ).encode()
+            ).await?;
+        },
+        "command" => {
+            handle_command(args, stream).await?;
+        },
+        _ => return Err(unknown_command(args)),
+    }
+    Ok(())
+}
+
+/// Replicates the writes queued in the keyspace.
+async fn propagate(state: &Arc<RwLock<State>>, storage: &Arc<RwLock<Db>>, broadcaster: &Arc<RwLock<Broadcaster>>) {
+    let replica = state.read().await.role == ServerRole::Slave;
+    let mut broadcaster = broadcaster.write().await;
+    broadcaster.propagate(&mut *storage.write().await, replica);
+}
+
+fn unknown_command(args: &[RObject]) -> RedisError {
+    let name = args.first()
+        .and_then(RObject::as_bytes)
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+    let rest = args.iter()
+        .skip(1)
+        .map(|a| format!("'{}' ", String::from_utf8_lossy(a.as_bytes().unwrap_or_default())))
+        .collect::<String>();
+    RedisError::UnknownCommand(name.to_string(), rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{quicklist::QuickList, value::Value};
+
+    fn command(args: &[&str]) -> Vec<RObject> {
+        args.iter().map(|arg| RObject::BulkString(Bytes::copy_from_slice(arg.as_bytes()))).collect()
+    }
+
+    struct Server {
+        storage: Arc<RwLock<Db>>,
+        state: Arc<RwLock<State>>,
+        broadcaster: Arc<RwLock<Broadcaster>>,
+    }
+
+    impl Server {
+        fn new() -> Self {
+            Server {
+                storage: Arc::new(RwLock::new(Db::new())),
+                state: Arc::new(RwLock::new(State::for_tests())),
+                broadcaster: Arc::new(RwLock::new(Broadcaster::default())),
+            }
+        }
+
+        // handles one command from a fresh client, returns what the client was sent
+        async fn send(&self, args: &[&str], session: &mut Session) -> Vec<u8> {
+            use tokio::{io::AsyncReadExt, net::TcpListener};
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let request = RObject::Array(command(args));
+            let result = handle(request.clone(), &request.encode(), server, session, Arc::clone(&self.storage), Arc::clone(&self.state), Arc::clone(&self.broadcaster)).await;
+            // the connection stays open whatever the reply was
+            let Ok(HandleResult::Normal(server)) = result else {
+                panic!("the connection was not handed back");
+            };
+            drop(server);
+            let mut reply = vec![];
+            client.read_to_end(&mut reply).await.unwrap();
+            reply
+        }
+    }
+
+    #[tokio::test]
+    async fn expires_the_keys_after_numkeys() {
+        let state = Arc::new(RwLock::new(State::for_tests()));
+        let broadcaster = Arc::new(RwLock::new(Broadcaster::default()));
+        for args in [
+            command(&["LMPOP", "2", "live", "gone", "LEFT"]),
+            command(&["BLMPOP", "0", "2", "live", "gone", "LEFT"]),
+        ] {
+            let storage = Arc::new(RwLock::new(Db::new()));
+            {
+                let mut storage = storage.write().await;
+                let list: QuickList = [Bytes::from("a")].into_iter().collect();
+                storage.insert(Bytes::from("live"), Value::List(list.clone()));
+                storage.insert_with_expiry(Bytes::from("gone"), Value::List(list), Some(1));
+            }
+            let offset = broadcaster.read().await.offset();
+            expire_keys(lookup(args[0].as_str().unwrap()).unwrap(), &args, &storage, &state, &broadcaster).await.unwrap();
+
+            assert!(storage.read().await.contains_key(b"live"));
+            assert!(!storage.read().await.contains_key(b"gone"));
+            let replicated = broadcaster.read().await.backlog.range_from(offset + 1).unwrap();
+            assert_eq!(replicated, RObject::Array(command(&["DEL", "gone"])).encode());
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_with_errors_instead_of_failing() {
+        let server = Server::new();
+        let mut session = Session::new(false);
+        assert_eq!(
+            server.send(&["FOO", "a", "b"], &mut session).await,
+            b"-ERR unknown command 'FOO', with args beginning with: 'a' 'b' \r\n"
+        );
+        assert_eq!(server.send(&["GET"], &mut session).await, b"-ERR wrong number of arguments for 'get' command\r\n");
+        assert_eq!(server.send(&["LPUSH", "l", "a"], &mut session).await, b":1\r\n");
+        assert_eq!(
+            server.send(&["GET", "l"], &mut session).await,
+            b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+        assert_eq!(server.send(&["EXPIRE", "l", "soon"], &mut session).await, b"-ERR value is not an integer or out of range\r\n");
+        assert_eq!(server.send(&["PING"], &mut session).await, b"+PONG\r\n");
+    }
+
+    #[tokio::test]
+    async fn read_only_replicas_refuse_client_writes() {
+        let server = Server::new();
+        server.state.write().await.role = ServerRole::Slave;
+        let mut client = Session::new(false);
+        assert_eq!(server.send(&["SET", "k", "v"], &mut client).await, b"-READONLY You can't write against a read only replica.\r\n");
+        assert_eq!(server.send(&["GET", "k"], &mut client).await, b"$-1\r\n");
+
+        // the master link still writes, without a reply
+        let mut master = Session::new(true);
+        assert_eq!(server.send(&["SET", "k", "v"], &mut master).await, b"");
+        assert_eq!(server.send(&["GET", "k"], &mut client).await, b"$1\r\nv\r\n");
+
+        server.state.write().await.replica_read_only = false;
+        assert_eq!(server.send(&["SET", "k", "w"], &mut client).await, b"+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn writes_need_enough_good_replicas() {
+        use std::sync::atomic::Ordering;
+        use tokio::net::TcpListener;
+
+        use crate::broadcast::Preamble;
+
+        let server = Server::new();
+        server.state.write().await.min_replicas_to_write = 1;
+        let mut client = Session::new(false);
+        assert_eq!(server.send(&["SET", "k", "v"], &mut client).await, b"-NOREPLICAS Not enough good replicas to write.\r\n");
+        assert_eq!(server.send(&["GET", "k"], &mut client).await, b"$-1\r\n");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _replica = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (link, _) = listener.accept().await.unwrap();
+        server.broadcaster.write().await.subscribe(link, None, Preamble::Ready(vec![]));
+        assert_eq!(server.send(&["SET", "k", "v"], &mut client).await, b"+OK\r\n");
+
+        // a replica silent for longer than min-replicas-max-lag doesn't count
+        server.broadcaster.read().await.subscribers[0].acked_at.store(crate::clock::now_ms() - 11_000, Ordering::SeqCst);
+        assert_eq!(server.send(&["DEL", "k"], &mut client).await, b"-NOREPLICAS Not enough good replicas to write.\r\n");
+    }
+
+    #[tokio::test]
+    async fn replicas_pass_the_stream_of_their_master_on() {
+        use tokio::{io::AsyncReadExt, net::TcpListener};
+
+        use crate::broadcast::Preamble;
+
+        let server = Server::new();
+        server.state.write().await.role = ServerRole::Slave;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut sub_replica = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (link, _) = listener.accept().await.unwrap();
+        server.broadcaster.write().await.subscribe(link, None, Preamble::Ready(vec![]));
+
+        // writes and everything else alike, byte for byte, so offsets agree down the chain
+        let mut master = Session::new(true);
+        let mut stream = vec![];
+        for args in [["SET", "k", "v"].as_slice(), &["PING"], &["EXPIRE", "k", "100"]] {
+            server.send(args, &mut master).await;
+            stream.extend(RObject::Array(command(args)).encode());
+        }
+        let mut received = vec![0; stream.len()];
+        sub_replica.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, stream);
+        assert_eq!(server.state.read().await.consumed, stream.len());
+        assert_eq!(server.broadcaster.read().await.offset(), stream.len());
+    }
+}
+
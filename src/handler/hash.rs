@@ -0,0 +1,636 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{db::{random_u64, Db}, fieldmap::FieldMap, error::RedisError, handler::{args::{bytes_arg, command_name, int_arg, str_arg}, pattern::glob_match, ReplyStream}, protocol::RObject, session::Session, value::Value};
+
+// fields HSCAN returns per call unless told otherwise
+const DEFAULT_SCAN_COUNT: usize = 10;
+// most fields a negative HRANDFIELD count may ask for
+const MAX_REPEATED_FIELDS: i64 = 16 * 1024 * 1024;
+
+fn hash_ref<'a>(storage: &'a Db, key: &[u8]) -> Result<Option<&'a FieldMap>, RedisError> {
+    match storage.get(key) {
+        Some(Value::Hash(fields)) => Ok(Some(fields)),
+        Some(_) => Err(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+fn hash_mut<'a>(storage: &'a mut Db, key: &[u8]) -> Result<Option<&'a mut FieldMap>, RedisError> {
+    match storage.get_mut(key) {
+        Some(Value::Hash(fields)) => Ok(Some(fields)),
+        Some(_) => Err(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+fn hash_or_create<'a>(storage: &'a mut Db, key: &Bytes) -> Result<&'a mut FieldMap, RedisError> {
+    if hash_mut(storage, key)?.is_none() {
+        storage.insert(key.clone(), Value::Hash(FieldMap::new()));
+    }
+    Ok(hash_mut(storage, key)?.expect("hash was just created"))
+}
+
+fn bulk(s: &Bytes) -> RObject {
+    RObject::BulkString(s.clone())
+}
+
+// HSET key field value [field value ...] and HMSET
+pub async fn handle_hset(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    if !args.len().is_multiple_of(2) {
+        return Err(RedisError::WrongArity(command_name(args)));
+    }
+    let key = bytes_arg(args, 1)?;
+    let pairs = (2..args.len())
+        .step_by(2)
+        .map(|i| Ok((bytes_arg(args, i)?.clone(), bytes_arg(args, i + 1)?.clone())))
+        .collect::<Result<Vec<_>, RedisError>>()?;
+
+    let created = {
+        let mut storage = storage.write().await;
+        let fields = hash_or_create(&mut storage, key)?;
+        let created = pairs.into_iter()
+            .filter(|(field, value)| fields.insert(field.clone(), value.clone()).is_none())
+            .count();
+        storage.dirty += (args.len() as u64 - 2) / 2;
+        storage.propagate(args.to_vec());
+        created
+    };
+
+    let reply = if command_name(args) == "hmset" {
+        RObject::SimpleString("OK".to_string())
+    } else {
+        RObject::Integer(created as i64)
+    };
+    stream.write_all(&reply.encode()).await?;
+    Ok(())
+}
+
+// HSETNX key field value
+pub async fn handle_hsetnx(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let field = bytes_arg(args, 2)?;
+    let value = bytes_arg(args, 3)?;
+
+    let set = {
+        let mut storage = storage.write().await;
+        let exists = hash_ref(&storage, key)?.is_some_and(|fields| fields.contains_key(field));
+        if !exists {
+            hash_or_create(&mut storage, key)?.insert(field.clone(), value.clone());
+            storage.dirty += 1;
+            storage.propagate(args.to_vec());
+        }
+        !exists
+    };
+
+    stream.write_all(&RObject::Integer(set as i64).encode()).await?;
+    Ok(())
+}
+
+// HGET key field
+pub async fn handle_hget(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let field = bytes_arg(args, 2)?;
+
+    let value = hash_ref(&*storage.read().await, key)?
+        .and_then(|fields| fields.get(field))
+        .map(bulk)
+        .unwrap_or(RObject::NullBulkString);
+
+    stream.write_all(&value.encode()).await?;
+    Ok(())
+}
+
+// HMGET key field [field ...]
+pub async fn handle_hmget(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+
+    let values = {
+        let storage = storage.read().await;
+        let fields = hash_ref(&storage, key)?;
+        (2..args.len())
+            .map(|i| Ok(fields
+                .and_then(|fields| fields.get(bytes_arg(args, i).ok()?))
+                .map(bulk)
+                .unwrap_or(RObject::NullBulkString)))
+            .collect::<Result<Vec<_>, RedisError>>()?
+    };
+
+    stream.write_all(&RObject::Array(values).encode()).await?;
+    Ok(())
+}
+
+// HDEL key field [field ...]
+pub async fn handle_hdel(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+
+    let deleted = {
+        let mut storage = storage.write().await;
+        let (deleted, empty) = match hash_mut(&mut storage, key)? {
+            Some(fields) => {
+                let mut deleted = 0;
+                for i in 2..args.len() {
+                    if fields.remove(bytes_arg(args, i)?).is_some() {
+                        deleted += 1;
+                    }
+                }
+                (deleted, fields.is_empty())
+            },
+            None => (0, false),
+        };
+        storage.dirty += deleted;
+        // redis never keeps empty hashes around
+        if empty {
+            storage.remove(key);
+        }
+        if deleted > 0 {
+            storage.propagate(args.to_vec());
+        }
+        deleted
+    };
+
+    stream.write_all(&RObject::Integer(deleted as i64).encode()).await?;
+    Ok(())
+}
+
+// HEXISTS key field
+pub async fn handle_hexists(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let field = bytes_arg(args, 2)?;
+
+    let exists = hash_ref(&*storage.read().await, key)?.is_some_and(|fields| fields.contains_key(field));
+
+    stream.write_all(&RObject::Integer(exists as i64).encode()).await?;
+    Ok(())
+}
+
+// HLEN key
+pub async fn handle_hlen(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+
+    let len = hash_ref(&*storage.read().await, key)?.map(FieldMap::len).unwrap_or(0);
+
+    stream.write_all(&RObject::Integer(len as i64).encode()).await?;
+    Ok(())
+}
+
+// HSTRLEN key field
+pub async fn handle_hstrlen(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let field = bytes_arg(args, 2)?;
+
+    let len = hash_ref(&*storage.read().await, key)?
+        .and_then(|fields| fields.get(field))
+        .map(Bytes::len)
+        .unwrap_or(0);
+
+    stream.write_all(&RObject::Integer(len as i64).encode()).await?;
+    Ok(())
+}
+
+// HGETALL | HKEYS | HVALS key
+pub async fn handle_hgetall(args: &[RObject], stream: &mut ReplyStream, session: &Session, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let name = command_name(args);
+    let key = bytes_arg(args, 1)?;
+
+    let reply = {
+        let storage = storage.read().await;
+        let fields = hash_ref(&storage, key)?;
+        let pairs = fields.into_iter().flat_map(|fields| fields.iter());
+        match name.as_str() {
+            "hkeys" => RObject::Array(pairs.map(|(field, _)| bulk(field)).collect()),
+            "hvals" => RObject::Array(pairs.map(|(_, value)| bulk(value)).collect()),
+            _ => session.map_reply(pairs.map(|(field, value)| (bulk(field), bulk(value))).collect()),
+        }
+    };
+
+    stream.write_all(&reply.encode()).await?;
+    Ok(())
+}
+
+// HINCRBY key field increment
+pub async fn handle_hincrby(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let field = bytes_arg(args, 2)?;
+    let increment = int_arg::<i64>(args, 3)?;
+
+    let value = {
+        let mut storage = storage.write().await;
+        let current = match hash_ref(&storage, key)?.and_then(|fields| fields.get(field)) {
+            Some(current) => std::str::from_utf8(current)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .ok_or_else(|| RedisError::Err("hash value is not an integer".to_string()))?,
+            None => 0,
+        };
+        let value = current.checked_add(increment)
+            .ok_or_else(|| RedisError::Err("increment or decrement would overflow".to_string()))?;
+        hash_or_create(&mut storage, key)?.insert(field.clone(), value.to_string().into());
+        storage.dirty += 1;
+        storage.propagate(args.to_vec());
+        value
+    };
+
+    stream.write_all(&RObject::Integer(value).encode()).await?;
+    Ok(())
+}
+
+// HINCRBYFLOAT key field increment
+/// Replicated as the HSET of the result, so replicas don't redo the float arithmetic.
+pub async fn handle_hincrbyfloat(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let field = bytes_arg(args, 2)?;
+    let increment_text = str_arg(args, 3).map_err(|_| RedisError::NotFloat)?;
+    let increment = increment_text.parse::<f64>()
+        .ok()
+        .filter(|increment| !increment.is_nan())
+        .ok_or(RedisError::NotFloat)?;
+
+    let value: Bytes = {
+        let mut storage = storage.write().await;
+        let current_text = match hash_ref(&storage, key)?.and_then(|fields| fields.get(field)) {
+            Some(current) => std::str::from_utf8(current)
+                .map_err(|_| RedisError::Err("hash value is not a float".to_string()))?
+                .to_string(),
+            None => "0".to_string(),
+        };
+        let current = current_text.parse::<f64>()
+            .ok()
+            .filter(|current| !current.is_nan())
+            .ok_or_else(|| RedisError::Err("hash value is not a float".to_string()))?;
+        let value = current + increment;
+        if !value.is_finite() {
+            return Err(RedisError::Err("increment would produce NaN or Infinity".to_string()));
+        }
+        let value: Bytes = format_sum(&current_text, increment_text, value).into();
+        hash_or_create(&mut storage, key)?.insert(field.clone(), value.clone());
+        storage.dirty += 1;
+        storage.propagate(vec![
+            RObject::BulkString("HSET".into()),
+            RObject::BulkString(key.clone()),
+            RObject::BulkString(field.clone()),
+            RObject::BulkString(value.clone()),
+        ]);
+        value
+    };
+
+    stream.write_all(&RObject::BulkString(value).encode()).await?;
+    Ok(())
+}
+
+// `current + increment` the way redis prints it, with %.17Lg and without trailing zeros.
+// Redis adds long doubles, so 0.1 + 0.2 comes out as 0.3. Adding the decimal inputs exactly
+// and rounding to 17 digits gives the same, the f64 sum is only used when they don't fit.
+fn format_sum(current: &str, increment: &str, sum: f64) -> String {
+    let exact = parse_decimal(current).zip(parse_decimal(increment)).and_then(|((a, a_exp), (b, b_exp))| {
+        let exponent = a_exp.min(b_exp);
+        let scale = |digits: i128, exp: i64| 10i128.checked_pow(u32::try_from(exp - exponent).ok()?)?.checked_mul(digits);
+        Some((scale(a, a_exp)?.checked_add(scale(b, b_exp)?)?, exponent))
+    });
+    let (digits, exponent) = exact
+        .or_else(|| parse_decimal(&format!("{:.16e}", sum)))
+        .expect("a finite f64 prints as a decimal");
+    format_decimal(digits, exponent)
+}
+
+// a plain or scientific decimal number as digits and a power of ten, None when the digits
+// don't fit
+fn parse_decimal(s: &str) -> Option<(i128, i64)> {
+    let (mantissa, exp) = match s.find(['e', 'E']) {
+        Some(at) => (&s[..at], s[at + 1..].parse::<i32>().ok()? as i64),
+        None => (s, 0),
+    };
+    let (negative, mantissa) = match mantissa.as_bytes().first() {
+        Some(b'-') => (true, &mantissa[1..]),
+        Some(b'+') => (false, &mantissa[1..]),
+        _ => (false, mantissa),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return None;
+    }
+    let mut digits: i128 = 0;
+    for c in whole.bytes().chain(fraction.bytes()) {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        digits = digits.checked_mul(10)?.checked_add((c - b'0') as i128)?;
+    }
+    let exp = exp - fraction.len() as i64;
+    Some((if negative { -digits } else { digits }, exp))
+}
+
+// digits * 10^exponent as printf's %.17g would print it, trailing zeros removed
+fn format_decimal(digits: i128, exponent: i64) -> String {
+    const PRECISION: usize = 17;
+    if digits == 0 {
+        return "0".to_string();
+    }
+    let mut text = digits.unsigned_abs().to_string();
+    let mut exponent = exponent;
+    if text.len() > PRECISION {
+        // round half up to 17 significant digits
+        let dropped = text.len() - PRECISION;
+        let round_up = text.as_bytes()[PRECISION] >= b'5';
+        let mut kept: u128 = text[..PRECISION].parse().expect("digits");
+        if round_up {
+            kept += 1;
+        }
+        text = kept.to_string();
+        exponent += dropped as i64;
+    }
+    let trimmed = text.trim_end_matches('0');
+    exponent += (text.len() - trimmed.len()) as i64;
+    let text = trimmed;
+    // the power of ten of the first digit
+    let magnitude = text.len() as i64 - 1 + exponent;
+    let sign = if digits < 0 { "-" } else { "" };
+    if magnitude < -4 || magnitude >= PRECISION as i64 {
+        let fraction = if text.len() > 1 { format!(".{}", &text[1..]) } else { String::new() };
+        format!("{}{}{}e{}{:02}", sign, &text[..1], fraction, if magnitude < 0 { '-' } else { '+' }, magnitude.abs())
+    } else if exponent >= 0 {
+        format!("{}{}{}", sign, text, "0".repeat(exponent as usize))
+    } else if magnitude >= 0 {
+        let point = (magnitude + 1) as usize;
+        format!("{}{}.{}", sign, &text[..point], &text[point..])
+    } else {
+        format!("{}0.{}{}", sign, "0".repeat((-magnitude - 1) as usize), text)
+    }
+}
+
+// HRANDFIELD key [count [WITHVALUES]]
+pub async fn handle_hrandfield(args: &[RObject], stream: &mut ReplyStream, session: &Session, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let count = match args.get(2) {
+        Some(_) => Some(int_arg::<i64>(args, 2)?),
+        None => None,
+    };
+    let with_values = match args.get(3) {
+        Some(_) if args.len() == 4 && str_arg(args, 3)?.eq_ignore_ascii_case("WITHVALUES") => true,
+        Some(_) => return Err(RedisError::Syntax),
+        None => false,
+    };
+    // redis' bounds, a reply with values is twice as long. Unlike redis the reply is built
+    // whole before it goes out, so a negative count, which repeats fields, is limited further
+    let limit = if with_values { i64::MAX / 2 } else { i64::MAX };
+    if count.is_some_and(|count| !(-limit..=limit).contains(&count) || count < -MAX_REPEATED_FIELDS) {
+        return Err(RedisError::Err("value is out of range".to_string()));
+    }
+
+    let reply = {
+        let storage = storage.read().await;
+        let fields = hash_ref(&storage, key)?;
+        let len = fields.map(FieldMap::len).unwrap_or(0);
+        let random_index = |len: usize| (random_u64() % len as u64) as usize;
+        match (fields, count) {
+            (None, None) => RObject::NullBulkString,
+            (Some(fields), None) => bulk(fields.iter().nth(random_index(len)).expect("index is in range").0),
+            (fields, Some(count)) => {
+                let fields = fields.into_iter().flat_map(FieldMap::iter);
+                let picked: Vec<(&Bytes, &Bytes)> = match count {
+                    _ if len == 0 => vec![],
+                    // a negative count may return the same field several times, the only case
+                    // that needs all of them at hand
+                    count if count < 0 => {
+                        let fields: Vec<(&Bytes, &Bytes)> = fields.collect();
+                        (0..count.unsigned_abs()).map(|_| fields[random_index(len)]).collect()
+                    },
+                    count if count as usize >= len => fields.collect(),
+                    count => {
+                        // reservoir sampling, distinct fields without copying the whole hash
+                        let count = count as usize;
+                        let mut picked = Vec::with_capacity(count);
+                        for (i, field) in fields.enumerate() {
+                            if i < count {
+                                picked.push(field);
+                            } else if let Some(slot) = picked.get_mut(random_index(i + 1)) {
+                                *slot = field;
+                            }
+                        }
+                        picked
+                    },
+                };
+                let items = picked.into_iter().flat_map(|(field, value)| {
+                    match (with_values, session.protocol >= 3) {
+                        (false, _) => vec![bulk(field)],
+                        // RESP3 clients get each pair as its own array
+                        (true, true) => vec![RObject::Array(vec![bulk(field), bulk(value)])],
+                        (true, false) => vec![bulk(field), bulk(value)],
+                    }
+                });
+                RObject::Array(items.collect())
+            },
+        }
+    };
+
+    stream.write_all(&reply.encode()).await?;
+    Ok(())
+}
+
+// HSCAN key cursor [MATCH pattern] [COUNT count] [NOVALUES]
+//
+// Fields are visited in their scan order and the cursor is the position to continue from,
+// so a field present for the whole scan is always returned no matter what changes in between.
+pub async fn handle_hscan(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let cursor = str_arg(args, 2)
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .ok_or_else(|| RedisError::Err("invalid cursor".to_string()))?;
+    let (mut pattern, mut count, mut no_values) = (None, DEFAULT_SCAN_COUNT, false);
+    let mut i = 3;
+    while i < args.len() {
+        match str_arg(args, i)?.to_uppercase().as_str() {
+            "MATCH" if i + 1 < args.len() => {
+                pattern = Some(bytes_arg(args, i + 1)?);
+                i += 2;
+            },
+            "COUNT" if i + 1 < args.len() => {
+                let n = int_arg::<i64>(args, i + 1)?;
+                if n < 1 {
+                    return Err(RedisError::Syntax);
+                }
+                count = n as usize;
+                i += 2;
+            },
+            "NOVALUES" => {
+                no_values = true;
+                i += 1;
+            },
+            _ => return Err(RedisError::Syntax),
+        }
+    }
+
+    let (next, items) = {
+        let storage = storage.read().await;
+        let small = storage.get_entry(key).is_some_and(|entry| entry.value.encoding() == "listpack");
+        let mut batch: Vec<(u64, &Bytes, &Bytes)> = vec![];
+        let mut next = 0;
+        for (position, field, value) in hash_ref(&storage, key)?.into_iter().flat_map(|fields| fields.scan_from(cursor)) {
+            // like redis, a hash small enough to be compact is returned in one go. Fields
+            // sharing a position go together, the cursor can't point between them
+            if !small && batch.len() >= count && batch.last().is_some_and(|(last, _, _)| *last != position) {
+                next = position;
+                break;
+            }
+            batch.push((position, field, value));
+        }
+        let items: Vec<RObject> = batch.iter()
+            .filter(|(_, field, _)| pattern.is_none_or(|pattern| glob_match(pattern, field)))
+            .flat_map(|(_, field, value)| {
+                let mut item = vec![bulk(field)];
+                if !no_values {
+                    item.push(bulk(value));
+                }
+                item
+            })
+            .collect();
+        (next, items)
+    };
+
+    stream.write_all(
+        &RObject::Array(vec![
+            RObject::BulkString(next.to_string().into()),
+            RObject::Array(items),
+        ]).encode()
+    ).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<RObject> {
+        args.iter().map(|arg| RObject::BulkString(Bytes::copy_from_slice(arg.as_bytes()))).collect()
+    }
+
+    fn storage_with_hash(len: usize) -> Arc<RwLock<Db>> {
+        let fields = (0..len).map(|n| (Bytes::from(format!("f{}", n)), Bytes::from(n.to_string()))).collect();
+        let mut db = Db::new();
+        db.insert(Bytes::from("h"), Value::Hash(fields));
+        Arc::new(RwLock::new(db))
+    }
+
+    fn array(reply: &[u8]) -> Vec<RObject> {
+        match RObject::decode(reply, 0).unwrap().0 {
+            RObject::Array(items) => items,
+            other => panic!("not an array: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn formats_float_sums_like_redis() {
+        let sum = |current: &str, increment: &str| {
+            format_sum(current, increment, current.parse::<f64>().unwrap() + increment.parse::<f64>().unwrap())
+        };
+        assert_eq!(sum("0.1", "0.2"), "0.3");
+        assert_eq!(sum("10.5", "0.1"), "10.6");
+        assert_eq!(sum("5.0e3", "2.0e2"), "5200");
+        assert_eq!(sum("1.5", "-1.5"), "0");
+        assert_eq!(sum("-3", "0.25"), "-2.75");
+        assert_eq!(sum("0", "0.0001"), "0.0001");
+        assert_eq!(sum("0", "0.00001"), "1e-05");
+        assert_eq!(sum("1e20", "1"), "1e+20");
+        assert_eq!(sum("12345678901234567", "0"), "12345678901234567");
+        assert_eq!(sum("123456789012345678", "0"), "1.2345678901234568e+17");
+        assert_eq!(sum("999999999999999999", "0"), "1e+18");
+        // digits that don't fit fall back to the f64 sum
+        assert_eq!(sum("1e300", "1e-300"), "1.0000000000000001e+300");
+    }
+
+    #[tokio::test]
+    async fn hincrbyfloat_replicates_the_formatted_result() {
+        let storage = storage_with_hash(0);
+        handle_hset(&command(&["HSET", "h", "f", "0.1"]), &mut vec![], Arc::clone(&storage)).await.unwrap();
+        let mut reply = vec![];
+        handle_hincrbyfloat(&command(&["HINCRBYFLOAT", "h", "f", "0.2"]), &mut reply, Arc::clone(&storage)).await.unwrap();
+        assert_eq!(reply, b"$3\r\n0.3\r\n");
+
+        let mut storage = storage.write().await;
+        assert!(matches!(storage.get(b"h"), Some(Value::Hash(fields)) if fields.get(b"f").is_some_and(|value| value == "0.3")));
+        let propagated = storage.take_propagated();
+        assert_eq!(propagated.last().unwrap(), &command(&["HSET", "h", "f", "0.3"]));
+    }
+
+    #[tokio::test]
+    async fn hrandfield_rejects_counts_out_of_range() {
+        let storage = storage_with_hash(3);
+        let session = Session::new(false);
+        for args in [
+            command(&["HRANDFIELD", "h", &i64::MIN.to_string()]),
+            command(&["HRANDFIELD", "h", &(i64::MIN + 1).to_string()]),
+            command(&["HRANDFIELD", "h", &i64::MAX.to_string(), "WITHVALUES"]),
+            command(&["HRANDFIELD", "h", &(-MAX_REPEATED_FIELDS - 1).to_string()]),
+        ] {
+            let mut reply = vec![];
+            let err = handle_hrandfield(&args, &mut reply, &session, Arc::clone(&storage)).await.unwrap_err();
+            assert_eq!(err.to_string(), "ERR value is out of range");
+            assert!(reply.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn hrandfield_counts() {
+        let storage = storage_with_hash(3);
+        let session = Session::new(false);
+
+        let mut reply = vec![];
+        handle_hrandfield(&command(&["HRANDFIELD", "h", "-5"]), &mut reply, &session, Arc::clone(&storage)).await.unwrap();
+        assert_eq!(array(&reply).len(), 5);
+
+        let mut reply = vec![];
+        handle_hrandfield(&command(&["HRANDFIELD", "h", "2", "WITHVALUES"]), &mut reply, &session, Arc::clone(&storage)).await.unwrap();
+        let items = array(&reply);
+        assert_eq!(items.len(), 4);
+        assert_ne!(items[0], items[2]);
+
+        let mut reply = vec![];
+        handle_hrandfield(&command(&["HRANDFIELD", "h", &i64::MAX.to_string()]), &mut reply, &session, Arc::clone(&storage)).await.unwrap();
+        assert_eq!(array(&reply).len(), 3);
+
+        let mut reply = vec![];
+        handle_hrandfield(&command(&["HRANDFIELD", "h"]), &mut reply, &session, Arc::clone(&storage)).await.unwrap();
+        assert!((0..3).any(|n| reply == format!("$2\r\nf{}\r\n", n).as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn hrandfield_picks_distinct_fields() {
+        let storage = storage_with_hash(100);
+        let session = Session::new(false);
+        for _ in 0..10 {
+            let mut reply = vec![];
+            handle_hrandfield(&command(&["HRANDFIELD", "h", "50"]), &mut reply, &session, Arc::clone(&storage)).await.unwrap();
+            let picked: std::collections::HashSet<RObject> = array(&reply).into_iter().collect();
+            assert_eq!(picked.len(), 50);
+        }
+    }
+
+    #[tokio::test]
+    async fn hscan_returns_every_field() {
+        let storage = storage_with_hash(1000);
+        let mut seen = std::collections::HashSet::new();
+        let mut cursor = "0".to_string();
+        loop {
+            let mut reply = vec![];
+            handle_hscan(&command(&["HSCAN", "h", &cursor, "COUNT", "7", "NOVALUES"]), &mut reply, Arc::clone(&storage)).await.unwrap();
+            let mut reply = array(&reply);
+            let fields = match reply.pop().unwrap() {
+                RObject::Array(fields) => fields,
+                other => panic!("not the fields: {:?}", other),
+            };
+            assert!(fields.len() <= 7);
+            seen.extend(fields);
+            cursor = match reply.pop().unwrap() {
+                RObject::BulkString(cursor) => String::from_utf8(cursor.to_vec()).unwrap(),
+                other => panic!("not a cursor: {:?}", other),
+            };
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(seen.len(), 1000);
+    }
+}
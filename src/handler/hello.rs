@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{error::RedisError, handler::{args::{int_arg, str_arg}, ReplyStream}, protocol::RObject, session::Session, state::ServerRole, State};
+
+// HELLO [protover [AUTH username password] [SETNAME clientname]]
+pub async fn handle_hello(args: &[RObject], stream: &mut ReplyStream, session: &mut Session, state: Arc<RwLock<State>>) -> Result<(), RedisError> {
+    let protocol = match args.get(1) {
+        Some(_) => {
+            let protocol = int_arg::<i64>(args, 1).map_err(|_| RedisError::Err("Protocol version is not an integer or out of range".to_string()))?;
+            if !(2..=3).contains(&protocol) {
+                return Err(RedisError::NoProto);
+            }
+            protocol as u8
+        },
+        None => session.protocol,
+    };
+    let mut i = 2;
+    while i < args.len() {
+        match str_arg(args, i)?.to_uppercase().as_str() {
+            // there are no users besides the default one, which needs no password
+            "AUTH" if i + 2 < args.len() => {
+                if str_arg(args, i + 1)? != "default" {
+                    return Err(RedisError::WrongPass);
+                }
+                i += 3;
+            },
+            // names are not kept, nothing lists the clients
+            "SETNAME" if i + 1 < args.len() => {
+                if str_arg(args, i + 1)?.bytes().any(|b| b <= b' ' || b > b'~') {
+                    return Err(RedisError::Err("Client names cannot contain spaces, newlines or special characters.".to_string()));
+                }
+                i += 2;
+            },
+            option => return Err(RedisError::Err(format!("Syntax error in HELLO option '{}'", option))),
+        }
+    }
+    session.protocol = protocol;
+
+    let role = match state.read().await.role {
+        ServerRole::Master => "master",
+        ServerRole::Slave => "replica",
+    };
+    let bulk = |s: &str| RObject::BulkString(s.to_string().into());
+    let reply = session.map_reply(vec![
+        (bulk("server"), bulk("redis")),
+        (bulk("version"), bulk("7.2.0")),
+        (bulk("proto"), RObject::Integer(protocol as i64)),
+        (bulk("id"), RObject::Integer(session.id as i64)),
+        (bulk("mode"), bulk("standalone")),
+        (bulk("role"), bulk(role)),
+        (bulk("modules"), RObject::Array(vec![])),
+    ]);
+    stream.write_all(&reply.encode()).await?;
+    Ok(())
+}
@@ -1,38 +1,209 @@
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc};
 
-use anyhow::{bail, Error};
-use tokio::{io::AsyncWriteExt, net::TcpStream, sync::RwLock};
+use tokio::{io::AsyncWriteExt, sync::RwLock};
 
-use crate::{protocol::RObject, State};
+use crate::{broadcast::{Broadcaster, SyncState}, clock::now_ms, db::Db, replica::LinkState, state::ServerRole, error::RedisError, handler::{args::str_arg, ReplyStream}, protocol::RObject, State};
 
 pub async fn handle_info(
-    args: &Vec<RObject>,
+    args: &[RObject],
     state: Arc<RwLock<State>>,
-    stream: &mut TcpStream
-) -> Result<(), Error> {
-    let specification = match args.get(1).expect("No specification") {
-        RObject::BulkString(s) => s,
-        _ => bail!("Expect a specification after the info command")
+    storage: Arc<RwLock<Db>>,
+    broadcaster: Arc<RwLock<Broadcaster>>,
+    stream: &mut ReplyStream
+) -> Result<(), RedisError> {
+    let specification = match args.len() {
+        1 => "replication".to_string(),
+        _ => str_arg(args, 1)?.to_lowercase(),
     };
 
-    match specification.as_str() {
-        "replication" => {
-            stream.write(
-                RObject::BulkString(
-                    format!(
+    let sections: &[&str] = match specification.as_str() {
+        "all" | "default" | "everything" => &["persistence", "replication"],
+        "persistence" => &["persistence"],
+        "replication" => &["replication"],
+        // unknown sections are simply empty
+        _ => &[],
+    };
+
+    let mut info = vec![];
+    for section in sections {
+        info.push(match *section {
+            "persistence" => {
+                let dirty = storage.read().await.dirty;
+                let (aof_current_size, aof_base_size) = broadcaster.read().await.aof.as_ref()
+                    .map(|aof| (aof.size, aof.base_size))
+                    .unwrap_or_default();
+                let state = state.read().await;
+                format!(
+                    concat!(
+                        "# Persistence\n",
+                        "rdb_changes_since_last_save:{}\n",
+                        "rdb_bgsave_in_progress:{}\n",
+                        "rdb_last_save_time:{}\n",
+                        "rdb_last_bgsave_status:{}\n",
+                        "aof_enabled:{}\n",
+                        "aof_rewrite_in_progress:{}\n",
+                        "aof_last_bgrewrite_status:{}\n",
+                        "aof_current_size:{}\n",
+                        "aof_base_size:{}\n"
+                    ),
+                    dirty,
+                    state.bgsave_in_progress as u8,
+                    state.lastsave,
+                    if state.last_bgsave_ok { "ok" } else { "err" },
+                    state.appendonly as u8,
+                    state.aof_rewrite_in_progress as u8,
+                    if state.aof_last_rewrite_ok { "ok" } else { "err" },
+                    aof_current_size,
+                    aof_base_size,
+                )
+            },
+            _ => {
+                let broadcaster = broadcaster.read().await;
+                let offset = broadcaster.offset();
+                let state = state.read().await;
+                let mut info = format!(
+                    concat!(
+                        "# Replication\n",
+                        "role:{}\n",
+                    ),
+                    state.role,
+                );
+                if state.role == ServerRole::Slave {
+                    let (host, port) = state.replica_of.as_deref()
+                        .and_then(|address| address.rsplit_once(':'))
+                        .unwrap_or_default();
+                    let connected = state.master_link_state == LinkState::Connected;
+                    let now = now_ms();
+                    info += &format!(
                         concat!(
-                            "role:{}\n",
-                            "master_replid:{}\n",
-                            "master_repl_offset:{}\n"
+                            "master_host:{}\n",
+                            "master_port:{}\n",
+                            "master_link_status:{}\n",
+                            "master_last_io_seconds_ago:{}\n",
+                            "master_sync_in_progress:{}\n",
                         ),
-                        state.read().await.role,
-                        state.read().await.master_replid,
-                        state.read().await.master_repl_offset,
-                    )
-                ).to_string().as_bytes()
-            ).await.expect("Failed to write to stream handling info replication.")
-        }
-        _ => bail!("Specification not allowed")
-    };
+                        host,
+                        port,
+                        if connected { "up" } else { "down" },
+                        if connected { (now.saturating_sub(state.master_last_io) / 1000) as i64 } else { -1 },
+                        (state.master_link_state == LinkState::Sync) as u8,
+                    );
+                    if !connected {
+                        info += &format!("master_link_down_since_seconds:{}\n", now.saturating_sub(state.master_link_down_since) / 1000);
+                    }
+                }
+                info += &format!("connected_slaves:{}\n", broadcaster.subscribers.len() + broadcaster.diskless_waiting.len());
+                for (i, subscriber) in broadcaster.subscribers.iter().enumerate() {
+                    info += &format!(
+                        "slave{}:ip={},port={},state={},offset={},lag={}\n",
+                        i,
+                        subscriber.ip,
+                        subscriber.port,
+                        subscriber.sync_state(),
+                        subscriber.acked_offset.load(Ordering::SeqCst),
+                        subscriber.lag(),
+                    );
+                }
+                // replicas waiting for the next diskless sync to start are yet to be subscribed
+                for (i, (stream, listening_port)) in broadcaster.diskless_waiting.iter().enumerate() {
+                    info += &format!(
+                        "slave{}:ip={},port={},state={},offset=0,lag=0\n",
+                        broadcaster.subscribers.len() + i,
+                        stream.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default(),
+                        listening_port.unwrap_or(0),
+                        SyncState::WaitBgsave,
+                    );
+                }
+                if state.min_replicas_to_write > 0 {
+                    info += &format!("min_slaves_good_slaves:{}\n", broadcaster.good_replicas(state.min_replicas_max_lag));
+                }
+                info += &format!(
+                    concat!(
+                        "master_replid:{}\n",
+                        "master_replid2:{}\n",
+                        "master_repl_offset:{}\n",
+                        "second_repl_offset:{}\n",
+                        "repl_backlog_active:1\n",
+                        "repl_backlog_size:{}\n",
+                        "repl_backlog_first_byte_offset:{}\n",
+                        "repl_backlog_histlen:{}\n"
+                    ),
+                    state.master_replid,
+                    state.master_replid2,
+                    // a replica's stream comes from its master
+                    if state.role == ServerRole::Master { offset } else { state.consumed },
+                    state.second_replid_offset,
+                    broadcaster.backlog.capacity(),
+                    broadcaster.backlog.first_offset(),
+                    broadcaster.backlog.histlen(),
+                );
+                info
+            },
+        });
+    }
+
+    stream.write_all(
+        &RObject::BulkString(info.join("\n").into()).encode()
+    ).await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::broadcast::Preamble;
+
+    async fn replication(state: &Arc<RwLock<State>>, broadcaster: &Arc<RwLock<Broadcaster>>) -> String {
+        let mut reply = vec![];
+        let args = [RObject::BulkString(Bytes::from("INFO")), RObject::BulkString(Bytes::from("replication"))];
+        handle_info(&args, Arc::clone(state), Arc::new(RwLock::new(Db::new())), Arc::clone(broadcaster), &mut reply).await.unwrap();
+        match RObject::decode(&reply, 0).unwrap().0 {
+            RObject::BulkString(info) => String::from_utf8(info.to_vec()).unwrap(),
+            other => panic!("not a bulk string: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_every_replica_with_its_state() {
+        let state = Arc::new(RwLock::new(State::for_tests()));
+        let broadcaster = Arc::new(RwLock::new(Broadcaster::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _online = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (link, _) = listener.accept().await.unwrap();
+        let _waiting = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (waiting_link, _) = listener.accept().await.unwrap();
+        {
+            let mut broadcaster = broadcaster.write().await;
+            broadcaster.subscribe(link, Some(6380), Preamble::Ready(vec![]));
+            broadcaster.subscribers[0].acked_offset.store(42, Ordering::SeqCst);
+            broadcaster.diskless_waiting.push((waiting_link, Some(6381)));
+        }
+
+        let info = replication(&state, &broadcaster).await;
+        assert!(info.contains("role:master\n"), "{}", info);
+        assert!(info.contains("connected_slaves:2\n"), "{}", info);
+        assert!(info.contains("slave0:ip=127.0.0.1,port=6380,state=online,offset=42,lag=0\n"), "{}", info);
+        assert!(info.contains("slave1:ip=127.0.0.1,port=6381,state=wait_bgsave,offset=0,lag=0\n"), "{}", info);
+    }
+
+    #[tokio::test]
+    async fn describes_the_link_of_a_replica() {
+        let state = Arc::new(RwLock::new(State::for_tests()));
+        {
+            let mut state = state.write().await;
+            state.role = ServerRole::Slave;
+            state.replica_of = Some("127.0.0.1:6379".to_string());
+            state.master_link_state = LinkState::Connected;
+            state.master_last_io = now_ms();
+            state.consumed = 99;
+        }
+        let info = replication(&state, &Arc::new(RwLock::new(Broadcaster::default()))).await;
+        for line in ["role:slave", "master_host:127.0.0.1", "master_port:6379", "master_link_status:up", "master_sync_in_progress:0", "master_repl_offset:99"] {
+            assert!(info.contains(&format!("{}\n", line)), "{} missing from {}", line, info);
+        }
+        assert!(!info.contains("master_link_down_since_seconds"));
+    }
+}
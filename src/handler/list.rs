@@ -0,0 +1,440 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{db::Db, error::RedisError, handler::{args::{bytes_arg, command_name, int_arg, str_arg}, blocking::serve_blocked, ReplyStream}, protocol::RObject, quicklist::{End, QuickList}, value::Value};
+
+// LEFT | RIGHT
+pub(crate) fn parse_end(args: &[RObject], index: usize) -> Result<End, RedisError> {
+    match str_arg(args, index)?.to_uppercase().as_str() {
+        "LEFT" => Ok(End::Left),
+        "RIGHT" => Ok(End::Right),
+        _ => Err(RedisError::Syntax),
+    }
+}
+
+fn list_ref<'a>(storage: &'a Db, key: &[u8]) -> Result<Option<&'a QuickList>, RedisError> {
+    match storage.get(key) {
+        Some(Value::List(list)) => Ok(Some(list)),
+        Some(_) => Err(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+fn list_mut<'a>(storage: &'a mut Db, key: &[u8]) -> Result<Option<&'a mut QuickList>, RedisError> {
+    match storage.get_mut(key) {
+        Some(Value::List(list)) => Ok(Some(list)),
+        Some(_) => Err(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+/// Deletes the key once its list is empty, redis never keeps empty lists around.
+fn remove_if_empty(storage: &mut Db, key: &[u8]) {
+    if let Some(Value::List(list)) = storage.get_entry(key).map(|entry| &entry.value) {
+        if list.is_empty() {
+            storage.remove(key);
+        }
+    }
+}
+
+/// A possibly negative index counted from the end, `None` when it falls outside the list.
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { index + len as i64 } else { index };
+    (index >= 0 && (index as usize) < len).then_some(index as usize)
+}
+
+/// The inclusive range LRANGE and LTRIM select, `None` when it is empty.
+fn resolve_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { (start + len).max(0) } else { start };
+    let stop = if stop < 0 { stop + len } else { stop.min(len - 1) };
+    (start <= stop && start < len).then_some((start as usize, stop as usize))
+}
+
+/// Pushes to the list at `key`, creating it unless `only_existing`.
+/// Returns the new length, 0 when the key was missing and left alone.
+pub(crate) fn push_elements(storage: &mut Db, key: &Bytes, end: End, elements: &[Bytes], only_existing: bool) -> Result<usize, RedisError> {
+    if list_mut(storage, key)?.is_none() {
+        if only_existing {
+            return Ok(0);
+        }
+        storage.insert(key.clone(), Value::List(QuickList::new()));
+    }
+    let list = list_mut(storage, key)?.expect("list was just created");
+    elements.iter().for_each(|element| list.push(end, element.clone()));
+    let len = list.len();
+    storage.dirty += elements.len() as u64;
+    storage.blocked.signal(key);
+    Ok(len)
+}
+
+/// Pops up to `count` elements, `None` when there is no such key.
+pub(crate) fn pop_elements(storage: &mut Db, key: &[u8], end: End, count: usize) -> Result<Option<Vec<Bytes>>, RedisError> {
+    let Some(list) = list_mut(storage, key)? else {
+        return Ok(None);
+    };
+    let mut popped = vec![];
+    while popped.len() < count {
+        match list.pop(end) {
+            Some(element) => popped.push(element),
+            None => break,
+        }
+    }
+    storage.dirty += popped.len() as u64;
+    remove_if_empty(storage, key);
+    Ok(Some(popped))
+}
+
+/// Pops from `source` and pushes to `destination`, which may be the same list.
+/// Checks both types before changing anything.
+pub(crate) fn move_element(storage: &mut Db, source: &Bytes, destination: &Bytes, from: End, to: End) -> Result<Option<Bytes>, RedisError> {
+    if source == destination {
+        // rotated in place, popping the last element would delete the key and its TTL
+        let Some(element) = list_mut(storage, source)?.and_then(|list| {
+            let element = list.pop(from)?;
+            list.push(to, element.clone());
+            Some(element)
+        }) else {
+            return Ok(None);
+        };
+        storage.dirty += 1;
+        return Ok(Some(element));
+    }
+    list_ref(storage, destination)?;
+    let Some(element) = pop_elements(storage, source, from, 1)?.and_then(|mut popped| popped.pop()) else {
+        return Ok(None);
+    };
+    push_elements(storage, destination, to, std::slice::from_ref(&element), false)?;
+    Ok(Some(element))
+}
+
+// LPUSH | RPUSH | LPUSHX | RPUSHX key element [element ...]
+pub async fn handle_push(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let name = command_name(args);
+    let end = if name.starts_with('l') { End::Left } else { End::Right };
+    let key = bytes_arg(args, 1)?;
+    let elements = (2..args.len())
+        .map(|i| bytes_arg(args, i).cloned())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let len = {
+        let mut storage = storage.write().await;
+        let len = push_elements(&mut storage, key, end, &elements, name.ends_with('x'))?;
+        if len > 0 {
+            storage.propagate(args.to_vec());
+        }
+        serve_blocked(&mut storage);
+        len
+    };
+
+    stream.write_all(&RObject::Integer(len as i64).encode()).await?;
+    Ok(())
+}
+
+// LPOP | RPOP key [count]
+pub async fn handle_pop(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let end = if command_name(args) == "lpop" { End::Left } else { End::Right };
+    let key = bytes_arg(args, 1)?;
+    if args.len() > 3 {
+        return Err(RedisError::Syntax);
+    }
+    let count = match args.get(2) {
+        Some(_) => {
+            let count = int_arg::<i64>(args, 2)?;
+            if count < 0 {
+                return Err(RedisError::Err("value is out of range, must be positive".to_string()));
+            }
+            Some(count as usize)
+        },
+        None => None,
+    };
+
+    let popped = {
+        let mut storage = storage.write().await;
+        let popped = pop_elements(&mut storage, key, end, count.unwrap_or(1))?;
+        if popped.as_ref().is_some_and(|popped| !popped.is_empty()) {
+            storage.propagate(args.to_vec());
+        }
+        popped
+    };
+
+    let reply = match (popped.as_ref(), count) {
+        (None, None) => RObject::NullBulkString,
+        (None, Some(_)) => RObject::NullArray,
+        (Some(popped), None) => popped.first().cloned().map(RObject::BulkString).unwrap_or(RObject::NullBulkString),
+        (Some(popped), Some(_)) => RObject::Array(popped.iter().cloned().map(RObject::BulkString).collect()),
+    };
+    stream.write_all(&reply.encode()).await?;
+    Ok(())
+}
+
+// LLEN key
+pub async fn handle_llen(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let len = list_ref(&*storage.read().await, key)?.map(QuickList::len).unwrap_or(0);
+
+    stream.write_all(&RObject::Integer(len as i64).encode()).await?;
+    Ok(())
+}
+
+// LRANGE key start stop
+pub async fn handle_lrange(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let start = int_arg::<i64>(args, 2)?;
+    let stop = int_arg::<i64>(args, 3)?;
+
+    let elements = {
+        let storage = storage.read().await;
+        match list_ref(&storage, key)? {
+            Some(list) => match resolve_range(start, stop, list.len()) {
+                Some((start, stop)) => list.iter_from(start)
+                    .take(stop - start + 1)
+                    .cloned()
+                    .map(RObject::BulkString)
+                    .collect(),
+                None => vec![],
+            },
+            None => vec![],
+        }
+    };
+
+    stream.write_all(&RObject::Array(elements).encode()).await?;
+    Ok(())
+}
+
+// LINDEX key index
+pub async fn handle_lindex(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let index = int_arg::<i64>(args, 2)?;
+
+    let element = {
+        let storage = storage.read().await;
+        list_ref(&storage, key)?
+            .and_then(|list| resolve_index(index, list.len()).and_then(|index| list.get(index)))
+            .cloned()
+    };
+
+    stream.write_all(
+        &element.map(RObject::BulkString).unwrap_or(RObject::NullBulkString).encode()
+    ).await?;
+    Ok(())
+}
+
+// LSET key index element
+pub async fn handle_lset(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let index = int_arg::<i64>(args, 2)?;
+    let element = bytes_arg(args, 3)?;
+
+    {
+        let mut storage = storage.write().await;
+        let list = list_mut(&mut storage, key)?
+            .ok_or_else(|| RedisError::Err("no such key".to_string()))?;
+        let index = resolve_index(index, list.len())
+            .ok_or_else(|| RedisError::Err("index out of range".to_string()))?;
+        list.set(index, element.clone());
+        storage.dirty += 1;
+        storage.propagate(args.to_vec());
+    }
+
+    stream.write_all(&RObject::SimpleString("OK".to_string()).encode()).await?;
+    Ok(())
+}
+
+// LREM key count element
+pub async fn handle_lrem(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let count = int_arg::<i64>(args, 2)?;
+    let element = bytes_arg(args, 3)?;
+
+    let removed = {
+        let mut storage = storage.write().await;
+        let removed = match list_mut(&mut storage, key)? {
+            // a negative count removes from the tail, 0 removes every match
+            Some(list) => {
+                let limit = if count == 0 { usize::MAX } else { count.unsigned_abs() as usize };
+                list.remove_matching(element, limit, count < 0)
+            },
+            None => 0,
+        };
+        storage.dirty += removed as u64;
+        remove_if_empty(&mut storage, key);
+        if removed > 0 {
+            storage.propagate(args.to_vec());
+        }
+        removed
+    };
+
+    stream.write_all(&RObject::Integer(removed as i64).encode()).await?;
+    Ok(())
+}
+
+// LTRIM key start stop
+pub async fn handle_ltrim(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let start = int_arg::<i64>(args, 2)?;
+    let stop = int_arg::<i64>(args, 3)?;
+
+    {
+        let mut storage = storage.write().await;
+        let removed = match list_mut(&mut storage, key)? {
+            Some(list) => {
+                let len = list.len();
+                match resolve_range(start, stop, len) {
+                    Some((start, stop)) => {
+                        list.drain_back(len - 1 - stop);
+                        list.drain_front(start);
+                    },
+                    None => list.drain_front(len),
+                }
+                len - list.len()
+            },
+            None => 0,
+        };
+        storage.dirty += removed as u64;
+        remove_if_empty(&mut storage, key);
+        if removed > 0 {
+            storage.propagate(args.to_vec());
+        }
+    }
+
+    stream.write_all(&RObject::SimpleString("OK".to_string()).encode()).await?;
+    Ok(())
+}
+
+// LINSERT key BEFORE | AFTER pivot element
+pub async fn handle_linsert(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let after = match str_arg(args, 2)?.to_uppercase().as_str() {
+        "BEFORE" => false,
+        "AFTER" => true,
+        _ => return Err(RedisError::Syntax),
+    };
+    let pivot = bytes_arg(args, 3)?;
+    let element = bytes_arg(args, 4)?;
+
+    let len = {
+        let mut storage = storage.write().await;
+        let len = match list_mut(&mut storage, key)? {
+            Some(list) => {
+                let position = list.iter().position(|item| item == pivot);
+                match position {
+                    Some(position) => {
+                        list.insert(if after { position + 1 } else { position }, element.clone());
+                        list.len() as i64
+                    },
+                    None => -1,
+                }
+            },
+            None => 0,
+        };
+        if len > 0 {
+            storage.dirty += 1;
+            storage.propagate(args.to_vec());
+        }
+        len
+    };
+
+    stream.write_all(&RObject::Integer(len).encode()).await?;
+    Ok(())
+}
+
+// LPOS key element [RANK rank] [COUNT num-matches] [MAXLEN len]
+pub async fn handle_lpos(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let element = bytes_arg(args, 2)?;
+    let (mut rank, mut count, mut maxlen) = (1i64, None, 0usize);
+    let mut i = 3;
+    while i < args.len() {
+        if i + 1 >= args.len() {
+            return Err(RedisError::Syntax);
+        }
+        match str_arg(args, i)?.to_uppercase().as_str() {
+            "RANK" => {
+                rank = int_arg(args, i + 1)?;
+                if rank == 0 || rank == i64::MIN {
+                    return Err(RedisError::Err("RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the last match".to_string()));
+                }
+            },
+            "COUNT" => {
+                let n = int_arg::<i64>(args, i + 1)?;
+                if n < 0 {
+                    return Err(RedisError::Err("COUNT can't be negative".to_string()));
+                }
+                count = Some(n as usize);
+            },
+            "MAXLEN" => {
+                let n = int_arg::<i64>(args, i + 1)?;
+                if n < 0 {
+                    return Err(RedisError::Err("MAXLEN can't be negative".to_string()));
+                }
+                maxlen = n as usize;
+            },
+            _ => return Err(RedisError::Syntax),
+        }
+        i += 2;
+    }
+
+    let matches: Vec<usize> = {
+        let storage = storage.read().await;
+        match list_ref(&storage, key)? {
+            Some(list) => {
+                let len = list.len();
+                let scanned = if maxlen == 0 { len } else { maxlen.min(len) };
+                // COUNT 0 means every match
+                let wanted = match count {
+                    Some(0) => usize::MAX,
+                    Some(n) => n,
+                    None => 1,
+                };
+                let skip = rank.unsigned_abs() as usize - 1;
+                let positions: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+                    Box::new(list.iter().take(scanned).enumerate()
+                        .filter(|(_, item)| *item == element)
+                        .map(|(position, _)| position))
+                } else {
+                    Box::new(list.iter().rev().take(scanned).enumerate()
+                        .filter(|(_, item)| *item == element)
+                        .map(move |(position, _)| len - 1 - position))
+                };
+                positions.skip(skip).take(wanted).collect()
+            },
+            None => vec![],
+        }
+    };
+
+    let reply = match count {
+        Some(_) => RObject::Array(matches.into_iter().map(|position| RObject::Integer(position as i64)).collect()),
+        None => matches.first().map(|position| RObject::Integer(*position as i64)).unwrap_or(RObject::NullBulkString),
+    };
+    stream.write_all(&reply.encode()).await?;
+    Ok(())
+}
+
+// LMOVE source destination LEFT | RIGHT LEFT | RIGHT, and RPOPLPUSH source destination
+pub async fn handle_lmove(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let source = bytes_arg(args, 1)?;
+    let destination = bytes_arg(args, 2)?;
+    let (from, to) = if command_name(args) == "rpoplpush" {
+        (End::Right, End::Left)
+    } else {
+        (parse_end(args, 3)?, parse_end(args, 4)?)
+    };
+
+    let element = {
+        let mut storage = storage.write().await;
+        let element = move_element(&mut storage, source, destination, from, to)?;
+        if element.is_some() {
+            storage.propagate(args.to_vec());
+        }
+        serve_blocked(&mut storage);
+        element
+    };
+
+    stream.write_all(
+        &element.map(RObject::BulkString).unwrap_or(RObject::NullBulkString).encode()
+    ).await?;
+    Ok(())
+}
@@ -1,4 +1,8 @@
+#[allow(clippy::module_inception)]
 pub mod handler;
+mod args;
+pub mod command_table;
+mod command;
 mod ping;
 mod echo;
 mod set;
@@ -8,6 +12,20 @@ mod replconf;
 mod psync;
 mod wait;
 mod config;
+mod del;
+mod expire;
+mod ttl;
+mod persist;
+mod save;
+mod bgrewriteaof;
+mod replicaof;
+mod role;
+mod object;
+mod list;
+mod blocking;
+mod hash;
+mod hello;
+mod pattern;
 
 pub use handler::*;
 pub(crate) use ping::handle_ping;
@@ -16,6 +34,20 @@ pub(crate) use set::handle_set;
 pub(crate) use get::handle_get;
 pub(crate) use info::handle_info;
 pub(crate) use replconf::handle_replconf;
-pub(crate) use psync::handle_psync;
+pub(crate) use psync::{handle_psync, queue_diskless_sync, PsyncReply};
 pub(crate) use wait::handle_wait;
-pub(crate) use config::handle_config;
\ No newline at end of file
+pub(crate) use config::handle_config;
+pub(crate) use command::handle_command;
+pub(crate) use del::handle_del;
+pub(crate) use expire::handle_expire;
+pub(crate) use ttl::handle_ttl;
+pub(crate) use persist::handle_persist;
+pub(crate) use save::{handle_bgsave, handle_lastsave, handle_save};
+pub(crate) use bgrewriteaof::handle_bgrewriteaof;
+pub(crate) use replicaof::handle_replicaof;
+pub(crate) use role::handle_role;
+pub(crate) use object::{handle_object, handle_type};
+pub(crate) use blocking::handle_blocking_pop;
+pub(crate) use hash::{handle_hdel, handle_hexists, handle_hget, handle_hgetall, handle_hincrby, handle_hincrbyfloat, handle_hlen, handle_hmget, handle_hrandfield, handle_hscan, handle_hset, handle_hsetnx, handle_hstrlen};
+pub(crate) use hello::handle_hello;
+pub(crate) use list::{handle_lindex, handle_linsert, handle_llen, handle_lmove, handle_lpos, handle_lrange, handle_lrem, handle_lset, handle_ltrim, handle_pop, handle_push};
\ No newline at end of file
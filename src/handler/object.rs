@@ -0,0 +1,69 @@
+use std::sync::Arc;
+
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{db::Db, error::RedisError, handler::{args::{bytes_arg, str_arg}, ReplyStream}, protocol::RObject};
+
+// TYPE key
+pub async fn handle_type(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let type_name = storage.read().await.get_entry(key)
+        .map(|entry| entry.value.type_name())
+        .unwrap_or("none");
+
+    stream.write_all(
+        &RObject::SimpleString(type_name.to_string()).encode()
+    ).await?;
+    Ok(())
+}
+
+// OBJECT ENCODING | FREQ | IDLETIME | REFCOUNT key
+pub async fn handle_object(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let subcommand = str_arg(args, 1)?.to_lowercase();
+    if subcommand == "help" {
+        let lines = [
+            "OBJECT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+            "ENCODING <key>",
+            "    Return the kind of internal representation used in order to store the value",
+            "    associated with a <key>.",
+            "FREQ <key>",
+            "    Return the access frequency index of the <key>. The returned integer is",
+            "    proportional to the logarithm of the recent access frequency of the key.",
+            "IDLETIME <key>",
+            "    Return the idle time of the <key>, that is the approximated number of",
+            "    seconds elapsed since the last access to the key.",
+            "REFCOUNT <key>",
+            "    Return the number of references of the value associated with the specified",
+            "    <key>.",
+            "HELP",
+            "    Print this help.",
+        ];
+        stream.write_all(
+            &RObject::Array(lines.iter().map(|line| RObject::SimpleString(line.to_string())).collect()).encode()
+        ).await?;
+        return Ok(());
+    }
+    if !matches!(subcommand.as_str(), "encoding" | "freq" | "idletime" | "refcount") {
+        return Err(RedisError::Err(format!("unknown subcommand '{}'. Try OBJECT HELP.", str_arg(args, 1)?)));
+    }
+    if args.len() != 3 {
+        return Err(RedisError::WrongArity(format!("object|{}", subcommand)));
+    }
+    let key = bytes_arg(args, 2)?;
+
+    let reply = match storage.read().await.get_entry(key) {
+        None => RObject::NullBulkString,
+        Some(entry) => match subcommand.as_str() {
+            "encoding" => RObject::BulkString(entry.value.encoding().into()),
+            "freq" => RObject::Integer(entry.access.frequency() as i64),
+            "idletime" => RObject::Integer(entry.access.idle_seconds() as i64),
+            // values are never shared between keys
+            _ => RObject::Integer(1),
+        },
+    };
+
+    stream.write_all(
+        &reply.encode()
+    ).await?;
+    Ok(())
+}
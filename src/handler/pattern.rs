@@ -0,0 +1,127 @@
+/// Glob-style matching as redis does it for MATCH and KEYS: `*`, `?`, `[...]` classes
+/// with `^` negation and ranges, and `\` escapes.
+///
+/// Iterative, a mismatch only backtracks to the last `*` seen: whatever an earlier `*`
+/// could still absorb, the last one can absorb as well.
+pub(crate) fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // where the pattern continues after the last `*` and where in the string it was tried
+    let mut star: Option<(usize, usize)> = None;
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, s));
+            continue;
+        }
+        if let Some(consumed) = match_one(&pattern[p..], string[s]) {
+            p += consumed;
+            s += 1;
+            continue;
+        }
+        match star {
+            // let the `*` take one more byte and try the rest of the pattern from there
+            Some((after_star, tried)) => {
+                p = after_star;
+                s = tried + 1;
+                star = Some((after_star, s));
+            },
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// whether the pattern element at the start of `pattern` matches `c`, and the bytes it spans
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern {
+        [] | [b'*', ..] => None,
+        [b'?', ..] => Some(1),
+        [b'[', class @ ..] => {
+            let (matched, consumed) = match_class(class, c);
+            matched.then_some(1 + consumed)
+        },
+        [b'\\', escaped, ..] => (*escaped == c).then_some(2),
+        [p, ..] => (*p == c).then_some(1),
+    }
+}
+
+// the part of a pattern after `[`, returns whether `c` is in the class and the bytes it spans
+fn match_class(class: &[u8], c: u8) -> (bool, usize) {
+    let negate = class.first() == Some(&b'^');
+    let mut i = usize::from(negate);
+    let mut matched = false;
+    while i < class.len() && class[i] != b']' {
+        if class[i] == b'\\' && i + 1 < class.len() {
+            matched |= class[i + 1] == c;
+            i += 2;
+        } else if i + 2 < class.len() && class[i + 1] == b'-' && class[i + 2] != b']' {
+            let (low, high) = (class[i].min(class[i + 2]), class[i].max(class[i + 2]));
+            matched |= (low..=high).contains(&c);
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+    // an unterminated class runs to the end of the pattern
+    (matched != negate, (i + 1).min(class.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("user:*", "user:1"));
+        assert!(!matches("user:*", "session:1"));
+        assert!(matches("*:*:name", "user:1:name"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(!matches("exact", "exactly"));
+    }
+
+    #[test]
+    fn matches_classes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("key[0-9]", "key7"));
+        assert!(matches("key[9-0]", "key7"));
+        assert!(!matches("key[0-9]", "keyx"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("a[\\]]b", "a]b"));
+        assert!(!matches("h[ae]llo", "h"));
+    }
+
+    #[test]
+    fn matches_escapes() {
+        assert!(matches("what\\?", "what?"));
+        assert!(!matches("what\\?", "whats"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "x"));
+        assert!(matches("trailing\\", "trailing\\"));
+    }
+
+    #[test]
+    fn matches_long_patterns_without_recursing() {
+        let pattern = "*a".repeat(200_000);
+        assert!(matches(&pattern, &"a".repeat(200_000)));
+        assert!(!matches(&pattern, &"a".repeat(199_999)));
+    }
+
+    #[test]
+    fn backtracks_only_to_the_last_star() {
+        let started = std::time::Instant::now();
+        assert!(!matches("*a*a*a*a*a*a*b", &"a".repeat(60)));
+        assert!(matches("*a*a*a*a*a*a*b", &format!("{}b", "a".repeat(60))));
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,20 @@
+use std::sync::Arc;
+
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{db::Db, error::RedisError, handler::{args::bytes_arg, ReplyStream}, protocol::RObject};
+
+pub async fn handle_persist(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+
+    let mut storage = storage.write().await;
+    let persisted = storage.get_entry(key).is_some_and(|entry| entry.expires_at.is_some())
+        && storage.set_expiry(key, None);
+    if persisted {
+        storage.propagate(args.to_vec());
+    }
+    drop(storage);
+
+    stream.write_all(&RObject::Integer(persisted as i64).encode()).await?;
+    Ok(())
+}
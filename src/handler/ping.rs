@@ -1,17 +1,14 @@
-use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
 
-use anyhow::Error;
-use tokio::{io::AsyncWriteExt, net::TcpStream, sync::RwLock};
+use crate::{error::RedisError, handler::ReplyStream, protocol::RObject};
 
-use crate::{state::ServerRole, protocol::RObject, State};
-
-pub async fn handle_ping(stream: &mut TcpStream, state: Arc<RwLock<State>>) -> Result<(), Error> {
-    if state.read().await.role == ServerRole::Master {
-        stream.write(
-            RObject::SimpleString("PONG".to_string()).to_string().as_bytes()
-        ).await.expect(
-            "error writing response to stream when responding to PING"
-        );
-    }
+pub async fn handle_ping(args: &[RObject], stream: &mut ReplyStream) -> Result<(), RedisError> {
+    let reply = match args.get(1) {
+        Some(message) => message.clone(),
+        None => RObject::SimpleString("PONG".to_string()),
+    };
+    stream.write_all(
+        &reply.encode()
+    ).await?;
     Ok(())
-}
\ No newline at end of file
+}
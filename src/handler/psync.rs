@@ -1,36 +1,114 @@
 use std::sync::Arc;
-use tokio::{io::AsyncWriteExt, sync::RwLock};
+use bytes::Bytes;
+use futures::FutureExt;
+use tokio::{net::TcpStream, sync::RwLock, time::{sleep, Duration}};
 
-use anyhow::Error;
-use tokio::net::TcpStream;
+use crate::{broadcast::{Broadcaster, PendingRdb, Preamble}, db::{Db, Entry}, error::RedisError, handler::args::{int_arg, str_arg}, protocol::RObject, rdb, replica::{generate_replid, LinkState}, state::ServerRole, State};
 
-use crate::{broadcast::Broadcaster, protocol::RObject, State};
-use hex;
+pub enum PsyncReply {
+    /// The replica can be subscribed right away, this goes out ahead of the stream.
+    Subscribe(Preamble),
+    /// A diskless full resync, the payload goes out with the next batch.
+    Diskless,
+}
 
+// dumps on the blocking pool while the replicas it is for are already subscribed
+fn dump_in_background(snapshot: Vec<(Bytes, Entry)>) -> PendingRdb {
+    tokio::task::spawn_blocking(move || rdb::dump(&snapshot))
+        .map(|dumped| dumped.map(Arc::new).map_err(|e| e.to_string()))
+        .boxed()
+        .shared()
+}
+
+// PSYNC replicationid offset
 pub async fn handle_psync(
-    _args: &Vec<RObject>,
-    mut stream: TcpStream,
+    args: &[RObject],
+    capa_eof: bool,
+    storage: Arc<RwLock<Db>>,
     state: Arc<RwLock<State>>,
-    broadcaster: Arc<RwLock<Broadcaster>>
-) -> Result<(), Error> {
-    stream.write(
-        RObject::SimpleString(
-            format!("FULLRESYNC {} 0", state.read().await.master_replid)
-        ).to_string().as_bytes()
-    ).await.expect(
-        "Failed to respond with FULLRESYNC."
-    );
+    broadcaster: &mut Broadcaster,
+) -> Result<PsyncReply, RedisError> {
+    let replid = str_arg(args, 1)?;
+    // ? -1 asks for a full resync
+    let offset = int_arg::<i64>(args, 2)?;
+    let (master_replid, continuable, diskless, replica) = {
+        let state = state.read().await;
+        // a replica only has a stream to pass on while its own master feeds it
+        if state.role == ServerRole::Slave && state.master_link_state != LinkState::Connected {
+            return Err(RedisError::NoMasterLink);
+        }
+        // replicas of our previous master know us by its id, up to where we took over
+        let continuable = replid == state.master_replid
+            || (replid == state.master_replid2 && offset <= state.second_replid_offset);
+        (state.master_replid.clone(), continuable, state.repl_diskless_sync, state.role == ServerRole::Slave)
+    };
+
+    if continuable && offset > 0 {
+        if let Some(missing) = broadcaster.backlog.range_from(offset as usize) {
+            let mut reply = RObject::SimpleString(format!("CONTINUE {}", master_replid)).encode();
+            reply.extend_from_slice(&missing);
+            return Ok(PsyncReply::Subscribe(Preamble::Ready(reply)));
+        }
+    }
 
-    let rdb_str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
-    let rdb_bytes = hex::decode(rdb_str).expect("Failed to decode RDB hex string");
+    // a replica that can't take an unannounced length gets the payload from disk
+    if diskless && capa_eof {
+        return Ok(PsyncReply::Diskless);
+    }
 
-    stream.write(
-        format!("${}\r\n", rdb_bytes.len()).as_bytes()
-    ).await.expect("Failed to write RDB length");
+    // once the writes queued in the keyspace are streamed, the snapshot holds exactly
+    // what the stream holds up to the offset
+    let snapshot = {
+        let mut storage = storage.write().await;
+        broadcaster.propagate(&mut storage, replica);
+        storage.snapshot()
+    };
+    let reply = RObject::SimpleString(
+        format!("FULLRESYNC {} {}", master_replid, broadcaster.offset())
+    ).encode();
+    Ok(PsyncReply::Subscribe(Preamble::FullResync { reply, rdb: dump_in_background(snapshot), eof_mark: None }))
+}
 
-    stream.write(&rdb_bytes).await.expect("Failed to write RDB file");
+/// Queues a replica for a diskless full resync. The first replica of a batch waits
+/// repl-diskless-sync-delay seconds so the ones arriving meanwhile share its snapshot.
+pub async fn queue_diskless_sync(
+    stream: TcpStream,
+    listening_port: Option<u16>,
+    storage: Arc<RwLock<Db>>,
+    state: Arc<RwLock<State>>,
+    broadcaster: Arc<RwLock<Broadcaster>>,
+) {
+    let mut guard = broadcaster.write().await;
+    guard.diskless_waiting.push((stream, listening_port));
+    if guard.diskless_waiting.len() == 1 {
+        drop(guard);
+        tokio::spawn(diskless_sync(storage, state, broadcaster));
+    }
+}
 
-    broadcaster.write().await.subscribe(stream);
+async fn diskless_sync(storage: Arc<RwLock<Db>>, state: Arc<RwLock<State>>, broadcaster: Arc<RwLock<Broadcaster>>) {
+    let delay = state.read().await.repl_diskless_sync_delay;
+    sleep(Duration::from_secs(delay)).await;
 
-    Ok(())
-}
\ No newline at end of file
+    // like a regular full resync, the batch subscribes at the offset the snapshot holds,
+    // the payload itself goes out from the writer of each replica
+    let mut broadcaster = broadcaster.write().await;
+    let waiting = std::mem::take(&mut broadcaster.diskless_waiting);
+    let (replid, replica) = {
+        let state = state.read().await;
+        (state.master_replid.clone(), state.role == ServerRole::Slave)
+    };
+    let snapshot = {
+        let mut storage = storage.write().await;
+        broadcaster.propagate(&mut storage, replica);
+        storage.snapshot()
+    };
+    let reply = RObject::SimpleString(format!("FULLRESYNC {} {}", replid, broadcaster.offset())).encode();
+    let rdb = dump_in_background(snapshot);
+    // the size isn't announced up front, the payload ends with a random 40 byte mark instead
+    let mark = generate_replid();
+    for (stream, listening_port) in waiting {
+        let preamble = Preamble::FullResync { reply: reply.clone(), rdb: rdb.clone(), eof_mark: Some(mark.clone()) };
+        broadcaster.subscribe(stream, listening_port, preamble);
+    }
+}
@@ -1,43 +1,77 @@
 use std::sync::Arc;
 
-use anyhow::{bail, Error};
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio::io::AsyncWriteExt;
 use tokio::sync::RwLock;
 
-use crate::{protocol::RObject, State};
+use crate::{error::RedisError, handler::{args::{int_arg, str_arg}, ReplyStream}, protocol::RObject, session::Session, State};
 
 pub async fn handle_replconf(
-    args: &Vec<RObject>,
-    stream: &mut TcpStream,
+    args: &[RObject],
+    stream: &mut ReplyStream,
+    session: &mut Session,
     state: Arc<RwLock<State>>
-) -> Result<(), Error> {
-    let target = match args.get(1) {
-        Some(RObject::BulkString(s)) => s,
-        _ => bail!("No stateurable target found")
-    };
+) -> Result<(), RedisError> {
+    let target = str_arg(args, 1)?;
 
-    match target.as_str() {
+    match target.to_lowercase().as_str() {
         "listening-port" => {
-            stream.write(
-                RObject::SimpleString("OK".to_string()).to_string().as_bytes()
-            ).await.expect("Failed to respond to replconf");
-        },
-        "GETACK" => {
-            stream.write(
-                RObject::Array(vec![
-                    RObject::BulkString("REPLCONF".to_string()),
-                    RObject::BulkString("ACK".to_string()),
-                    RObject::BulkString(state.read().await.consumed.to_string())
-                ]).to_string().as_bytes()
-            ).await.expect("Failed to respond to replconf GETACK");
+            session.listening_port = Some(int_arg::<u16>(args, 2)?);
+            stream.write_all(
+                &RObject::SimpleString("OK".to_string()).encode()
+            ).await?;
         },
+        // capa <capability> [capa <capability> ...]
         "capa" => {
-            stream.write(
-                RObject::SimpleString("OK".to_string()).to_string().as_bytes()
-            ).await.expect("Failed to respond to replconf");
-        }
-        _ => bail!("Unrecognized replconf target")
+            for i in (2..args.len()).step_by(2) {
+                if str_arg(args, i - 1)?.eq_ignore_ascii_case("capa") && str_arg(args, i)?.eq_ignore_ascii_case("eof") {
+                    session.capa_eof = true;
+                }
+            }
+            stream.write_all(
+                &RObject::SimpleString("OK".to_string()).encode()
+            ).await?;
+        },
+        "getack" => {
+            stream.write_all(
+                &RObject::Array(vec![
+                    RObject::BulkString("REPLCONF".into()),
+                    RObject::BulkString("ACK".into()),
+                    RObject::BulkString(state.read().await.consumed.to_string().into())
+                ]).encode()
+            ).await?;
+        },
+        _ => return Err(RedisError::Err(format!("Unrecognized REPLCONF option: {}", target))),
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<RObject> {
+        args.iter().map(|arg| RObject::BulkString(Bytes::copy_from_slice(arg.as_bytes()))).collect()
+    }
+
+    async fn replconf(args: &[&str], session: &mut Session) -> Vec<u8> {
+        let state = Arc::new(RwLock::new(State::for_tests()));
+        let mut reply = vec![];
+        handle_replconf(&command(args), &mut reply, session, state).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn only_capa_eof_allows_diskless_payloads() {
+        let mut session = Session::new(false);
+        assert_eq!(replconf(&["REPLCONF", "capa", "psync2"], &mut session).await, b"+OK\r\n");
+        assert!(!session.capa_eof);
+        // the value of another option doesn't count
+        replconf(&["REPLCONF", "capa", "psync2", "listening-port", "eof"], &mut session).await;
+        assert!(!session.capa_eof);
+        replconf(&["REPLCONF", "capa", "psync2", "CAPA", "EOF"], &mut session).await;
+        assert!(session.capa_eof);
+    }
+}
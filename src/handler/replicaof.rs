@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{broadcast::Broadcaster, clock::now_ms, db::Db, error::RedisError, handler::{args::str_arg, ReplyStream}, protocol::RObject, replica::{generate_replid, spawn_master_link, LinkState}, state::ServerRole, State};
+
+// REPLICAOF host port | REPLICAOF NO ONE
+pub async fn handle_replicaof(
+    args: &[RObject],
+    stream: &mut ReplyStream,
+    storage: Arc<RwLock<Db>>,
+    state: Arc<RwLock<State>>,
+    broadcaster: Arc<RwLock<Broadcaster>>,
+) -> Result<(), RedisError> {
+    let host = str_arg(args, 1)?;
+    let port = str_arg(args, 2)?;
+
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+        promote(&state, &broadcaster).await;
+        stream.write_all(&RObject::SimpleString("OK".to_string()).encode()).await?;
+        return Ok(());
+    }
+
+    let port: u16 = port.parse().map_err(|_| RedisError::Err("Invalid master port".to_string()))?;
+    let address = format!("{}:{}", host, port);
+    let offset = broadcaster.read().await.offset();
+
+    let mut guard = state.write().await;
+    if guard.role == ServerRole::Slave && guard.replica_of.as_deref() == Some(address.as_str()) {
+        drop(guard);
+        stream.write_all(&RObject::SimpleString("OK Already connected to specified master".to_string()).encode()).await?;
+        return Ok(());
+    }
+    if guard.role == ServerRole::Master {
+        // our own history is what the new master may let us continue, which works out
+        // when it is one of our replicas that got promoted
+        guard.has_cached_master = true;
+        guard.consumed = offset;
+        // what the blocked clients wait for now only comes from the new master, like redis
+        // they are told so rather than left waiting
+        storage.write().await.blocked.unblock_all();
+    }
+    if let Some(link) = guard.master_link_task.take() {
+        link.abort();
+    }
+    guard.role = ServerRole::Slave;
+    guard.replica_of = Some(address.clone());
+    guard.master_link_state = LinkState::Connect;
+    guard.master_link_down_since = now_ms();
+    let link = spawn_master_link(storage, Arc::clone(&state), broadcaster);
+    guard.master_link_task = Some(link);
+    drop(guard);
+
+    eprintln!("Replicating {} as requested by REPLICAOF", address);
+    stream.write_all(&RObject::SimpleString("OK".to_string()).encode()).await?;
+    Ok(())
+}
+
+// turns a replica into a master, the old replid stays valid up to where we got so the
+// other replicas of our old master can continue from us. The backlog is kept as is: the
+// stream we send on from now on continues the one we were applying, and its history is
+// what those replicas may still be missing.
+async fn promote(state: &Arc<RwLock<State>>, broadcaster: &Arc<RwLock<Broadcaster>>) {
+    // held while the link is stopped, so it can't be halfway through passing a command on
+    let broadcaster = broadcaster.write().await;
+    let mut guard = state.write().await;
+    if guard.role == ServerRole::Master {
+        return;
+    }
+    if let Some(link) = guard.master_link_task.take() {
+        link.abort();
+    }
+    let offset = broadcaster.offset();
+    guard.consumed = offset;
+    guard.master_replid2 = std::mem::replace(&mut guard.master_replid, generate_replid());
+    guard.second_replid_offset = offset as i64 + 1;
+    guard.role = ServerRole::Master;
+    guard.replica_of = None;
+    guard.has_cached_master = false;
+    guard.master_link_state = LinkState::Connect;
+    eprintln!("Promoted to master by REPLICAOF NO ONE");
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{blocking::BlockedOp, quicklist::End};
+
+    fn command(args: &[&str]) -> Vec<RObject> {
+        args.iter().map(|arg| RObject::BulkString(Bytes::copy_from_slice(arg.as_bytes()))).collect()
+    }
+
+    struct Server {
+        storage: Arc<RwLock<Db>>,
+        state: Arc<RwLock<State>>,
+        broadcaster: Arc<RwLock<Broadcaster>>,
+    }
+
+    impl Server {
+        fn new() -> Self {
+            Server {
+                storage: Arc::new(RwLock::new(Db::new())),
+                state: Arc::new(RwLock::new(State::for_tests())),
+                broadcaster: Arc::new(RwLock::new(Broadcaster::default())),
+            }
+        }
+
+        async fn replicaof(&self, host: &str, port: &str) -> Result<Vec<u8>, RedisError> {
+            let mut reply = vec![];
+            let args = command(&["REPLICAOF", host, port]);
+            handle_replicaof(&args, &mut reply, Arc::clone(&self.storage), Arc::clone(&self.state), Arc::clone(&self.broadcaster)).await?;
+            Ok(reply)
+        }
+
+        async fn stop_link(&self) {
+            if let Some(link) = self.state.write().await.master_link_task.take() {
+                link.abort();
+            }
+        }
+    }
+
+    // nothing listens there, the link just keeps retrying
+    const NOWHERE: &str = "1";
+
+    #[tokio::test]
+    async fn a_master_becomes_a_replica() {
+        let server = Server::new();
+        server.broadcaster.write().await.feed_replicas(b"*1\r\n$4\r\nPING\r\n");
+        let (_, mut waiting) = server.storage.write().await.blocked.block(vec![Bytes::from("l")], BlockedOp::Pop(End::Left));
+
+        assert_eq!(server.replicaof("127.0.0.1", NOWHERE).await.unwrap(), b"+OK\r\n");
+        {
+            let state = server.state.read().await;
+            assert_eq!(state.role, ServerRole::Slave);
+            assert_eq!(state.replica_of.as_deref(), Some("127.0.0.1:1"));
+            // what we had is what the new master may let us continue
+            assert!(state.has_cached_master);
+            assert_eq!(state.consumed, 14);
+            assert!(state.master_link_task.is_some());
+        }
+        assert!(matches!(waiting.try_recv(), Ok(Err(RedisError::Unblocked))));
+
+        assert_eq!(server.replicaof("127.0.0.1", NOWHERE).await.unwrap(), b"+OK Already connected to specified master\r\n");
+        server.stop_link().await;
+    }
+
+    #[tokio::test]
+    async fn no_one_promotes_a_replica() {
+        let server = Server::new();
+        server.replicaof("127.0.0.1", NOWHERE).await.unwrap();
+        let replid = server.state.read().await.master_replid.clone();
+        server.broadcaster.write().await.feed_replicas(b"*1\r\n$4\r\nPING\r\n");
+
+        assert_eq!(server.replicaof("no", "one").await.unwrap(), b"+OK\r\n");
+        let state = server.state.read().await;
+        assert_eq!(state.role, ServerRole::Master);
+        assert_eq!(state.replica_of, None);
+        assert!(state.master_link_task.is_none());
+        // replicas of the old master may continue from us with its id
+        assert_eq!(state.master_replid2, replid);
+        assert_ne!(state.master_replid, replid);
+        assert_eq!(state.second_replid_offset, 15);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_ports() {
+        let server = Server::new();
+        let err = server.replicaof("127.0.0.1", "port").await.unwrap_err();
+        assert_eq!(err.to_string(), "ERR Invalid master port");
+        assert_eq!(server.state.read().await.role, ServerRole::Master);
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{broadcast::Broadcaster, error::RedisError, handler::ReplyStream, protocol::RObject, state::ServerRole, State};
+
+pub async fn handle_role(
+    stream: &mut ReplyStream,
+    state: Arc<RwLock<State>>,
+    broadcaster: Arc<RwLock<Broadcaster>>,
+) -> Result<(), RedisError> {
+    let reply = {
+        let broadcaster = broadcaster.read().await;
+        let state = state.read().await;
+        match state.role {
+            // master, offset, [[ip, port, acked offset], ...]
+            ServerRole::Master => RObject::Array(vec![
+                RObject::BulkString("master".into()),
+                RObject::Integer(broadcaster.offset() as i64),
+                RObject::Array(broadcaster.subscribers.iter().map(|subscriber| RObject::Array(vec![
+                    RObject::BulkString(subscriber.ip.to_string().into()),
+                    RObject::BulkString(subscriber.port.to_string().into()),
+                    RObject::BulkString(subscriber.acked_offset.load(Ordering::SeqCst).to_string().into()),
+                ])).collect()),
+            ]),
+            // slave, master host, master port, link state, offset
+            ServerRole::Slave => {
+                let (host, port) = state.replica_of.as_deref()
+                    .and_then(|address| address.rsplit_once(':'))
+                    .unwrap_or_default();
+                RObject::Array(vec![
+                    RObject::BulkString("slave".into()),
+                    RObject::BulkString(host.to_string().into()),
+                    RObject::Integer(port.parse().unwrap_or_default()),
+                    RObject::BulkString(state.master_link_state.to_string().into()),
+                    RObject::Integer(state.consumed as i64),
+                ])
+            },
+        }
+    };
+
+    stream.write_all(
+        &reply.encode()
+    ).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replica::LinkState;
+
+    async fn role(state: State) -> RObject {
+        let mut reply = vec![];
+        handle_role(&mut reply, Arc::new(RwLock::new(state)), Arc::new(RwLock::new(Broadcaster::default()))).await.unwrap();
+        RObject::decode(&reply, 0).unwrap().0
+    }
+
+    #[tokio::test]
+    async fn reports_the_master_side() {
+        assert_eq!(role(State::for_tests()).await, RObject::Array(vec![
+            RObject::BulkString("master".into()),
+            RObject::Integer(0),
+            RObject::Array(vec![]),
+        ]));
+    }
+
+    #[tokio::test]
+    async fn reports_the_replica_side() {
+        let mut state = State::for_tests();
+        state.role = ServerRole::Slave;
+        state.replica_of = Some("10.0.0.1:6380".to_string());
+        state.master_link_state = LinkState::Sync;
+        state.consumed = 12;
+        assert_eq!(role(state).await, RObject::Array(vec![
+            RObject::BulkString("slave".into()),
+            RObject::BulkString("10.0.0.1".into()),
+            RObject::Integer(6380),
+            RObject::BulkString("sync".into()),
+            RObject::Integer(12),
+        ]));
+    }
+}
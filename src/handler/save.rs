@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{db::Db, error::RedisError, handler::{args::str_arg, ReplyStream}, protocol::RObject, rdb::save::{bgsave, save}, State};
+
+pub async fn handle_save(stream: &mut ReplyStream, storage: &Arc<RwLock<Db>>, state: &Arc<RwLock<State>>) -> Result<(), RedisError> {
+    save(storage, state).await?;
+    stream.write_all(&RObject::SimpleString("OK".to_string()).encode()).await?;
+    Ok(())
+}
+
+// BGSAVE [SCHEDULE]
+pub async fn handle_bgsave(args: &[RObject], stream: &mut ReplyStream, storage: &Arc<RwLock<Db>>, state: &Arc<RwLock<State>>) -> Result<(), RedisError> {
+    let schedule = match args.len() {
+        1 => false,
+        2 if str_arg(args, 1)?.eq_ignore_ascii_case("schedule") => true,
+        _ => return Err(RedisError::Syntax),
+    };
+
+    if schedule {
+        let mut state = state.write().await;
+        if state.bgsave_in_progress {
+            // the save cron picks it up once the running one is done
+            state.bgsave_scheduled = true;
+            drop(state);
+            stream.write_all(&RObject::SimpleString("Background saving scheduled".to_string()).encode()).await?;
+            return Ok(());
+        }
+    }
+
+    bgsave(storage, state).await?;
+    stream.write_all(&RObject::SimpleString("Background saving started".to_string()).encode()).await?;
+    Ok(())
+}
+
+pub async fn handle_lastsave(stream: &mut ReplyStream, state: &Arc<RwLock<State>>) -> Result<(), RedisError> {
+    let lastsave = state.read().await.lastsave;
+    stream.write_all(&RObject::Integer(lastsave as i64).encode()).await?;
+    Ok(())
+}
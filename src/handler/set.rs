@@ -1,56 +1,202 @@
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
+
 use tokio::sync::RwLock;
 
-use anyhow::Error;
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio::io::AsyncWriteExt;
 
-use crate::{state::ServerRole, protocol::RObject, State};
+use crate::{clock::now_ms, db::Db, error::RedisError, handler::{args::{bytes_arg, int_arg, str_arg}, ReplyStream}, protocol::RObject, value::Value};
 
-pub async fn handle_set(args: &Vec<RObject>, stream: &mut TcpStream, storage: Arc<RwLock<HashMap<String, RObject>>>, state: Arc<RwLock<State>>) -> Result<(), Error> {
-    if args.len() < 3 {
-        return Err(anyhow::anyhow!("SET requires at least 2 arguments"));
-    }
+#[derive(PartialEq, Eq)]
+enum Condition {
+    Nx,
+    Xx,
+}
 
-    let key = match &args[1] {
-        RObject::BulkString(s) => s,
-        _ => return Err(anyhow::anyhow!("Expected BulkString as key")),
-    };
+enum Expiry {
+    /// Absolute deadline in unix milliseconds.
+    At(u64),
+    KeepTtl,
+}
 
-    let value = args[2].clone();
-
-    storage.write().await.insert(key.clone(), value);
-    
-
-    if args.len() >= 5 {
-        if let RObject::BulkString(s) = &args[3] {
-            if s.to_lowercase() == "px" {
-                if let RObject::BulkString(i) = &args[4] {
-                    let i = i.parse::<u64>()?;
-                    let storage = Arc::clone(&storage);
-                    let key = key.clone();
-                    eprintln!("Scheduling expiration in {}ms for key {}", i, key);
-                    tokio::spawn(async move {
-                        tokio::time::sleep(tokio::time::Duration::from_millis(i)).await;
-                        expire(key, storage).await;
-                    });
+struct SetOptions {
+    condition: Option<Condition>,
+    get: bool,
+    expiry: Option<Expiry>,
+}
+
+// SET key value [NX | XX] [GET] [EX seconds | PX milliseconds | EXAT unix-time-seconds | PXAT unix-time-milliseconds | KEEPTTL]
+fn parse_options(args: &[RObject]) -> Result<SetOptions, RedisError> {
+    let mut options = SetOptions { condition: None, get: false, expiry: None };
+    let mut i = 3;
+    while i < args.len() {
+        let option = str_arg(args, i)?.to_uppercase();
+        match option.as_str() {
+            "NX" | "XX" if options.condition.is_none() => {
+                options.condition = Some(if option == "NX" { Condition::Nx } else { Condition::Xx });
+            },
+            "GET" if !options.get => options.get = true,
+            "KEEPTTL" if options.expiry.is_none() => options.expiry = Some(Expiry::KeepTtl),
+            "EX" | "PX" | "EXAT" | "PXAT" if options.expiry.is_none() && i + 1 < args.len() => {
+                i += 1;
+                let value = int_arg::<i64>(args, i)?;
+                if value <= 0 {
+                    return Err(RedisError::Err("invalid expire time in 'set' command".to_string()));
                 }
-            }
+                let value = value as u64;
+                let deadline = match option.as_str() {
+                    "EX" => value.checked_mul(1000).and_then(|ms| ms.checked_add(now_ms())),
+                    "PX" => value.checked_add(now_ms()),
+                    "EXAT" => value.checked_mul(1000),
+                    _ => Some(value),
+                }.ok_or_else(|| RedisError::Err("invalid expire time in 'set' command".to_string()))?;
+                options.expiry = Some(Expiry::At(deadline));
+            },
+            _ => return Err(RedisError::Syntax),
         }
+        i += 1;
     }
+    Ok(options)
+}
+
+/// Replicates relative expirations as `PXAT`, and nothing when NX / XX kept the key untouched.
+pub async fn handle_set(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let key = bytes_arg(args, 1)?;
+    let value = bytes_arg(args, 2)?;
+    let options = parse_options(args)?;
 
-    if state.read().await.role == ServerRole::Master {
-        stream.write(
-            RObject::SimpleString("OK".to_string()).to_string().as_bytes()
-        ).await.expect(
-            "failed to write response to stream handling SET"
-        );
+    let mut guard = storage.write().await;
+    let old = match guard.get(key) {
+        Some(Value::String(s)) => Some(s.clone()),
+        Some(_) if options.get => return Err(RedisError::WrongType),
+        Some(_) => None,
+        None => None,
+    };
+    let exists = guard.contains_key(key);
+    let apply = match options.condition {
+        Some(Condition::Nx) => !exists,
+        Some(Condition::Xx) => exists,
+        None => true,
+    };
+
+    if apply {
+        let mut command = vec![
+            RObject::BulkString("SET".into()),
+            RObject::BulkString(key.clone()),
+            RObject::BulkString(value.clone()),
+        ];
+        let value = Value::String(value.clone());
+        match options.expiry {
+            Some(Expiry::At(deadline)) => {
+                guard.insert_with_expiry(key.clone(), value, Some(deadline));
+                command.push(RObject::BulkString("PXAT".into()));
+                command.push(RObject::BulkString(deadline.to_string().into()));
+            },
+            Some(Expiry::KeepTtl) => {
+                guard.insert_keep_ttl(key.clone(), value);
+                command.push(RObject::BulkString("KEEPTTL".into()));
+            },
+            None => guard.insert(key.clone(), value),
+        }
+        guard.propagate(command);
     }
+    drop(guard);
 
+    let reply = if options.get {
+        old.map(RObject::BulkString).unwrap_or(RObject::NullBulkString)
+    } else if apply {
+        RObject::SimpleString("OK".to_string())
+    } else {
+        RObject::NullBulkString
+    };
+    stream.write_all(
+        &reply.encode()
+    ).await?;
     Ok(())
 }
 
-async fn expire(key: String, storage: Arc<RwLock<HashMap<String, RObject>>>) {
-    eprintln!("Expiring key: {}", key);
-    let mut storage = storage.write().await;
-    storage.remove(&key);
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::quicklist::QuickList;
+
+    fn command(args: &[&str]) -> Vec<RObject> {
+        args.iter().map(|arg| RObject::BulkString(Bytes::copy_from_slice(arg.as_bytes()))).collect()
+    }
+
+    async fn set(args: &[&str], storage: &Arc<RwLock<Db>>) -> Result<Vec<u8>, RedisError> {
+        let mut reply = vec![];
+        handle_set(&command(args), &mut reply, Arc::clone(storage)).await?;
+        Ok(reply)
+    }
+
+    #[tokio::test]
+    async fn rejects_conflicting_options() {
+        let storage = Arc::new(RwLock::new(Db::new()));
+        for args in [
+            ["SET", "k", "v", "NX", "XX"].as_slice(),
+            &["SET", "k", "v", "XX", "NX"],
+            &["SET", "k", "v", "EX", "10", "PX", "100"],
+            &["SET", "k", "v", "KEEPTTL", "EX", "10"],
+            &["SET", "k", "v", "GET", "GET"],
+            &["SET", "k", "v", "EX"],
+        ] {
+            let err = set(args, &storage).await.unwrap_err();
+            assert!(matches!(err, RedisError::Syntax), "{:?}", args);
+        }
+        let err = set(&["SET", "k", "v", "EX", "0"], &storage).await.unwrap_err();
+        assert_eq!(err.to_string(), "ERR invalid expire time in 'set' command");
+        assert!(storage.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn nx_and_xx_depend_on_the_key() {
+        let storage = Arc::new(RwLock::new(Db::new()));
+        assert_eq!(set(&["SET", "k", "a", "XX"], &storage).await.unwrap(), b"$-1\r\n");
+        assert_eq!(set(&["SET", "k", "b", "NX"], &storage).await.unwrap(), b"+OK\r\n");
+        assert_eq!(set(&["SET", "k", "c", "NX"], &storage).await.unwrap(), b"$-1\r\n");
+        assert_eq!(set(&["SET", "k", "d", "XX", "GET"], &storage).await.unwrap(), b"$1\r\nb\r\n");
+        assert!(matches!(storage.read().await.get(b"k"), Some(Value::String(value)) if value == "d"));
+        // only the writes that happened are replicated
+        assert_eq!(storage.write().await.take_propagated().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn get_fails_against_other_types() {
+        let storage = Arc::new(RwLock::new(Db::new()));
+        let list: QuickList = [Bytes::from("a")].into_iter().collect();
+        storage.write().await.insert(Bytes::from("k"), Value::List(list));
+
+        let err = set(&["SET", "k", "v", "GET"], &storage).await.unwrap_err();
+        assert!(matches!(err, RedisError::WrongType));
+        assert!(matches!(storage.read().await.get(b"k"), Some(Value::List(_))));
+
+        // without GET the value is simply replaced
+        assert_eq!(set(&["SET", "k", "v"], &storage).await.unwrap(), b"+OK\r\n");
+        assert!(matches!(storage.read().await.get(b"k"), Some(Value::String(_))));
+    }
+
+    #[tokio::test]
+    async fn keepttl_keeps_the_deadline() {
+        let storage = Arc::new(RwLock::new(Db::new()));
+        let deadline = now_ms() + 100_000;
+        set(&["SET", "k", "a", "PXAT", &deadline.to_string()], &storage).await.unwrap();
+
+        set(&["SET", "k", "b", "KEEPTTL"], &storage).await.unwrap();
+        assert_eq!(storage.read().await.get_entry(b"k").unwrap().expires_at, Some(deadline));
+        assert_eq!(storage.write().await.take_propagated().last().unwrap(), &command(&["SET", "k", "b", "KEEPTTL"]));
+
+        set(&["SET", "k", "c"], &storage).await.unwrap();
+        assert_eq!(storage.read().await.get_entry(b"k").unwrap().expires_at, None);
+    }
+
+    #[tokio::test]
+    async fn replicates_relative_expiries_as_pxat() {
+        let storage = Arc::new(RwLock::new(Db::new()));
+        set(&["SET", "k", "v", "EX", "100"], &storage).await.unwrap();
+        let deadline = storage.read().await.get_entry(b"k").unwrap().expires_at.unwrap();
+        assert!(deadline > now_ms() + 90_000);
+        assert_eq!(storage.write().await.take_propagated(), [command(&["SET", "k", "v", "PXAT", &deadline.to_string()])]);
+    }
+}
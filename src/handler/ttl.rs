@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{clock::now_ms, db::Db, error::RedisError, handler::{args::{bytes_arg, command_name}, ReplyStream}, protocol::RObject};
+
+/// TTL, PTTL, EXPIRETIME and PEXPIRETIME.
+///
+/// -2 for a missing key, -1 for a key without a deadline.
+pub async fn handle_ttl(args: &[RObject], stream: &mut ReplyStream, storage: Arc<RwLock<Db>>) -> Result<(), RedisError> {
+    let name = command_name(args);
+    let key = bytes_arg(args, 1)?;
+
+    let expires_at = match storage.read().await.get_entry(key) {
+        Some(entry) => entry.expires_at,
+        None => {
+            stream.write_all(&RObject::Integer(-2).encode()).await?;
+            return Ok(());
+        },
+    };
+
+    let reply = match expires_at {
+        None => -1,
+        Some(deadline) => {
+            let remaining = deadline.saturating_sub(now_ms());
+            let reply = match name.as_str() {
+                "ttl" => (remaining + 500) / 1000,
+                "pttl" => remaining,
+                "expiretime" => deadline / 1000,
+                _ => deadline,
+            };
+            reply as i64
+        },
+    };
+
+    stream.write_all(&RObject::Integer(reply).encode()).await?;
+    Ok(())
+}
@@ -1,90 +1,160 @@
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 
-use anyhow::{bail, Error};
-use futures::stream::FuturesUnordered;
-use futures::StreamExt;
-use tokio::{io::AsyncWriteExt, net::TcpStream, sync::RwLock};
-use std::time::Duration;
+use tokio::{io::AsyncWriteExt, sync::RwLock, time::{sleep_until, Duration, Instant}};
 
-use crate::{broadcast::Broadcaster, protocol::RObject, State};
+use crate::{broadcast::Broadcaster, error::RedisError, handler::{args::int_arg, ReplyStream}, protocol::RObject, session::Session, state::ServerRole, State};
 
+// WAIT numreplicas timeout
 pub async fn handle_wait(
-    args: &Vec<RObject>, 
-    stream: &mut TcpStream, 
-    _storage: Arc<RwLock<HashMap<String, RObject>>>, 
-    _state: Arc<RwLock<State>>,
+    args: &[RObject],
+    stream: &mut ReplyStream,
+    session: &Session,
+    state: Arc<RwLock<State>>,
     broadcaster: Arc<RwLock<Broadcaster>>,
-) -> Result<(), Error> { 
-    let wait_time = Duration::from_millis(
-        match &args[2] {
-            RObject::BulkString(s) => {
-                s.parse::<u64>().expect("Failed to parse timeout")
-            }
-            _ => bail!("Timeout is not found")
+) -> Result<(), RedisError> {
+    if state.read().await.role != ServerRole::Master {
+        return Err(RedisError::Err("WAIT cannot be used with replica instances.".to_string()));
+    }
+    let expect_count = int_arg::<usize>(args, 1)?;
+    let timeout = int_arg::<i64>(args, 2)?;
+    if timeout < 0 {
+        return Err(RedisError::Err("timeout is negative".to_string()));
+    }
+    // 0 waits for as long as it takes
+    let deadline = (timeout > 0).then(|| Instant::now() + Duration::from_millis(timeout as u64));
+
+    // everything this client wrote is covered once a replica acked its last write
+    let target = session.write_offset;
+    let acks = Arc::clone(&broadcaster.read().await.acks);
+    let mut asked = false;
+
+    let count = loop {
+        // registered before counting so an ack arriving in between still wakes us
+        let notified = acks.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let count = broadcaster.read().await.acked_replicas(target);
+        if count >= expect_count {
+            break count;
+        }
+        if !asked {
+            // don't wait for the periodic acks, ask for them through the stream
+            asked = true;
+            let getack = RObject::Array(vec![
+                RObject::BulkString("REPLCONF".into()),
+                RObject::BulkString("GETACK".into()),
+                RObject::BulkString("*".into()),
+            ]);
+            broadcaster.write().await.feed_replicas(&getack.encode());
         }
-    );
-    let expect_count = match &args[1] {
-        RObject::BulkString(s) => {
-            s.parse::<usize>().expect("Failed to parse expect_count")
+
+        match deadline {
+            Some(deadline) => tokio::select! {
+                _ = &mut notified => {},
+                _ = sleep_until(deadline) => break broadcaster.read().await.acked_replicas(target),
+            },
+            None => notified.await,
         }
-        _ => bail!("Expected count is not found")
     };
 
-    // get around the previous stage
-    if expect_count == 0 {
-        stream.write(
-            RObject::Integer(
-                0
-            ).to_string().as_bytes()
-        ).await.expect("Failed to write to stream handling wait.");
-        return Ok(());
-    }
+    stream.write_all(
+        &RObject::Integer(
+            count as i64
+        ).encode()
+    ).await?;
 
-    // get around the previous stage
-    if broadcaster.read().await.broadcasted == 0 {
-        stream.write(
-            RObject::Integer(
-                broadcaster.read().await.subscribers.len() as i64
-            ).to_string().as_bytes()
-        ).await.expect("Failed to write to stream handling wait.");
-        return Ok(());
-    }
+    Ok(())
+}
 
-    let mut broadcaster = broadcaster.write().await;
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::{io::AsyncReadExt, net::{TcpListener, TcpStream}};
 
-    let mut futures: FuturesUnordered<_> = broadcaster.ask_ack(wait_time).into_iter().collect();
+    use super::*;
+    use crate::broadcast::Preamble;
 
-    let mut cnt = 0;
-    let mut timer = Box::pin(tokio::time::sleep(wait_time));
+    fn command(args: &[&str]) -> Vec<RObject> {
+        args.iter().map(|arg| RObject::BulkString(Bytes::copy_from_slice(arg.as_bytes()))).collect()
+    }
 
-    loop {
-        tokio::select! {
-            _ = &mut timer => {
-                // The timer has timed out, break the loop
-                break;
-            }
-            Some(result) = futures.next(), if cnt < expect_count => {
-                // A future has completed
-                if let Ok(Some(_)) = result {
-                    cnt += 1;
-                }
-            }
-            else => {
-                // All futures have completed or we have reached the expected count
-                break;
+    // attaches a replica that already synced, returns the replica's end of the link
+    async fn attach_replica(broadcaster: &Arc<RwLock<Broadcaster>>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut replica = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (master, _) = listener.accept().await.unwrap();
+        broadcaster.write().await.subscribe(master, None, Preamble::Ready(b"+CONTINUE\r\n".to_vec()));
+        let mut continued = [0; 11];
+        replica.read_exact(&mut continued).await.unwrap();
+        replica
+    }
+
+    async fn ack(replica: &mut TcpStream, offset: usize, broadcaster: &Arc<RwLock<Broadcaster>>) {
+        replica.write_all(&RObject::Array(command(&["REPLCONF", "ACK", &offset.to_string()])).encode()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while broadcaster.read().await.acked_replicas(offset) == 0 {
+                tokio::task::yield_now().await;
             }
-        }
+        }).await.unwrap();
     }
-    for future in futures {
-        future.abort();
+
+    async fn wait(args: &[&str], session: &Session, state: &Arc<RwLock<State>>, broadcaster: &Arc<RwLock<Broadcaster>>) -> Result<Vec<u8>, RedisError> {
+        let mut reply = vec![];
+        handle_wait(&command(args), &mut reply, session, Arc::clone(state), Arc::clone(broadcaster)).await?;
+        Ok(reply)
     }
 
+    #[tokio::test]
+    async fn counts_the_replicas_that_acked_the_last_write() {
+        let state = Arc::new(RwLock::new(State::for_tests()));
+        let broadcaster = Arc::new(RwLock::new(Broadcaster::default()));
+        let mut first = attach_replica(&broadcaster).await;
+        let mut second = attach_replica(&broadcaster).await;
+        let mut session = Session::new(false);
 
-    stream.write(
-        RObject::Integer(
-            cnt as i64
-        ).to_string().as_bytes()
-    ).await.expect("Failed to write to stream handling wait.");
+        // nothing written yet, every replica is caught up
+        assert_eq!(wait(&["WAIT", "5", "10"], &session, &state, &broadcaster).await.unwrap(), b":2\r\n");
 
-    Ok(())
-}
\ No newline at end of file
+        session.write_offset = 100;
+        ack(&mut first, 100, &broadcaster).await;
+        ack(&mut second, 60, &broadcaster).await;
+        assert_eq!(wait(&["WAIT", "1", "0"], &session, &state, &broadcaster).await.unwrap(), b":1\r\n");
+        assert_eq!(wait(&["WAIT", "2", "50"], &session, &state, &broadcaster).await.unwrap(), b":1\r\n");
+    }
+
+    #[tokio::test]
+    async fn asks_for_acks_and_wakes_up_on_them() {
+        let state = Arc::new(RwLock::new(State::for_tests()));
+        let broadcaster = Arc::new(RwLock::new(Broadcaster::default()));
+        let mut replica = attach_replica(&broadcaster).await;
+        let mut session = Session::new(false);
+        session.write_offset = 42;
+
+        let waiting = {
+            let (state, broadcaster) = (Arc::clone(&state), Arc::clone(&broadcaster));
+            tokio::spawn(async move { wait(&["WAIT", "1", "0"], &session, &state, &broadcaster).await.unwrap() })
+        };
+        let getack = RObject::Array(command(&["REPLCONF", "GETACK", "*"])).encode();
+        let mut received = vec![0; getack.len()];
+        replica.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, getack);
+
+        replica.write_all(&RObject::Array(command(&["REPLCONF", "ACK", "42"])).encode()).await.unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert_eq!(reply, b":1\r\n");
+    }
+
+    #[tokio::test]
+    async fn rejects_replicas_and_negative_timeouts() {
+        let state = Arc::new(RwLock::new(State::for_tests()));
+        let broadcaster = Arc::new(RwLock::new(Broadcaster::default()));
+        let session = Session::new(false);
+        let err = wait(&["WAIT", "1", "-1"], &session, &state, &broadcaster).await.unwrap_err();
+        assert_eq!(err.to_string(), "ERR timeout is negative");
+
+        state.write().await.role = ServerRole::Slave;
+        let err = wait(&["WAIT", "1", "0"], &session, &state, &broadcaster).await.unwrap_err();
+        assert_eq!(err.to_string(), "ERR WAIT cannot be used with replica instances.");
+    }
+}
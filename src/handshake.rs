@@ -1,121 +1,322 @@
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::{future::Future, sync::Arc};
+use tokio::{sync::RwLock, time::{timeout, Duration}};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-
-use anyhow::Error;
+use anyhow::{anyhow, bail, Context, Error};
 use tokio::net::TcpStream;
-use crate::BUFFER_SIZE;
-use crate::{protocol::RObject, State};
+use crate::{aof, broadcast::Broadcaster, clock::now_ms, db::Db, protocol::RObject, rdb, replica::LinkState, State};
 
-pub async fn handshake(
-    state: Arc<RwLock<State>>
-) -> Result<Option<TcpStream>, Error> {
-    let address = state.read().await.replica_of.clone().unwrap();
-    if address.len() == 0 {
-        return Ok(None);
+const EOF_MARK_LEN: usize = 40;
+// how much of a diskless payload is read at once
+const PAYLOAD_CHUNK: usize = 64 * 1024;
+
+fn command(args: &[&str]) -> Vec<u8> {
+    RObject::Array(
+        args.iter().map(|arg| RObject::BulkString(arg.to_string().into())).collect()
+    ).encode()
+}
+
+// sends a handshake command and fails unless the master answers with a simple string
+async fn exchange(stream: &mut TcpStream, state: &Arc<RwLock<State>>, args: &[&str]) -> Result<String, Error> {
+    stream.write_all(&command(args)).await
+        .with_context(|| format!("Failed to send {} to master", args[0]))?;
+    let reply = read_reply_line(stream, state).await
+        .with_context(|| format!("Failed to read the {} reply from master", args[0]))?;
+    match reply.strip_prefix('+') {
+        Some(reply) => Ok(reply.to_string()),
+        None => bail!("Master answered {} with {}", args[0], reply),
     }
-    
-    let mut stream = TcpStream::connect(address).await.expect("Failed to connect to master");
+}
+
+/// How the master answered PSYNC.
+pub enum Resync {
+    /// The stream picks up right after what the replica already has.
+    Partial,
+    /// The master's dataset follows, then the stream from `offset` on.
+    Full { replid: String, offset: usize },
+}
 
+/// Runs the replica side of the handshake on a fresh connection to the master, up to the
+/// answer to PSYNC.
+///
+/// PSYNC asks to continue from the last processed offset when a previous link left one.
+pub async fn handshake(
+    stream: &mut TcpStream,
+    state: &Arc<RwLock<State>>,
+    broadcaster: &Arc<RwLock<Broadcaster>>,
+) -> Result<Resync, Error> {
     // 1. s->m ping
-    stream.write_all(
-        RObject::Array(
-            vec![
-                RObject::BulkString("PING".to_string())
-            ]
-        ).to_string().as_bytes()
-    ).await.expect("Failed to ping when handshaking with master");
-    
-    let mut ping_response_buffer = [0; BUFFER_SIZE];
-    stream.read(&mut ping_response_buffer).await.expect("Failed to receive ping response when handshaking");
-    // let (ping_res, _) = RObject::decode(std::str::from_utf8(&ping_response_buffer).expect(
-    //     "Failed to decode ping response when handshaking."
-    // ), 0).expect("Failed to parse the ping response when handshaking.");
+    exchange(stream, state, &["PING"]).await?;
 
     // 2. s->m replconf listening-port <>
-    // replconf cap psync2
-
-    stream.write_all(
-        RObject::Array(
-            vec![
-                RObject::BulkString("REPLCONF".to_string()),
-                RObject::BulkString("listening-port".to_string()),
-                RObject::BulkString(
-                    format!(
-                        "{}", state.read().await.working_port
-                    )
-                )
-            ]
-        ).to_string().as_bytes()
-    ).await.expect("Failed to state listening port");
-
-    let mut replconf_listening_port_response = [0; BUFFER_SIZE];
-    stream.read(&mut replconf_listening_port_response).await.expect("Failed to receive response ");
-
-    stream.write_all(
-        RObject::Array(
-            vec![
-                RObject::BulkString("REPLCONF".to_string()),
-                RObject::BulkString("capa".to_string()),
-                RObject::BulkString("psync2".to_string())
-            ]
-        ).to_string().as_bytes()
-    ).await.expect("Failed to state listening port");
-
-    let mut replconf_capa_response = [0; BUFFER_SIZE];
-    stream.read(&mut replconf_capa_response).await.expect("Failed to receive capa responose when handshaking");
-
-    // 3. m->s psync ? -1
-    stream.write_all(
-        RObject::Array(
-            vec![
-                RObject::BulkString("PSYNC".to_string()),
-                RObject::BulkString("?".to_string()),
-                RObject::BulkString("-1".to_string())
-            ]
-        ).to_string().as_bytes()
-    ).await.expect("Failed to send psync");
-
-    // read until meet \r\n
-    let mut psync_response = Vec::new();
+    // replconf capa eof capa psync2
+    let port = state.read().await.working_port.to_string();
+    exchange(stream, state, &["REPLCONF", "listening-port", &port]).await?;
+    exchange(stream, state, &["REPLCONF", "capa", "eof", "capa", "psync2"]).await?;
+
+    // 3. s->m psync <replid> <offset + 1>, or ? -1 the first time
+    let (replid, offset) = {
+        let mut state = state.write().await;
+        state.master_link_state = LinkState::Sync;
+        if state.has_cached_master {
+            (state.master_replid.clone(), (state.consumed + 1).to_string())
+        } else {
+            ("?".to_string(), "-1".to_string())
+        }
+    };
+    let psync_response = exchange(stream, state, &["PSYNC", &replid, &offset]).await?;
+    eprintln!("PSYNC response: {}", psync_response);
+
+    let mut words = psync_response.split_whitespace();
+    match words.next() {
+        // +CONTINUE [<new replid>], the stream picks up after what we already have
+        Some("CONTINUE") => {
+            if let Some(new_replid) = words.next() {
+                // the master was promoted, keep answering to the old id like it does and
+                // let our own replicas reconnect to learn the new one
+                let mut broadcaster = broadcaster.write().await;
+                let mut state = state.write().await;
+                if new_replid != state.master_replid {
+                    state.master_replid2 = std::mem::replace(&mut state.master_replid, new_replid.to_string());
+                    state.second_replid_offset = state.consumed as i64 + 1;
+                    broadcaster.disconnect_replicas();
+                }
+            }
+            Ok(Resync::Partial)
+        },
+        // +FULLRESYNC <replid> <offset>, the stream that follows starts at that offset
+        Some("FULLRESYNC") => {
+            let replid = words.next().ok_or_else(|| anyhow!("Missing replid in FULLRESYNC"))?.to_string();
+            let offset = words.next()
+                .and_then(|offset| offset.parse::<usize>().ok())
+                .ok_or_else(|| anyhow!("Missing offset in FULLRESYNC"))?;
+            Ok(Resync::Full { replid, offset })
+        },
+        _ => bail!("Unexpected PSYNC response: {}", psync_response),
+    }
+}
+
+/// Finishes what the handshake started: a full resync replaces the dataset with the payload
+/// that follows. Returns the start of the replication stream read along with a diskless
+/// payload, the stream itself continues right after it.
+///
+/// A large payload may take long, only a master silent for the whole repl-timeout is gone.
+pub async fn receive_payload(
+    stream: &mut TcpStream,
+    resync: Resync,
+    state: &Arc<RwLock<State>>,
+    storage: &Arc<RwLock<Db>>,
+    broadcaster: &Arc<RwLock<Broadcaster>>,
+) -> Result<Vec<u8>, Error> {
+    let Resync::Full { replid, offset } = resync else {
+        return Ok(vec![]);
+    };
+    let rest = full_sync(stream, state, storage, broadcaster).await?;
+    // our history is gone, so is the one our replicas share with us
+    let mut broadcaster = broadcaster.write().await;
+    broadcaster.disconnect_replicas();
+    broadcaster.backlog.restart_at(offset);
+    let mut state = state.write().await;
+    state.master_replid = replid;
+    state.master_replid2 = "0".repeat(40);
+    state.second_replid_offset = -1;
+    state.consumed = offset;
+    state.has_cached_master = true;
+    Ok(rest)
+}
+
+async fn full_sync(
+    stream: &mut TcpStream,
+    state: &Arc<RwLock<State>>,
+    storage: &Arc<RwLock<Db>>,
+    broadcaster: &Arc<RwLock<Broadcaster>>,
+) -> Result<Vec<u8>, Error> {
+    eprintln!("Ready to receive RDB file");
+    let limit = Duration::from_secs(state.read().await.repl_timeout);
+    // $<length>\r\n<rdb> without the trailing \r\n, or $EOF:<mark>\r\n<rdb><mark> when diskless
+    let header = read_reply_line(stream, state).await.context("Failed to read RDB length")?;
+    let (rdb_buf, rest) = match header.strip_prefix("$EOF:") {
+        Some(mark) if mark.len() == EOF_MARK_LEN => {
+            eprintln!("Receiving a diskless RDB");
+            read_until_mark(stream, mark.as_bytes(), limit).await.context("Failed to read RDB file")?
+        },
+        _ => {
+            let len: usize = header.strip_prefix('$')
+                .and_then(|len| len.parse().ok())
+                .ok_or_else(|| anyhow!("Invalid RDB length line: {}", header))?;
+            eprintln!("RDB length: {}", len);
+            let mut rdb_buf = vec![0; len];
+            let mut filled = 0;
+            while filled < len {
+                let n = within(limit, stream.read(&mut rdb_buf[filled..])).await.context("Failed to read RDB file")?;
+                if n == 0 {
+                    bail!("Connection closed before the end of the payload");
+                }
+                filled += n;
+            }
+            (rdb_buf, vec![])
+        },
+    };
+    state.write().await.master_last_io = now_ms();
+
+    // a full resync replaces whatever we had, including what was loaded from disk
+    let mut db = Db::new();
+    let loaded = rdb::load(&rdb_buf, &mut db)?;
+    db.dirty = 0;
+
+    // the AOF has to start over from the new dataset, holding the broadcaster keeps
+    // it in step with the keyspace
+    let mut broadcaster = broadcaster.write().await;
+    if broadcaster.aof.is_some() {
+        let (dir, appendfilename, policy) = {
+            let state = state.read().await;
+            (state.aof_dir(), state.appendfilename.clone(), state.appendfsync)
+        };
+        broadcaster.aof = Some(aof::create(&dir, &appendfilename, policy, &db.snapshot())?);
+    }
+    *storage.write().await = db;
+    drop(broadcaster);
+
+    eprintln!("Loaded {} keys from master", loaded);
+    Ok(rest)
+}
+
+// reads in large pieces and scans for the mark, the replication stream follows it right away
+// so the last read may take some of it along, that part is returned separately
+async fn read_until_mark(stream: &mut TcpStream, mark: &[u8], limit: Duration) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut buf = Vec::new();
+    let mut chunk = vec![0; PAYLOAD_CHUNK];
     loop {
-        let mut byte = [0; 1];
-        stream.read_exact(&mut byte).await.expect("Failed to read byte");
-        // read until we reach the \r\n
-        if byte[0] == b'\n' && psync_response.last() == Some(&b'\r') {
-            psync_response.pop(); // Remove the '\r'
-            break;
+        let n = within(limit, stream.read(&mut chunk)).await?;
+        if n == 0 {
+            bail!("Connection closed before the end of the payload");
+        }
+        // the mark may straddle the previous read
+        let from = buf.len().saturating_sub(mark.len() - 1);
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(at) = buf[from..].windows(mark.len()).position(|window| window == mark) {
+            let end = from + at;
+            let rest = buf.split_off(end + mark.len());
+            buf.truncate(end);
+            return Ok((buf, rest));
         }
-        psync_response.push(byte[0]);
     }
+}
 
+// a read that must make progress within the repl-timeout
+async fn within<T, E: Into<Error>>(limit: Duration, read: impl Future<Output = Result<T, E>>) -> Result<T, Error> {
+    match timeout(limit, read).await {
+        Ok(read) => read.map_err(Into::into),
+        Err(_) => bail!("No data from master for {} seconds", limit.as_secs()),
+    }
+}
 
-    eprintln!("PSYNC response: {}", String::from_utf8_lossy(&psync_response));
+// redis may send bare newlines as keepalives while it prepares the payload, each one counts
+// as the master still being there
+async fn read_reply_line(stream: &mut TcpStream, state: &Arc<RwLock<State>>) -> Result<String, Error> {
+    loop {
+        let limit = Duration::from_secs(state.read().await.repl_timeout);
+        let line = within(limit, read_line(stream)).await?;
+        state.write().await.master_last_io = now_ms();
+        if !line.is_empty() {
+            return Ok(String::from_utf8_lossy(&line).to_string());
+        }
+    }
+}
 
-    eprintln!("Ready to receive RDB file");
-    // Read the length of the RDB file
-    let mut len_buf = Vec::new();
-    // the first byte is the '$'
-    let mut dollar = [0; 1];
-    eprintln!("Reading the dollar");
-    stream.read_exact(&mut dollar).await.expect("Failed to read byte");
-    eprintln!("Reading the length");
+// reads a single \r\n terminated line byte by byte, so that nothing after it is consumed
+async fn read_line(stream: &mut TcpStream) -> Result<Vec<u8>, Error> {
+    let mut line = Vec::new();
     loop {
         let mut byte = [0; 1];
-        stream.read_exact(&mut byte).await.expect("Failed to read byte");
+        stream.read_exact(&mut byte).await?;
         // read until we reach the \r\n
-        if byte[0] == b'\n' && len_buf.last() == Some(&b'\r') {
-            len_buf.pop(); // Remove the '\r'
+        if byte[0] == b'\n' {
+            if line.last() == Some(&b'\r') {
+                line.pop(); // Remove the '\r'
+            }
             break;
         }
-        len_buf.push(byte[0]);
+        line.push(byte[0]);
+    }
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{db::Entry, value::Value};
+
+    const MARK: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    // the master's end and the replica's end of a fresh link
+    async fn link() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let replica = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (master, _) = listener.accept().await.unwrap();
+        (master, replica)
+    }
+
+    #[tokio::test]
+    async fn finds_a_mark_split_across_reads() {
+        let (mut master, mut replica) = link().await;
+        let payload: Vec<u8> = (0..PAYLOAD_CHUNK * 2 + 7).map(|n| n as u8).collect();
+        let sent = payload.clone();
+        tokio::spawn(async move {
+            master.write_all(&sent).await.unwrap();
+            master.write_all(&MARK.as_bytes()[..15]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            master.write_all(&MARK.as_bytes()[15..]).await.unwrap();
+            master.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+            // keep the link open
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+        let (received, rest) = read_until_mark(&mut replica, MARK.as_bytes(), Duration::from_secs(5)).await.unwrap();
+        assert_eq!(received, payload);
+        // whatever of the stream came along is handed back, the rest is still on the socket
+        let mut stream = rest;
+        while stream.len() < 14 {
+            let mut byte = [0; 1];
+            replica.read_exact(&mut byte).await.unwrap();
+            stream.push(byte[0]);
+        }
+        assert_eq!(stream, b"*1\r\n$4\r\nPING\r\n");
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_link_closes_before_the_mark() {
+        let (mut master, mut replica) = link().await;
+        master.write_all(b"REDIS0011").await.unwrap();
+        drop(master);
+        let err = read_until_mark(&mut replica, MARK.as_bytes(), Duration::from_secs(5)).await.unwrap_err();
+        assert_eq!(err.to_string(), "Connection closed before the end of the payload");
+    }
+
+    #[tokio::test]
+    async fn loads_a_diskless_payload() {
+        let (mut master, mut replica) = link().await;
+        let state = Arc::new(RwLock::new(State::for_tests()));
+        let storage = Arc::new(RwLock::new(Db::new()));
+        let broadcaster = Arc::new(RwLock::new(Broadcaster::default()));
+        storage.write().await.insert(Bytes::from("stale"), Value::String(Bytes::from("x")));
+
+        let rdb = rdb::dump(&[(Bytes::from("k"), Entry::new(Value::String(Bytes::from("v")), None))]);
+        let ping = command(&["PING"]);
+        master.write_all(&[format!("$EOF:{}\r\n", MARK).as_bytes(), &rdb, MARK.as_bytes(), &ping].concat()).await.unwrap();
+
+        let replid = "a".repeat(40);
+        let resync = Resync::Full { replid: replid.clone(), offset: 100 };
+        let rest = receive_payload(&mut replica, resync, &state, &storage, &broadcaster).await.unwrap();
+        assert!(ping.starts_with(&rest));
+
+        let storage = storage.read().await;
+        assert!(matches!(storage.get(b"k"), Some(Value::String(value)) if value == "v"));
+        assert!(!storage.contains_key(b"stale"));
+        let state = state.read().await;
+        assert_eq!((state.master_replid.as_str(), state.consumed), (replid.as_str(), 100));
+        assert_eq!(broadcaster.read().await.offset(), 100);
     }
-    let len_str = std::str::from_utf8(&len_buf).expect("Failed to decode RDB length").trim();
-    let len: usize = len_str.parse().expect("Failed to parse RDB length");
-    eprintln!("RDB length: {}", len);
-    let mut rdb_buf = vec![0; len];
-    stream.read_exact(&mut rdb_buf).await.expect("Failed to read RDB file");
-    
-    Ok(Some(stream))
-}
\ No newline at end of file
+}
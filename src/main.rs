@@ -2,23 +2,38 @@ pub mod protocol;
 pub mod handler;
 pub mod state;
 pub mod handshake;
+pub mod replica;
 pub mod broadcast;
+pub mod error;
+pub mod session;
+pub mod clock;
+pub mod db;
+pub mod expire;
+pub mod rdb;
+pub mod aof;
+pub mod backlog;
+pub mod value;
+pub mod quicklist;
+pub mod fieldmap;
+pub mod blocking;
 
-use std::collections::HashMap;
 use std::sync::Arc;
 
 use broadcast::Broadcaster;
+use aof::FsyncPolicy;
+use db::Db;
 use state::ServerRole;
 use handler::HandleResult;
 use structopt::StructOpt;
-use tokio::net::TcpListener;
-use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::spawn;
+use tokio::time::Duration;
 use tokio::sync::RwLock;
 
 use crate::handler::handle;
-use crate::protocol::RObject;
-use crate::handshake::handshake;
+use crate::protocol::{RObject, RespCodec};
+use crate::session::Session;
 
 pub use crate::state::State;
 pub use crate::state::BUFFER_SIZE;
@@ -34,57 +49,174 @@ struct Cli {
     dir: Option<String>,
     #[structopt(long)]
     dbfilename: Option<String>,
+    /// Pairs of `<seconds> <changes>` after which a background save starts, "" disables them.
+    #[structopt(default_value = "3600 1 300 100 60 10000", long)]
+    save: String,
+    #[structopt(default_value = "no", long, parse(try_from_str = parse_yes_no))]
+    appendonly: bool,
+    #[structopt(default_value = "appendonly.aof", long)]
+    appendfilename: String,
+    /// always, everysec or no.
+    #[structopt(default_value = "everysec", long, parse(try_from_str = parse_fsync_policy))]
+    appendfsync: FsyncPolicy,
+    #[structopt(default_value = "appendonlydir", long)]
+    appenddirname: String,
+    #[structopt(default_value = "yes", long, parse(try_from_str = parse_yes_no))]
+    aof_load_truncated: bool,
+    #[structopt(default_value = "100", long)]
+    auto_aof_rewrite_percentage: u64,
+    #[structopt(default_value = "67108864", long)]
+    auto_aof_rewrite_min_size: u64,
+    #[structopt(default_value = "1048576", long)]
+    repl_backlog_size: usize,
+    #[structopt(default_value = "yes", long, parse(try_from_str = parse_yes_no))]
+    replica_read_only: bool,
+    #[structopt(default_value = "no", long, parse(try_from_str = parse_yes_no))]
+    repl_diskless_sync: bool,
+    #[structopt(default_value = "5", long)]
+    repl_diskless_sync_delay: u64,
+    #[structopt(default_value = "10", long)]
+    repl_ping_replica_period: u64,
+    #[structopt(default_value = "60", long)]
+    repl_timeout: u64,
+    #[structopt(default_value = "0", long)]
+    min_replicas_to_write: usize,
+    #[structopt(default_value = "10", long)]
+    min_replicas_max_lag: u64,
+    #[structopt(default_value = "536870912", long)]
+    proto_max_bulk_len: usize,
+    #[structopt(default_value = "1048576", long)]
+    proto_max_multibulk_len: usize,
 }
 
+fn parse_yes_no(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string()),
+    }
+}
+
+fn parse_fsync_policy(value: &str) -> Result<FsyncPolicy, String> {
+    value.parse().map_err(|_| "argument must be one of always, everysec or no".to_string())
+}
 
 #[tokio::main]
 async fn main() {
     let args = Cli::from_args();
     let port = args.port;
 
+    let save_params = rdb::save::parse_save_params(&args.save).unwrap_or_else(|| {
+        eprintln!("Invalid save parameters: {}", args.save);
+        std::process::exit(1);
+    });
+
     let state_data = State {
         role: if args.replicaof.is_some() { ServerRole::Slave } else { ServerRole::Master },
-        master_replid: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
-        master_repl_offset: 0,
-        replica_of: match args.replicaof {
-            Some(s) => Some(s.replace(" ", ":")),
-            None => None,
-        },
+        master_replid: replica::generate_replid(),
+        master_replid2: "0".repeat(40),
+        second_replid_offset: -1,
+        replica_of: args.replicaof.map(|s| s.replace(' ', ":")),
         working_port: port,
         consumed: 0,
+        has_cached_master: false,
+        master_link_state: replica::LinkState::Connect,
+        master_last_io: 0,
+        master_link_down_since: clock::now_ms(),
+        master_link_task: None,
+        repl_backlog_size: args.repl_backlog_size,
+        replica_read_only: args.replica_read_only,
+        repl_diskless_sync: args.repl_diskless_sync,
+        repl_diskless_sync_delay: args.repl_diskless_sync_delay,
+        repl_ping_replica_period: args.repl_ping_replica_period,
+        repl_timeout: args.repl_timeout,
+        min_replicas_to_write: args.min_replicas_to_write,
+        min_replicas_max_lag: args.min_replicas_max_lag,
         dir: args.dir.clone(),
         dbfilename: args.dbfilename.clone(),
+        save_params,
+        lastsave: clock::now_ms() / 1000,
+        bgsave_in_progress: false,
+        bgsave_scheduled: false,
+        last_bgsave_ok: true,
+        last_bgsave_try: 0,
+        appendonly: args.appendonly,
+        appendfilename: args.appendfilename.clone(),
+        appendfsync: args.appendfsync,
+        appenddirname: args.appenddirname.clone(),
+        aof_load_truncated: args.aof_load_truncated,
+        auto_aof_rewrite_percentage: args.auto_aof_rewrite_percentage,
+        auto_aof_rewrite_min_size: args.auto_aof_rewrite_min_size,
+        aof_rewrite_in_progress: false,
+        aof_last_rewrite_ok: true,
+        proto_max_bulk_len: args.proto_max_bulk_len,
+        proto_max_multibulk_len: args.proto_max_multibulk_len,
     };
 
+    let manifest = if state_data.appendonly {
+        aof::find_manifest(&state_data).unwrap_or_else(|e| {
+            eprintln!("Failed to read the AOF manifest: {}", e);
+            std::process::exit(1);
+        })
+    } else {
+        None
+    };
+    let mut db = Db::new();
+    // the AOF is the more complete of the two, the RDB is only read without one
+    if manifest.is_none() {
+        let path = state_data.rdb_path();
+        match rdb::load_file(&path, &mut db) {
+            Ok(loaded) => println!("Loaded {} keys from {}", loaded, path.display()),
+            Err(e) => {
+                eprintln!("Failed to load {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     let state = Arc::new(RwLock::new(state_data));
 
-    let storage = Arc::new(RwLock::new(HashMap::<String, RObject>::new()));
-
-    let broadcaster = Arc::new(RwLock::new(Broadcaster{ subscribers: vec![], broadcasted: 0}));
-
-    let master_stream = handshake(Arc::clone(&state)).await.expect(
-        "Handshake failed"
-    );
-    
-    if master_stream.is_some() {
-        let mut master_stream = master_stream.unwrap();
-        let storage: Arc<RwLock<HashMap<String, RObject>>> = Arc::clone(&storage);
-        let state = Arc::clone(&state);
-        let broadcaster = Arc::clone(&broadcaster);
-        spawn(async move {
-            loop {
-                let mut buf = [0; BUFFER_SIZE];
-                let s = master_stream.read(&mut buf)
-                    .await.expect("error reading from stream");
-                if s != 0 {
-                    match handle(&buf[..s], master_stream, Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster))
-                    .await.expect("error handling request") {
-                        HandleResult::Normal(s) => master_stream = s,
-                        HandleResult::Subscribed => break,
-                    }
-                }
+    let storage = Arc::new(RwLock::new(db));
+
+    let broadcaster = Arc::new(RwLock::new(Broadcaster::new(args.repl_backlog_size, Duration::from_secs(args.repl_timeout))));
+
+    if let Some(manifest) = &manifest {
+        let dir = state.read().await.aof_dir();
+        match aof::load(&dir, manifest, &storage, &state, args.aof_load_truncated).await {
+            Ok(commands) => println!("Replayed {} commands from {}", commands, dir.display()),
+            Err(e) => {
+                eprintln!("Failed to load the AOF from {}: {}", dir.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    // loading is not a change that needs saving
+    storage.write().await.dirty = 0;
+
+    if args.appendonly {
+        let dir = state.read().await.aof_dir();
+        let opened = match manifest {
+            Some(mut manifest) => aof::open(&dir, &args.appendfilename, args.appendfsync, &mut manifest),
+            _ => aof::create(&dir, &args.appendfilename, args.appendfsync, &storage.read().await.snapshot()),
+        };
+        match opened {
+            Ok(writer) => broadcaster.write().await.aof = Some(writer),
+            Err(e) => {
+                eprintln!("Failed to open the AOF in {}: {}", dir.display(), e);
+                std::process::exit(1);
             }
-        });
+        }
+    }
+
+    spawn(expire::active_expire_cycle(Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster)));
+    spawn(rdb::save::save_cron(Arc::clone(&storage), Arc::clone(&state)));
+    spawn(aof::fsync_cycle(Arc::clone(&broadcaster)));
+    spawn(broadcast::replication_cron(Arc::clone(&state), Arc::clone(&broadcaster)));
+    spawn(aof::rewrite::rewrite_cron(Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster)));
+
+    if state.read().await.role == ServerRole::Slave {
+        let link = replica::spawn_master_link(Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster));
+        state.write().await.master_link_task = Some(link);
     }
 
     let listener = TcpListener::bind(
@@ -92,23 +224,153 @@ async fn main() {
     ).await.unwrap();
 
     loop {
-        let (mut stream, _) = listener.accept().await.unwrap();
-        let storage: Arc<RwLock<HashMap<String, RObject>>> = Arc::clone(&storage);
-        let state = Arc::clone(&state);
-        let broadcaster = Arc::clone(&broadcaster);
-        spawn(async move {
-            loop {
-                let mut buf = [0; BUFFER_SIZE];
-                let s = stream.read(&mut buf)
-                    .await.expect("error reading from stream");
-                if s != 0 {
-                    match handle(&buf[..s], stream, Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster))
-                        .await.expect("error handling request") {
-                            HandleResult::Normal(s) => stream = s,
-                            HandleResult::Subscribed => break,
-                        }
+        let (stream, _) = listener.accept().await.unwrap();
+        spawn(serve(stream, &[], Session::new(false), Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster)));
+    }
+}
+
+/// Runs the commands arriving on a connection, starting with the ones in `received` that
+/// were already read off it.
+async fn serve(
+    mut stream: TcpStream,
+    received: &[u8],
+    mut session: Session,
+    storage: Arc<RwLock<Db>>,
+    state: Arc<RwLock<State>>,
+    broadcaster: Arc<RwLock<Broadcaster>>,
+) {
+    let mut codec = {
+        let state = state.read().await;
+        RespCodec::new(state.proto_max_bulk_len, state.proto_max_multibulk_len)
+    };
+    codec.extend(received);
+    // a replica reports how far it got every second, the first tick fires right away
+    let mut ack_interval = tokio::time::interval(replica::REPLICA_ACK_PERIOD);
+    loop {
+        loop {
+            let (request, raw) = match codec.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    // the rest of the buffer cannot be trusted, reply and drop the connection like redis does
+                    let _ = stream.write_all(&RObject::SimpleError(format!("ERR {}", e)).encode()).await;
+                    return;
+                }
+            };
+            match handle(request, &raw, stream, &mut session, Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster)).await {
+                Ok(HandleResult::Normal(s)) => stream = s,
+                Ok(HandleResult::Subscribed) => return,
+                Err(e) => {
+                    eprintln!("Error writing to stream: {}", e);
+                    return;
+                }
+            }
+        }
+        let mut buf = [0; BUFFER_SIZE];
+        let read = tokio::select! {
+            read = stream.read(&mut buf) => read,
+            _ = ack_interval.tick(), if session.master_link => {
+                let (consumed, silent_for, repl_timeout) = {
+                    let state = state.read().await;
+                    (state.consumed, clock::now_ms().saturating_sub(state.master_last_io), state.repl_timeout)
+                };
+                // the master pings every few seconds, this long without a byte means it is gone
+                if silent_for > repl_timeout * 1000 {
+                    eprintln!("Master timed out, no data nor PING received for {} seconds", silent_for / 1000);
+                    return;
+                }
+                let ack = RObject::Array(vec![
+                    RObject::BulkString("REPLCONF".into()),
+                    RObject::BulkString("ACK".into()),
+                    RObject::BulkString(consumed.to_string().into()),
+                ]);
+                if let Err(e) = stream.write_all(&ack.encode()).await {
+                    eprintln!("Error writing to stream: {}", e);
+                    return;
                 }
+                continue;
+            },
+        };
+        let s = match read {
+            Ok(0) => return,
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Error reading from stream: {}", e);
+                return;
             }
-        });
+        };
+        if session.master_link {
+            state.write().await.master_last_io = clock::now_ms();
+        }
+        codec.extend(&buf[..s]);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a master link served from its replica's end, and the master's end of it
+    async fn master_link(state: &Arc<RwLock<State>>) -> (tokio::task::JoinHandle<()>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let replica_end = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (master_end, _) = listener.accept().await.unwrap();
+        let served = spawn(serve(
+            replica_end,
+            &[],
+            Session::new(true),
+            Arc::new(RwLock::new(Db::new())),
+            Arc::clone(state),
+            Arc::new(RwLock::new(Broadcaster::default())),
+        ));
+        (served, master_end)
+    }
+
+    #[tokio::test]
+    async fn acks_the_offset_and_takes_pings_as_signs_of_life() {
+        let state = Arc::new(RwLock::new(State::for_tests()));
+        {
+            let mut state = state.write().await;
+            state.role = ServerRole::Slave;
+            state.consumed = 7;
+            state.master_last_io = clock::now_ms();
+        }
+        let (served, mut master) = master_link(&state).await;
+        let ack = RObject::Array(vec![
+            RObject::BulkString("REPLCONF".into()),
+            RObject::BulkString("ACK".into()),
+            RObject::BulkString("7".into()),
+        ]).encode();
+        let mut received = vec![0; ack.len()];
+        master.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, ack);
+
+        // a PING is applied silently and counted like any other part of the stream
+        let ping = RObject::Array(vec![RObject::BulkString("PING".into())]).encode();
+        master.write_all(&ping).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.read().await.consumed != 7 + ping.len() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        assert!(!served.is_finished());
+        served.abort();
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_silent_master() {
+        let state = Arc::new(RwLock::new(State::for_tests()));
+        {
+            let mut state = state.write().await;
+            state.role = ServerRole::Slave;
+            state.repl_timeout = 1;
+            state.master_last_io = clock::now_ms() - 2_000;
+        }
+        let (served, mut master) = master_link(&state).await;
+        tokio::time::timeout(Duration::from_secs(5), served).await.unwrap().unwrap();
+        let mut rest = vec![];
+        master.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+}
+
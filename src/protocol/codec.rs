@@ -0,0 +1,245 @@
+use bytes::{Bytes, BytesMut};
+use thiserror::Error;
+
+use super::RObject;
+
+const CRLF: &[u8] = b"\r\n";
+// the longest type/length line we wait for before giving up on finding its CRLF
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+#[error("Protocol error: {0}")]
+pub struct ProtocolError(pub String);
+
+/// Accumulates the bytes read from a connection and splits them into complete RESP frames.
+///
+/// Anything after the last complete frame stays buffered until more data arrives. Requests are
+/// multibulks of bulk strings like redis expects them, so frames never nest.
+pub struct RespCodec {
+    buffer: BytesMut,
+    max_bulk_len: usize,
+    max_multibulk_len: usize,
+    // how far the incomplete multibulk at the front of the buffer was walked already
+    progress: Option<Progress>,
+}
+
+struct Progress {
+    /// Where the next element starts.
+    cur: usize,
+    /// Elements still missing.
+    pending: usize,
+}
+
+impl RespCodec {
+
+    pub fn new(max_bulk_len: usize, max_multibulk_len: usize) -> Self {
+        RespCodec {
+            buffer: BytesMut::new(),
+            max_bulk_len,
+            max_multibulk_len,
+            progress: None,
+        }
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the next complete frame together with the raw bytes it was decoded from,
+    /// or `None` if the buffer does not hold a complete frame yet.
+    pub fn next_frame(&mut self) -> Result<Option<(RObject, Bytes)>, ProtocolError> {
+        let end = match self.check()? {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let raw = self.buffer.split_to(end).freeze();
+        let (frame, _) = RObject::decode(&raw, 0)
+            .map_err(|e| ProtocolError(e.to_string()))?;
+        Ok(Some((frame, raw)))
+    }
+
+    // walks the frame at the front of the buffer without decoding it, picking up where the
+    // previous call stopped, returns where it ends or None if more data is needed
+    fn check(&mut self) -> Result<Option<usize>, ProtocolError> {
+        let (mut cur, mut pending) = match self.progress.take() {
+            Some(Progress { cur, pending }) => (cur, pending),
+            None => {
+                let (line, after_line) = match self.line(0)? {
+                    Some(line) => line,
+                    None => return Ok(None),
+                };
+                match self.buffer[0] {
+                    b'*' => {
+                        let length = Self::parse_length(&self.buffer[1..line], "multibulk")?;
+                        if length < 0 {
+                            return Ok(Some(after_line));
+                        }
+                        if length as usize > self.max_multibulk_len {
+                            return Err(ProtocolError("invalid multibulk length".to_string()));
+                        }
+                        (after_line, length as usize)
+                    },
+                    b'+' | b'-' | b':' | b'_' | b'#' | b',' | b'(' => return Ok(Some(after_line)),
+                    b'$' | b'!' | b'=' => return self.blob_end(1..line, after_line),
+                    other => return Err(ProtocolError(format!("expected '*', got '{}'", other as char))),
+                }
+            },
+        };
+        while pending > 0 {
+            let end = match self.bulk_end(cur)? {
+                Some(end) => end,
+                None => {
+                    self.progress = Some(Progress { cur, pending });
+                    return Ok(None);
+                },
+            };
+            cur = end;
+            pending -= 1;
+        }
+        Ok(Some(cur))
+    }
+
+    // one element of a multibulk, which has to be a bulk string
+    fn bulk_end(&self, start: usize) -> Result<Option<usize>, ProtocolError> {
+        let (line, after_line) = match self.line(start)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        match self.buffer[start] {
+            b'$' => self.blob_end(start + 1..line, after_line),
+            other => Err(ProtocolError(format!("expected '$', got '{}'", other as char))),
+        }
+    }
+
+    // the type/length line starting at `start`, as where its CRLF is and where the next one starts
+    fn line(&self, start: usize) -> Result<Option<(usize, usize)>, ProtocolError> {
+        if start >= self.buffer.len() {
+            return Ok(None);
+        }
+        match Self::find_crlf(&self.buffer, start + 1) {
+            Some(end) => Ok(Some((end, end + CRLF.len()))),
+            None if self.buffer.len() - start > MAX_LINE_LEN => Err(ProtocolError("too big line".to_string())),
+            None => Ok(None),
+        }
+    }
+
+    // where a blob whose length is in `length` and whose content starts at `content` ends
+    fn blob_end(&self, length: std::ops::Range<usize>, content: usize) -> Result<Option<usize>, ProtocolError> {
+        let length = Self::parse_length(&self.buffer[length], "bulk")?;
+        if length < 0 {
+            return Ok(Some(content));
+        }
+        if length as usize > self.max_bulk_len {
+            return Err(ProtocolError("invalid bulk length".to_string()));
+        }
+        let end = content + length as usize + CRLF.len();
+        if end > self.buffer.len() {
+            return Ok(None);
+        }
+        Ok(Some(end))
+    }
+    fn find_crlf(data: &[u8], start: usize) -> Option<usize> {
+        if start >= data.len() {
+            return None;
+        }
+        data[start..].windows(CRLF.len()).position(|w| w == CRLF).map(|p| p + start)
+    }
+
+    fn parse_length(line: &[u8], kind: &str) -> Result<i64, ProtocolError> {
+        std::str::from_utf8(line)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .filter(|&l| l >= -1)
+            .ok_or_else(|| ProtocolError(format!("invalid {} length", kind)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec() -> RespCodec {
+        RespCodec::new(512 * 1024 * 1024, 1024 * 1024)
+    }
+
+    fn args(frame: &RObject) -> Vec<&[u8]> {
+        match frame {
+            RObject::Array(items) => items.iter().map(|item| match item {
+                RObject::BulkString(s) => s.as_ref(),
+                other => panic!("not a bulk string: {:?}", other),
+            }).collect(),
+            other => panic!("not an array: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn splits_pipelined_requests() {
+        let mut codec = codec();
+        codec.extend(b"*1\r\n$4\r\nPING\r\n*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n*1");
+        let (frame, raw) = codec.next_frame().unwrap().unwrap();
+        assert_eq!(args(&frame), [b"PING".as_ref()]);
+        assert_eq!(raw.as_ref(), b"*1\r\n$4\r\nPING\r\n");
+        let (frame, _) = codec.next_frame().unwrap().unwrap();
+        assert_eq!(args(&frame), [b"ECHO".as_ref(), b"hi"]);
+        assert!(codec.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn resumes_a_partial_multibulk() {
+        let request = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+        let mut codec = codec();
+        for byte in &request[..request.len() - 1] {
+            codec.extend(std::slice::from_ref(byte));
+            assert!(codec.next_frame().unwrap().is_none());
+        }
+        codec.extend(&request[request.len() - 1..]);
+        let (frame, raw) = codec.next_frame().unwrap().unwrap();
+        assert_eq!(args(&frame), [b"SET".as_ref(), b"key", b"value"]);
+        assert_eq!(raw.as_ref(), request);
+        assert!(codec.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn resumes_a_large_multibulk_fed_in_pieces() {
+        let count = 100_000;
+        let mut request = format!("*{}\r\n", count).into_bytes();
+        for _ in 0..count {
+            request.extend_from_slice(b"$1\r\nx\r\n");
+        }
+        let mut codec = codec();
+        for piece in request.chunks(1000) {
+            codec.extend(piece);
+            if let Some((frame, _)) = codec.next_frame().unwrap() {
+                assert_eq!(args(&frame).len(), count);
+                return;
+            }
+        }
+        panic!("the request never completed");
+    }
+
+    #[test]
+    fn rejects_nested_multibulks_without_recursing() {
+        let mut codec = codec();
+        codec.extend(&b"*1\r\n".repeat(200_000));
+        let err = codec.next_frame().unwrap_err();
+        assert_eq!(err.0, "expected '$', got '*'");
+    }
+
+    #[test]
+    fn rejects_lengths_over_the_limits() {
+        let mut codec = RespCodec::new(4, 2);
+        codec.extend(b"*3\r\n");
+        assert_eq!(codec.next_frame().unwrap_err().0, "invalid multibulk length");
+
+        let mut codec = RespCodec::new(4, 2);
+        codec.extend(b"*1\r\n$5\r\n");
+        assert_eq!(codec.next_frame().unwrap_err().0, "invalid bulk length");
+    }
+
+    #[test]
+    fn rejects_a_line_without_end() {
+        let mut codec = codec();
+        codec.extend(&vec![b'*'; MAX_LINE_LEN + 1]);
+        assert_eq!(codec.next_frame().unwrap_err().0, "too big line");
+    }
+}
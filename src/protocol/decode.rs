@@ -1,215 +1,252 @@
 use std::collections::HashMap;
+use std::str::FromStr;
+
+use bytes::Bytes;
 
 use super::RObject;
 use anyhow::*;
 
-const CRLF: &str = "\r\n";
+const CRLF: &[u8] = b"\r\n";
 
 impl RObject {
-    
-    pub fn decode(data: &str, start: usize) -> Result<(RObject, usize)> {
+
+    pub fn decode(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         if start >= data.len() {
             bail!("No data to decode");
         }
-        match &data[start..=start] {
-            "+" => Self::decode_simple_string(data, start),
-            "-" => Self::decode_simple_error(data, start),
-            ":" => Self::decode_integer(data, start),
-            "$" => Self::decode_bulk_string(data, start),
-            "*" => Self::decode_array(data, start),
-            "_" => Ok((RObject::Null, start + 1 + CRLF.len())),
-            "#" => Self::decode_boolean(data, start),
-            "," => Self::decode_double(data, start),
-            "(" => Self::decode_big_number(data, start),
-            "!" => Self::decode_bulk_error(data, start),
-            "=" => Self::decode_verbatim_string(data, start),
-            "%" => Self::decode_map(data, start),
-            "~" => Self::decode_set(data, start),
-            ">" => Self::decode_push(data, start),
+        match data[start] {
+            b'+' => Self::decode_simple_string(data, start),
+            b'-' => Self::decode_simple_error(data, start),
+            b':' => Self::decode_integer(data, start),
+            b'$' => Self::decode_bulk_string(data, start),
+            b'*' => Self::decode_array(data, start),
+            b'_' => Ok((RObject::Null, start + 1 + CRLF.len())),
+            b'#' => Self::decode_boolean(data, start),
+            b',' => Self::decode_double(data, start),
+            b'(' => Self::decode_big_number(data, start),
+            b'!' => Self::decode_bulk_error(data, start),
+            b'=' => Self::decode_verbatim_string(data, start),
+            b'%' => Self::decode_map(data, start),
+            b'~' => Self::decode_set(data, start),
+            b'>' => Self::decode_push(data, start),
             _ => bail!("Unknown type"),
         }
     }
 
-    fn decode_simple_string(data: &str, start: usize) -> Result<(RObject, usize)> {
+    fn find_crlf(data: &[u8], start: usize) -> Option<usize> {
+        data[start..].windows(CRLF.len()).position(|w| w == CRLF).map(|p| p + start)
+    }
+
+    fn parse_number<T: FromStr>(data: &[u8]) -> Result<T> {
+        std::str::from_utf8(data)?
+            .parse::<T>()
+            .map_err(|_| anyhow!("Failed to parse number: {}", String::from_utf8_lossy(data)))
+    }
+
+    fn parse_line(data: &[u8]) -> Result<String> {
+        Ok(std::str::from_utf8(data)?.to_string())
+    }
+
+    // reads <length> bytes after cur and the CRLF that terminates them
+    fn take_blob(data: &[u8], cur: usize, length: usize) -> Result<(Bytes, usize)> {
+        let content_end = cur + length;
+        if content_end + CRLF.len() > data.len() {
+            bail!("Not enough data for {} bytes of content", length);
+        }
+        if &data[content_end..content_end + CRLF.len()] != CRLF {
+            bail!("No CRLF found after content");
+        }
+        Ok((Bytes::copy_from_slice(&data[cur..content_end]), content_end + CRLF.len()))
+    }
+
+    fn decode_simple_string(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         let mut cur = start;
-        if &data[cur..=cur] != "+" {
+        if data[cur] != b'+' {
             bail!("No + found at start of simple string");
         }
         cur += 1;
         // return the part between + and \r\n
-        let end = data[cur..].find(CRLF).ok_or_else(|| anyhow!("No CRLF found in simple string"))? + cur;
-        let simple_string = RObject::SimpleString(data[cur..end].to_string());
+        let end = Self::find_crlf(data, cur).ok_or_else(|| anyhow!("No CRLF found in simple string"))?;
+        let simple_string = RObject::SimpleString(Self::parse_line(&data[cur..end])?);
         cur = end + CRLF.len();
 
         Ok((simple_string, cur))
     }
 
-    fn decode_simple_error(data: &str, start: usize) -> Result<(RObject, usize)> {
+    fn decode_simple_error(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         let mut cur = start;
-        if &data[cur..=cur] != "-" {
+        if data[cur] != b'-' {
             bail!("No - found at start of simple error");
         }
         cur += 1;
         // return the part between - and \r\n
-        let end = data[cur..].find(CRLF).ok_or_else(|| anyhow!("No CRLF found in simple error"))? + cur;
-        let simple_error = RObject::SimpleError(data[cur..end].to_string());
+        let end = Self::find_crlf(data, cur).ok_or_else(|| anyhow!("No CRLF found in simple error"))?;
+        let simple_error = RObject::SimpleError(Self::parse_line(&data[cur..end])?);
         cur = end + CRLF.len();
 
         Ok((simple_error, cur))
     }
 
-    fn decode_integer(data: &str, start: usize) -> Result<(RObject, usize)> {
+    fn decode_integer(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         let mut cur = start;
-        if &data[cur..=cur] != ":" {
+        if data[cur] != b':' {
             bail!("No : found at start of integer");
         }
         cur += 1;
         // return the part between : and \r\n
-        let end = data[cur..].find(CRLF).ok_or_else(|| anyhow!("No CRLF found in integer"))? + cur;
-        let integer = RObject::Integer(data[cur..end].parse()?);
+        let end = Self::find_crlf(data, cur).ok_or_else(|| anyhow!("No CRLF found in integer"))?;
+        let integer = RObject::Integer(Self::parse_number(&data[cur..end])?);
         cur = end + CRLF.len();
 
         Ok((integer, cur))
     }
 
-    fn decode_bulk_string(data: &str, start: usize) -> Result<(RObject, usize)> {
+    fn decode_bulk_string(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         let mut cur = start;
-        if &data[cur..=cur] != "$" {
+        if data[cur] != b'$' {
             bail!("No $ found at start of bulk string");
         }
         cur += 1;
         // $<length><CRLF><content><CRLF>
-        let length_end = data[cur..].find(CRLF).ok_or_else(|| anyhow!("No CRLF found in bulk string length"))? + cur;
-        let maybe_length = data[cur..length_end].parse::<i128>()?;
+        let length_end = Self::find_crlf(data, cur).ok_or_else(|| anyhow!("No CRLF found in bulk string length"))?;
+        let maybe_length = Self::parse_number::<i64>(&data[cur..length_end])?;
+        cur = length_end + CRLF.len();
         if maybe_length == -1 {
-            cur += CRLF.len();
             return Ok((RObject::NullBulkString, cur));
         }
-        let length = maybe_length as usize;
-        cur = length_end + CRLF.len();
-        let content_end = cur + length;
-        let bulk_string = RObject::BulkString(data[cur..content_end].to_string());
-        cur = content_end + CRLF.len();
-        Ok((bulk_string, cur))
+        if maybe_length < 0 {
+            bail!("Invalid bulk string length: {}", maybe_length);
+        }
+        let (content, cur) = Self::take_blob(data, cur, maybe_length as usize)?;
+        Ok((RObject::BulkString(content), cur))
     }
 
-    fn decode_array(data: &str, start: usize) -> Result<(RObject, usize)> {
+    fn decode_array(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         let mut cur = start;
-        if &data[cur..=cur] != "*" {
+        if data[cur] != b'*' {
             bail!("No * found at start of array");
         }
         cur += 1;
         // *<length><CRLF><content>
-        let length_end = data[cur..].find(CRLF).ok_or_else(|| anyhow!("No CRLF found in array length"))? + cur;
-        let maybe_length = data[cur..length_end].parse::<i128>()?;
+        let length_end = Self::find_crlf(data, cur).ok_or_else(|| anyhow!("No CRLF found in array length"))?;
+        let maybe_length = Self::parse_number::<i64>(&data[cur..length_end])?;
+        cur = length_end + CRLF.len();
         if maybe_length == -1 {
-            cur += CRLF.len();
             return Ok((RObject::NullArray, cur));
         }
-        let length = maybe_length as usize;
-        cur = length_end + CRLF.len();
-        let mut array = Vec::with_capacity(length as usize);
+        if maybe_length < 0 {
+            bail!("Invalid array length: {}", maybe_length);
+        }
+        let (array, cur) = Self::decode_elements(data, cur, maybe_length as usize)?;
+        Ok((RObject::Array(array), cur))
+    }
+
+    fn decode_elements(data: &[u8], start: usize, length: usize) -> Result<(Vec<RObject>, usize)> {
+        let mut cur = start;
+        let mut elements = Vec::with_capacity(length.min(1024));
         for _ in 0..length {
             let (element, new_cur) = RObject::decode(data, cur)?;
-            array.push(element);
+            elements.push(element);
             cur = new_cur;
         }
-        Ok((RObject::Array(array), cur))
+        Ok((elements, cur))
     }
 
-    fn decode_boolean(data: &str, start: usize) -> Result<(RObject, usize)> {
+    fn decode_boolean(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         let mut cur = start;
-        if &data[cur..=cur] != "#" {
+        if data[cur] != b'#' {
             bail!("No # found at start of boolean");
         }
         cur += 1;
         // #<t|f>CRLF
-        let boolean = RObject::Boolean(data[cur..=cur] == *"t");
-        cur += CRLF.len();
+        if cur + 1 + CRLF.len() > data.len() {
+            bail!("Not enough data for boolean");
+        }
+        let boolean = RObject::Boolean(data[cur] == b't');
+        cur += 1 + CRLF.len();
         Ok((boolean, cur))
     }
 
-    fn decode_double(data: &str, start: usize) -> Result<(RObject, usize)> {
+    fn decode_double(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         let mut cur = start;
-        if &data[cur..=cur] != "," {
+        if data[cur] != b',' {
             bail!("No , found at start of double");
         }
         cur += 1;
 
-        let end = data[cur..].find(CRLF).ok_or_else(|| anyhow!("No CRLF found in double"))? + cur;
-        let double_str = &data[cur..end];
-        let double_val = double_str.parse::<f64>().map_err(|_| anyhow!("Failed to parse double: {}", double_str))?;
+        let end = Self::find_crlf(data, cur).ok_or_else(|| anyhow!("No CRLF found in double"))?;
+        let double_val = Self::parse_number::<f64>(&data[cur..end])?;
         let double = RObject::Double(double_val);
         cur = end + CRLF.len();
 
         Ok((double, cur))
     }
 
-    fn decode_big_number(data: &str, start: usize) -> Result<(RObject, usize)> {
+    fn decode_big_number(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         let mut cur = start;
-        if &data[cur..=cur] != "(" {
+        if data[cur] != b'(' {
             bail!("No ( found at start of big number");
         }
         cur += 1;
 
-        let end = data[cur..].find(CRLF).ok_or_else(|| anyhow!("No CRLF found in big number"))? + cur;
-        let big_number = RObject::BigNumber(data[cur..end].to_string());
+        let end = Self::find_crlf(data, cur).ok_or_else(|| anyhow!("No CRLF found in big number"))?;
+        let big_number = RObject::BigNumber(Self::parse_line(&data[cur..end])?);
         cur = end + CRLF.len();
 
         Ok((big_number, cur))
     }
 
-    fn decode_bulk_error(data: &str, start: usize) -> Result<(RObject, usize)> {
+    fn decode_bulk_error(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         let mut cur = start;
-        if &data[cur..=cur] != "!" {
+        if data[cur] != b'!' {
             bail!("No ! found at start of bulk error");
         }
         cur += 1;
         // !<length>\r\n<error>\r\n
-        let length_end = data[cur..].find(CRLF).ok_or_else(|| anyhow!("No CRLF found in bulk error length"))? + cur;
-        let length = data[cur..length_end].parse::<usize>()?;
+        let length_end = Self::find_crlf(data, cur).ok_or_else(|| anyhow!("No CRLF found in bulk error length"))?;
+        let length = Self::parse_number::<usize>(&data[cur..length_end])?;
         cur = length_end + CRLF.len();
-        let error_end = cur + length;
-        let bulk_error = RObject::BulkError(data[cur..error_end].to_string());
-        cur = error_end + CRLF.len();
-        Ok((bulk_error, cur))
+        let (error, cur) = Self::take_blob(data, cur, length)?;
+        Ok((RObject::BulkError(error), cur))
     }
 
-    fn decode_verbatim_string(data: &str, start: usize) -> Result<(RObject, usize)> {
+    fn decode_verbatim_string(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         let mut cur = start;
-        if &data[cur..=cur] != "=" {
+        if data[cur] != b'=' {
             bail!("No = found at start of verbatim string");
         }
         cur += 1;
-        // =<length>\r\n<encoding>\r\n<content>\r\n
-        let length_end = data[cur..].find(CRLF).ok_or_else(|| anyhow!("No CRLF found in verbatim string length"))? + cur;
-        let length = data[cur..length_end].parse::<usize>()?;
+        // =<length>\r\n<encoding>:<content>\r\n, the length covers the encoding prefix
+        let length_end = Self::find_crlf(data, cur).ok_or_else(|| anyhow!("No CRLF found in verbatim string length"))?;
+        let length = Self::parse_number::<usize>(&data[cur..length_end])?;
         cur = length_end + CRLF.len();
-        let encoding_end = data[cur..].find(CRLF).ok_or_else(|| anyhow!("No CRLF found in verbatim string encoding"))? + cur;
-        let encoding = data[cur..encoding_end].to_string();
-        cur = encoding_end + CRLF.len();
-        let content_end = cur + length;
-        let verbatim_string = RObject::VerbatimString(data[cur..content_end].to_string(), encoding);
-        cur = content_end + CRLF.len();
+        let (payload, cur) = Self::take_blob(data, cur, length)?;
+        if payload.len() < 4 || payload[3] != b':' {
+            bail!("No encoding found in verbatim string");
+        }
+        let encoding = Self::parse_line(&payload[..3])?;
+        let verbatim_string = RObject::VerbatimString(payload.slice(4..), encoding);
         Ok((verbatim_string, cur))
     }
 
-    fn decode_map(data: &str, start: usize) -> Result<(RObject, usize)> {
+    fn decode_map(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         let mut cur = start;
-        if &data[cur..=cur] != "%" {
+        if data[cur] != b'%' {
             bail!("No % found at start of map");
         }
         cur += 1;
         // %<length>\r\n<key><value>...
-        let length_end = data[cur..].find(CRLF).ok_or_else(|| anyhow!("No CRLF found in map length"))? + cur;
-        let maybe_length = data[cur..length_end].parse::<i128>()?;
+        let length_end = Self::find_crlf(data, cur).ok_or_else(|| anyhow!("No CRLF found in map length"))?;
+        let maybe_length = Self::parse_number::<i64>(&data[cur..length_end])?;
+        cur = length_end + CRLF.len();
         if maybe_length == -1 {
-            cur += CRLF.len();
             return Ok((RObject::Null, cur));
         }
+        if maybe_length < 0 {
+            bail!("Invalid map length: {}", maybe_length);
+        }
         let length = maybe_length as usize;
-        cur = length_end + CRLF.len();
-        let mut map = HashMap::with_capacity(length as usize);
+        let mut map = HashMap::with_capacity(length.min(1024));
         for _ in 0..length {
             let (key, new_cur) = RObject::decode(data, cur)?;
             let (value, new_cur) = RObject::decode(data, new_cur)?;
@@ -219,51 +256,43 @@ impl RObject {
         Ok((RObject::Map(map), cur))
     }
 
-    fn decode_set(data: &str, start: usize) -> Result<(RObject, usize)> {
+    fn decode_set(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         let mut cur = start;
-        if &data[cur..=cur] != "~" {
+        if data[cur] != b'~' {
             bail!("No ~ found at start of set");
         }
         cur += 1;
         // ~<length>\r\n<element>...
-        let length_end = data[cur..].find(CRLF).ok_or_else(|| anyhow!("No CRLF found in set length"))? + cur;
-        let maybe_length = data[cur..length_end].parse::<i128>()?;
+        let length_end = Self::find_crlf(data, cur).ok_or_else(|| anyhow!("No CRLF found in set length"))?;
+        let maybe_length = Self::parse_number::<i64>(&data[cur..length_end])?;
+        cur = length_end + CRLF.len();
         if maybe_length == -1 {
-            cur += CRLF.len();
             return Ok((RObject::Null, cur));
         }
-        let length = maybe_length as usize;
-        cur = length_end + CRLF.len();
-        let mut set = Vec::with_capacity(length as usize);
-        for _ in 0..length {
-            let (element, new_cur) = RObject::decode(data, cur)?;
-            set.push(element);
-            cur = new_cur;
+        if maybe_length < 0 {
+            bail!("Invalid set length: {}", maybe_length);
         }
+        let (set, cur) = Self::decode_elements(data, cur, maybe_length as usize)?;
         Ok((RObject::Set(set), cur))
     }
 
-    fn decode_push(data: &str, start: usize) -> Result<(RObject, usize)> {
+    fn decode_push(data: &[u8], start: usize) -> Result<(RObject, usize)> {
         let mut cur = start;
-        if &data[cur..=cur] != ">" {
+        if data[cur] != b'>' {
             bail!("No > found at start of push");
         }
         cur += 1;
         // ><length>\r\n<element>...
-        let length_end = data[cur..].find(CRLF).ok_or_else(|| anyhow!("No CRLF found in push length"))? + cur;
-        let maybe_length = data[cur..length_end].parse::<i128>()?;
+        let length_end = Self::find_crlf(data, cur).ok_or_else(|| anyhow!("No CRLF found in push length"))?;
+        let maybe_length = Self::parse_number::<i64>(&data[cur..length_end])?;
+        cur = length_end + CRLF.len();
         if maybe_length == -1 {
-            cur += CRLF.len();
             return Ok((RObject::Null, cur));
         }
-        let length = maybe_length as usize;
-        cur = length_end + CRLF.len();
-        let mut push = Vec::with_capacity(length as usize);
-        for _ in 0..length {
-            let (element, new_cur) = RObject::decode(data, cur)?;
-            push.push(element);
-            cur = new_cur;
+        if maybe_length < 0 {
+            bail!("Invalid push length: {}", maybe_length);
         }
+        let (push, cur) = Self::decode_elements(data, cur, maybe_length as usize)?;
         Ok((RObject::Push(push), cur))
     }
-}
\ No newline at end of file
+}
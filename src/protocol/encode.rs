@@ -0,0 +1,86 @@
+use super::RObject;
+
+const CRLF: &[u8] = b"\r\n";
+
+impl RObject {
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            RObject::SimpleString(s) => Self::encode_line(buf, b'+', s.as_bytes()),
+            RObject::SimpleError(s) => Self::encode_line(buf, b'-', s.as_bytes()),
+            RObject::Integer(i) => Self::encode_line(buf, b':', i.to_string().as_bytes()),
+            RObject::BulkString(s) => Self::encode_blob(buf, b'$', s),
+            RObject::NullBulkString => buf.extend_from_slice(b"$-1\r\n"),
+            RObject::Array(a) => Self::encode_aggregate(buf, b'*', a),
+            RObject::NullArray => buf.extend_from_slice(b"*-1\r\n"),
+            RObject::Null => buf.extend_from_slice(b"_\r\n"),
+            RObject::Boolean(b) => Self::encode_line(buf, b'#', if *b { b"t" } else { b"f" }),
+            RObject::Double(d) => Self::encode_line(buf, b',', Self::format_double(*d).as_bytes()),
+            RObject::BigNumber(s) => Self::encode_line(buf, b'(', s.as_bytes()),
+            RObject::BulkError(s) => Self::encode_blob(buf, b'!', s),
+            RObject::VerbatimString(s, e) => {
+                // =<length>\r\n<encoding>:<content>\r\n, the length covers the encoding prefix
+                buf.push(b'=');
+                buf.extend_from_slice((e.len() + 1 + s.len()).to_string().as_bytes());
+                buf.extend_from_slice(CRLF);
+                buf.extend_from_slice(e.as_bytes());
+                buf.push(b':');
+                buf.extend_from_slice(s);
+                buf.extend_from_slice(CRLF);
+            },
+            RObject::Map(m) => {
+                buf.push(b'%');
+                buf.extend_from_slice(m.len().to_string().as_bytes());
+                buf.extend_from_slice(CRLF);
+                let mut keys: Vec<_> = m.keys().collect();
+                keys.sort_by_key(|&k| k.encode());
+                for key in keys {
+                    key.encode_into(buf);
+                    m.get(key).unwrap().encode_into(buf);
+                }
+            },
+            RObject::Set(s) => Self::encode_aggregate(buf, b'~', s),
+            RObject::Push(s) => Self::encode_aggregate(buf, b'>', s),
+        }
+    }
+
+    fn encode_line(buf: &mut Vec<u8>, prefix: u8, line: &[u8]) {
+        buf.push(prefix);
+        buf.extend_from_slice(line);
+        buf.extend_from_slice(CRLF);
+    }
+
+    fn encode_blob(buf: &mut Vec<u8>, prefix: u8, blob: &[u8]) {
+        // <prefix><length in bytes>\r\n<blob>\r\n
+        buf.push(prefix);
+        buf.extend_from_slice(blob.len().to_string().as_bytes());
+        buf.extend_from_slice(CRLF);
+        buf.extend_from_slice(blob);
+        buf.extend_from_slice(CRLF);
+    }
+
+    fn encode_aggregate(buf: &mut Vec<u8>, prefix: u8, items: &[RObject]) {
+        buf.push(prefix);
+        buf.extend_from_slice(items.len().to_string().as_bytes());
+        buf.extend_from_slice(CRLF);
+        for item in items {
+            item.encode_into(buf);
+        }
+    }
+
+    fn format_double(d: f64) -> String {
+        if d.is_nan() {
+            "nan".to_string()
+        } else if d.is_infinite() {
+            if d > 0.0 { "inf".to_string() } else { "-inf".to_string() }
+        } else {
+            d.to_string()
+        }
+    }
+}
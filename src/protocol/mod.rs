@@ -1,4 +1,7 @@
 pub mod r_object;
 pub mod decode;
+pub mod encode;
+pub mod codec;
 
-pub use r_object::RObject;
\ No newline at end of file
+pub use r_object::RObject;
+pub use codec::{ProtocolError, RespCodec};
@@ -0,0 +1,377 @@
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+
+// redis' default list-max-listpack-size of -2, nodes of at most 8 kb
+const NODE_MAX_BYTES: usize = 8 * 1024;
+
+/// A list kept as a chain of small nodes, like redis' quicklist.
+///
+/// Pushes and pops at either end only touch the outer nodes, and walking to an index
+/// skips whole nodes, so long lists stay cheap to use as queues.
+#[derive(Clone, Default)]
+pub struct QuickList {
+    nodes: VecDeque<Node>,
+    len: usize,
+}
+
+/// Which end of a list an element is pushed to or popped from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Default)]
+struct Node {
+    items: VecDeque<Bytes>,
+    /// Sum of the item lengths, what the node size limit applies to.
+    bytes: usize,
+}
+
+impl Node {
+
+    fn has_room(&self, item: &Bytes) -> bool {
+        self.items.is_empty() || self.bytes + item.len() <= NODE_MAX_BYTES
+    }
+}
+
+impl QuickList {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn push_front(&mut self, item: Bytes) {
+        if !self.nodes.front().is_some_and(|node| node.has_room(&item)) {
+            self.nodes.push_front(Node::default());
+        }
+        let node = self.nodes.front_mut().expect("node was just ensured");
+        node.bytes += item.len();
+        node.items.push_front(item);
+        self.len += 1;
+    }
+
+    pub fn push_back(&mut self, item: Bytes) {
+        if !self.nodes.back().is_some_and(|node| node.has_room(&item)) {
+            self.nodes.push_back(Node::default());
+        }
+        let node = self.nodes.back_mut().expect("node was just ensured");
+        node.bytes += item.len();
+        node.items.push_back(item);
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<Bytes> {
+        let node = self.nodes.front_mut()?;
+        let item = node.items.pop_front()?;
+        node.bytes -= item.len();
+        if node.items.is_empty() {
+            self.nodes.pop_front();
+        }
+        self.len -= 1;
+        Some(item)
+    }
+
+    pub fn pop_back(&mut self) -> Option<Bytes> {
+        let node = self.nodes.back_mut()?;
+        let item = node.items.pop_back()?;
+        node.bytes -= item.len();
+        if node.items.is_empty() {
+            self.nodes.pop_back();
+        }
+        self.len -= 1;
+        Some(item)
+    }
+
+    pub fn push(&mut self, end: End, item: Bytes) {
+        match end {
+            End::Left => self.push_front(item),
+            End::Right => self.push_back(item),
+        }
+    }
+
+    pub fn pop(&mut self, end: End) -> Option<Bytes> {
+        match end {
+            End::Left => self.pop_front(),
+            End::Right => self.pop_back(),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Bytes> {
+        let (node, offset) = self.locate(index)?;
+        self.nodes[node].items.get(offset)
+    }
+
+    /// Replaces the item at `index`, returns false when it is out of range.
+    pub fn set(&mut self, index: usize, item: Bytes) -> bool {
+        let Some((node, offset)) = self.locate(index) else {
+            return false;
+        };
+        let target = &mut self.nodes[node];
+        target.bytes = target.bytes - target.items[offset].len() + item.len();
+        target.items[offset] = item;
+        self.split_if_full(node);
+        true
+    }
+
+    /// Inserts so the item ends up at `index`, which may be the length to append.
+    pub fn insert(&mut self, index: usize, item: Bytes) {
+        if index >= self.len {
+            self.push_back(item);
+            return;
+        }
+        let (node, offset) = self.locate(index).expect("index is in range");
+        let target = &mut self.nodes[node];
+        target.bytes += item.len();
+        target.items.insert(offset, item);
+        self.len += 1;
+        self.split_if_full(node);
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Bytes> {
+        self.nodes.iter().flat_map(|node| node.items.iter())
+    }
+
+    /// The items from `index` on, skipping whole nodes to get there.
+    pub fn iter_from(&self, index: usize) -> impl Iterator<Item = &Bytes> {
+        let (node, offset) = self.locate(index).unwrap_or((self.nodes.len(), 0));
+        self.nodes.iter()
+            .skip(node)
+            .flat_map(|node| node.items.iter())
+            .skip(offset)
+    }
+
+    /// Drops `count` items from the front.
+    pub fn drain_front(&mut self, mut count: usize) {
+        while count > 0 {
+            let Some(node) = self.nodes.front_mut() else { break };
+            if node.items.len() <= count {
+                count -= node.items.len();
+                self.len -= node.items.len();
+                self.nodes.pop_front();
+            } else {
+                for item in node.items.drain(..count) {
+                    node.bytes -= item.len();
+                }
+                self.len -= count;
+                count = 0;
+            }
+        }
+    }
+
+    /// Drops `count` items from the back.
+    pub fn drain_back(&mut self, mut count: usize) {
+        while count > 0 {
+            let Some(node) = self.nodes.back_mut() else { break };
+            let len = node.items.len();
+            if len <= count {
+                count -= len;
+                self.len -= len;
+                self.nodes.pop_back();
+            } else {
+                for item in node.items.drain(len - count..) {
+                    node.bytes -= item.len();
+                }
+                self.len -= count;
+                count = 0;
+            }
+        }
+    }
+
+    /// Removes up to `limit` items equal to `element`, scanning from the back when `from_back`.
+    /// Returns how many were removed.
+    pub fn remove_matching(&mut self, element: &[u8], limit: usize, from_back: bool) -> usize {
+        let mut removed = 0;
+        let order: Vec<usize> = if from_back {
+            (0..self.nodes.len()).rev().collect()
+        } else {
+            (0..self.nodes.len()).collect()
+        };
+        for i in order {
+            if removed == limit {
+                break;
+            }
+            let node = &mut self.nodes[i];
+            let mut positions: Vec<usize> = node.items.iter()
+                .enumerate()
+                .filter(|(_, item)| item.as_ref() == element)
+                .map(|(position, _)| position)
+                .collect();
+            if from_back {
+                positions.reverse();
+            }
+            positions.truncate(limit - removed);
+            // highest first so the remaining positions stay valid
+            positions.sort_unstable_by(|a, b| b.cmp(a));
+            for position in &positions {
+                let item = node.items.remove(*position).expect("position was just found");
+                node.bytes -= item.len();
+            }
+            removed += positions.len();
+        }
+        self.nodes.retain(|node| !node.items.is_empty());
+        self.len -= removed;
+        removed
+    }
+
+    /// The node and the offset in it holding `index`, walking from the closer end.
+    fn locate(&self, index: usize) -> Option<(usize, usize)> {
+        if index >= self.len {
+            return None;
+        }
+        if index < self.len / 2 {
+            let mut index = index;
+            for (i, node) in self.nodes.iter().enumerate() {
+                if index < node.items.len() {
+                    return Some((i, index));
+                }
+                index -= node.items.len();
+            }
+        } else {
+            let mut from_end = self.len - 1 - index;
+            for (i, node) in self.nodes.iter().enumerate().rev() {
+                if from_end < node.items.len() {
+                    return Some((i, node.items.len() - 1 - from_end));
+                }
+                from_end -= node.items.len();
+            }
+        }
+        None
+    }
+
+    fn split_if_full(&mut self, index: usize) {
+        let node = &mut self.nodes[index];
+        if node.bytes <= NODE_MAX_BYTES || node.items.len() < 2 {
+            return;
+        }
+        let items = node.items.split_off(node.items.len() / 2);
+        let bytes = items.iter().map(Bytes::len).sum::<usize>();
+        node.bytes -= bytes;
+        self.nodes.insert(index + 1, Node { items, bytes });
+    }
+}
+
+impl FromIterator<Bytes> for QuickList {
+    fn from_iter<I: IntoIterator<Item = Bytes>>(iter: I) -> Self {
+        let mut list = QuickList::new();
+        iter.into_iter().for_each(|item| list.push_back(item));
+        list
+    }
+}
+
+impl Extend<Bytes> for QuickList {
+    fn extend<I: IntoIterator<Item = Bytes>>(&mut self, iter: I) {
+        iter.into_iter().for_each(|item| self.push_back(item));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // items large enough that a node holds only a few of them
+    fn item(n: usize) -> Bytes {
+        Bytes::from(format!("{:04}{}", n, "x".repeat(1000)))
+    }
+
+    fn items(list: &QuickList) -> Vec<Bytes> {
+        list.iter().cloned().collect()
+    }
+
+    #[test]
+    fn pushes_and_pops_at_both_ends() {
+        let mut list = QuickList::new();
+        for n in 0..20 {
+            list.push(End::Right, item(n + 20));
+            list.push(End::Left, item(19 - n));
+        }
+        assert_eq!(list.len(), 40);
+        assert!(list.node_count() > 1);
+        assert_eq!(items(&list), (0..40).map(item).collect::<Vec<_>>());
+
+        for n in 0..20 {
+            assert_eq!(list.pop(End::Left), Some(item(n)));
+            assert_eq!(list.pop(End::Right), Some(item(39 - n)));
+        }
+        assert!(list.is_empty());
+        assert_eq!(list.node_count(), 0);
+        assert_eq!(list.pop_front(), None);
+    }
+
+    #[test]
+    fn indexes_across_nodes() {
+        let mut list: QuickList = (0..40).map(item).collect();
+        assert_eq!(list.get(0), Some(&item(0)));
+        assert_eq!(list.get(25), Some(&item(25)));
+        assert_eq!(list.get(40), None);
+        assert_eq!(list.iter_from(37).cloned().collect::<Vec<_>>(), [item(37), item(38), item(39)]);
+        assert_eq!(list.iter_from(40).count(), 0);
+
+        assert!(list.set(30, item(100)));
+        assert!(!list.set(40, item(100)));
+        assert_eq!(list.get(30), Some(&item(100)));
+
+        list.insert(10, item(200));
+        list.insert(list.len(), item(300));
+        assert_eq!(list.len(), 42);
+        assert_eq!(list.get(10), Some(&item(200)));
+        assert_eq!(list.get(11), Some(&item(10)));
+        assert_eq!(list.get(41), Some(&item(300)));
+    }
+
+    #[test]
+    fn splits_a_node_that_grew_too_large() {
+        let mut list: QuickList = (0..4).map(item).collect();
+        assert_eq!(list.node_count(), 1);
+        for n in 4..12 {
+            list.insert(1, item(n));
+        }
+        assert!(list.node_count() > 1);
+        let mut expected: Vec<Bytes> = (4..12).rev().map(item).collect();
+        expected.insert(0, item(0));
+        expected.extend((1..4).map(item));
+        assert_eq!(items(&list), expected);
+    }
+
+    #[test]
+    fn drains_whole_and_partial_nodes() {
+        let mut list: QuickList = (0..40).map(item).collect();
+        list.drain_front(11);
+        list.drain_back(12);
+        assert_eq!(list.len(), 17);
+        assert_eq!(items(&list), (11..28).map(item).collect::<Vec<_>>());
+        list.drain_back(100);
+        assert!(list.is_empty());
+        assert_eq!(list.node_count(), 0);
+    }
+
+    #[test]
+    fn removes_matching_items_from_either_end() {
+        let target = Bytes::from_static(b"target");
+        let mut list: QuickList = (0..40).map(|n| if n % 4 == 0 { target.clone() } else { item(n) }).collect();
+
+        assert_eq!(list.remove_matching(&target, 2, false), 2);
+        assert_eq!(list.get(0), Some(&item(1)));
+        assert_eq!(list.get(1), Some(&item(2)));
+        assert_eq!(list.remove_matching(&target, 2, true), 2);
+        assert_eq!(list.get(list.len() - 1), Some(&item(39)));
+        assert_eq!(list.get(list.len() - 4), Some(&item(35)));
+
+        assert_eq!(list.remove_matching(&target, usize::MAX, false), 6);
+        assert_eq!(list.len(), 30);
+        assert!(list.iter().all(|item| *item != target));
+    }
+}
@@ -0,0 +1,27 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+/// Decodes a set of integers: <encoding u32> <count u32> and the little endian members,
+/// each 2, 4 or 8 bytes as the encoding says.
+pub fn members(data: &[u8]) -> Result<Vec<Bytes>> {
+    if data.len() < 8 {
+        bail!("Intset too short");
+    }
+    let size = u32::from_le_bytes(data[0..4].try_into()?) as usize;
+    let count = u32::from_le_bytes(data[4..8].try_into()?) as usize;
+    if !matches!(size, 2 | 4 | 8) {
+        bail!("Unknown intset encoding {}", size);
+    }
+    let contents = &data[8..];
+    if contents.len() < count * size {
+        bail!("Intset shorter than its count");
+    }
+    Ok(contents.chunks_exact(size).take(count).map(|member| {
+        let n = match size {
+            2 => i16::from_le_bytes([member[0], member[1]]) as i64,
+            4 => i32::from_le_bytes([member[0], member[1], member[2], member[3]]) as i64,
+            _ => i64::from_le_bytes(member.try_into().unwrap_or_default()),
+        };
+        Bytes::from(n.to_string())
+    }).collect())
+}
@@ -0,0 +1,139 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+// <total bytes u32> <element count u16>
+const HEADER_SIZE: usize = 6;
+const EOF: u8 = 0xff;
+
+/// Decodes the elements of a listpack, integers come back in their decimal form.
+pub fn entries(data: &[u8]) -> Result<Vec<Bytes>> {
+    if data.len() < HEADER_SIZE + 1 {
+        bail!("Listpack too short");
+    }
+    let mut entries = vec![];
+    let mut pos = HEADER_SIZE;
+    loop {
+        let first = *data.get(pos).ok_or_else(|| anyhow::anyhow!("Listpack without terminator"))?;
+        if first == EOF {
+            break;
+        }
+        let (entry, encoded_len) = match first {
+            // 0xxxxxxx, 7 bit unsigned integer
+            0x00..=0x7f => (Bytes::from((first as i64).to_string()), 1),
+            // 10xxxxxx, string up to 63 bytes
+            0x80..=0xbf => {
+                let len = (first & 0x3f) as usize;
+                (Bytes::copy_from_slice(slice(data, pos + 1, len)?), 1 + len)
+            },
+            // 110xxxxx yyyyyyyy, 13 bit signed integer
+            0xc0..=0xdf => {
+                let n = (((first & 0x1f) as i64) << 8) | slice(data, pos + 1, 1)?[0] as i64;
+                (Bytes::from(sign_extend(n, 13).to_string()), 2)
+            },
+            // 1110xxxx yyyyyyyy, string up to 4095 bytes
+            0xe0..=0xef => {
+                let len = (((first & 0x0f) as usize) << 8) | slice(data, pos + 1, 1)?[0] as usize;
+                (Bytes::copy_from_slice(slice(data, pos + 2, len)?), 2 + len)
+            },
+            // 11110000 <u32 len>, large string
+            0xf0 => {
+                let len = u32::from_le_bytes(slice(data, pos + 1, 4)?.try_into()?) as usize;
+                (Bytes::copy_from_slice(slice(data, pos + 5, len)?), 5 + len)
+            },
+            // 1111xxxx, 16, 24, 32 and 64 bit signed integers
+            0xf1..=0xf4 => {
+                let size = match first {
+                    0xf1 => 2,
+                    0xf2 => 3,
+                    0xf3 => 4,
+                    _ => 8,
+                };
+                let mut buf = [0; 8];
+                buf[..size].copy_from_slice(slice(data, pos + 1, size)?);
+                let n = sign_extend(i64::from_le_bytes(buf), size as u32 * 8);
+                (Bytes::from(n.to_string()), 1 + size)
+            },
+            _ => bail!("Unknown listpack encoding 0x{:02x}", first),
+        };
+        entries.push(entry);
+        // every entry is followed by its own length, for walking backwards
+        pos += encoded_len + backlen_size(encoded_len);
+    }
+    Ok(entries)
+}
+
+fn slice(data: &[u8], pos: usize, len: usize) -> Result<&[u8]> {
+    match data.get(pos..pos + len) {
+        Some(bytes) => Ok(bytes),
+        None => bail!("Listpack entry past the end of the listpack"),
+    }
+}
+
+fn sign_extend(n: i64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (n << shift) >> shift
+}
+
+fn backlen_size(encoded_len: usize) -> usize {
+    match encoded_len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listpack(encoded: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        for entry in encoded {
+            data.extend_from_slice(entry);
+            data.push(entry.len() as u8);
+        }
+        data.push(EOF);
+        let total = data.len() as u32;
+        data[..4].copy_from_slice(&total.to_le_bytes());
+        data[4..6].copy_from_slice(&(encoded.len() as u16).to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn decodes_strings_and_integers() {
+        let data = listpack(&[
+            &[0x05],
+            &[0x82, b'h', b'i'],
+            &[0xdf, 0xff],
+            &[0xf1, 0xe8, 0x03],
+            &[0xf2, 0x00, 0x00, 0x80],
+            &[0xf4, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f],
+            &[0xe0, 0x03, b'a', b'b', b'c'],
+        ]);
+        let decoded = entries(&data).unwrap();
+        assert_eq!(decoded, [
+            "5",
+            "hi",
+            "-1",
+            "1000",
+            "-8388608",
+            "9223372036854775807",
+            "abc",
+        ]);
+    }
+
+    #[test]
+    fn rejects_truncated_listpacks() {
+        let data = listpack(&[&[0x82, b'h', b'i']]);
+        assert!(entries(&data[..data.len() - 1]).is_err());
+        assert!(entries(&data[..HEADER_SIZE + 2]).is_err());
+        assert!(entries(&[0; HEADER_SIZE]).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_encodings() {
+        assert!(entries(&listpack(&[&[0xf5]])).is_err());
+    }
+}
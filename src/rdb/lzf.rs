@@ -0,0 +1,68 @@
+use anyhow::{bail, Result};
+
+// most bytes of output per byte of input, a 3 byte back reference copies 264
+const MAX_EXPANSION: usize = 88;
+
+/// Decompresses an LZF block into exactly `expected_len` bytes.
+pub fn decompress(input: &[u8], expected_len: usize) -> Result<Vec<u8>> {
+    // the length comes from the file, don't trust it with more than the input can expand to
+    let mut output = Vec::with_capacity(expected_len.min(input.len().saturating_mul(MAX_EXPANSION)));
+    let mut ip = 0;
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl < 32 {
+            // literal run of ctrl + 1 bytes
+            let len = ctrl + 1;
+            if ip + len > input.len() {
+                bail!("LZF literal run past the end of input");
+            }
+            output.extend_from_slice(&input[ip..ip + len]);
+            ip += len;
+        } else {
+            // back reference, the length is stored minus 2 and extended by a byte when it is 7
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(ip).ok_or_else(|| anyhow::anyhow!("LZF truncated back reference"))? as usize;
+                ip += 1;
+            }
+            let low = *input.get(ip).ok_or_else(|| anyhow::anyhow!("LZF truncated back reference"))? as usize;
+            ip += 1;
+            let distance = ((ctrl & 0x1f) << 8) + low + 1;
+            if distance > output.len() {
+                bail!("LZF back reference before the start of output");
+            }
+            let start = output.len() - distance;
+            // byte by byte, the source may overlap with what is being written
+            for i in 0..len + 2 {
+                output.push(output[start + i]);
+            }
+        }
+    }
+    if output.len() != expected_len {
+        bail!("LZF decompressed {} bytes, expected {}", output.len(), expected_len);
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_literals_and_back_references() {
+        // "abc" as a literal, then 6 bytes from 3 back
+        assert_eq!(decompress(&[0x02, b'a', b'b', b'c', 0x80, 0x02], 9).unwrap(), b"abcabcabc");
+        // a single literal repeated through an overlapping reference with an extended length
+        assert_eq!(decompress(&[0x00, b'a', 0xe0, 0x00, 0x00], 10).unwrap(), b"aaaaaaaaaa");
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert!(decompress(&[0x03, b'a', b'b'], 4).is_err());
+        assert!(decompress(&[0x00, b'a', 0xe0], 10).is_err());
+        assert!(decompress(&[0x00, b'a', 0x20, 0x05], 4).is_err());
+        assert!(decompress(&[0x01, b'a', b'b'], 3).is_err());
+        assert!(decompress(&[0x00, b'a'], usize::MAX).is_err());
+    }
+}
@@ -0,0 +1,49 @@
+pub mod crc64;
+pub mod intset;
+pub mod listpack;
+pub mod lzf;
+pub mod reader;
+pub mod writer;
+pub mod save;
+
+pub use reader::{load, load_file};
+pub use writer::dump;
+
+pub const MAGIC: &[u8] = b"REDIS";
+
+// opcodes that can appear where a value type is expected
+pub const OPCODE_FUNCTION2: u8 = 0xF5;
+pub const OPCODE_MODULE_AUX: u8 = 0xF7;
+pub const OPCODE_IDLE: u8 = 0xF8;
+pub const OPCODE_FREQ: u8 = 0xF9;
+pub const OPCODE_AUX: u8 = 0xFA;
+pub const OPCODE_RESIZEDB: u8 = 0xFB;
+pub const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+pub const OPCODE_EXPIRETIME: u8 = 0xFD;
+pub const OPCODE_SELECTDB: u8 = 0xFE;
+pub const OPCODE_EOF: u8 = 0xFF;
+
+pub const TYPE_STRING: u8 = 0;
+pub const TYPE_LIST: u8 = 1;
+pub const TYPE_SET: u8 = 2;
+pub const TYPE_ZSET: u8 = 3;
+pub const TYPE_HASH: u8 = 4;
+pub const TYPE_ZSET_2: u8 = 5;
+pub const TYPE_SET_INTSET: u8 = 11;
+pub const TYPE_STREAM_LISTPACKS: u8 = 15;
+pub const TYPE_HASH_LISTPACK: u8 = 16;
+pub const TYPE_ZSET_LISTPACK: u8 = 17;
+pub const TYPE_LIST_QUICKLIST_2: u8 = 18;
+pub const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+pub const TYPE_SET_LISTPACK: u8 = 20;
+pub const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+// quicklist node containers
+pub const QUICKLIST_NODE_PLAIN: u64 = 1;
+pub const QUICKLIST_NODE_PACKED: u64 = 2;
+
+// special string encodings, flagged by the two high bits of a length being 11
+pub const ENC_INT8: u8 = 0;
+pub const ENC_INT16: u8 = 1;
+pub const ENC_INT32: u8 = 2;
+pub const ENC_LZF: u8 = 3;
@@ -0,0 +1,399 @@
+use std::path::Path;
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+
+use crate::{clock::now_ms, db::Db, fieldmap::FieldMap, quicklist::QuickList, value::Value};
+
+use super::*;
+
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+struct RdbReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RdbReader<'a> {
+
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if n > self.data.len() - self.pos {
+            bail!("Unexpected end of RDB at offset {}", self.pos);
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_length_or_encoding(&mut self) -> Result<Length> {
+        let first = self.read_u8()?;
+        Ok(match first >> 6 {
+            0b00 => Length::Len((first & 0x3f) as u64),
+            0b01 => Length::Len((((first & 0x3f) as u64) << 8) | self.read_u8()? as u64),
+            0b10 => match first {
+                0x80 => Length::Len(u32::from_be_bytes(self.read_bytes(4)?.try_into()?) as u64),
+                0x81 => Length::Len(u64::from_be_bytes(self.read_bytes(8)?.try_into()?)),
+                _ => bail!("Unknown length encoding 0x{:02x}", first),
+            },
+            _ => Length::Encoded(first & 0x3f),
+        })
+    }
+
+    fn read_length(&mut self) -> Result<u64> {
+        match self.read_length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => bail!("Expected a length, found a string encoding"),
+        }
+    }
+
+    fn read_string(&mut self) -> Result<Bytes> {
+        match self.read_length_or_encoding()? {
+            Length::Len(len) => Ok(Bytes::copy_from_slice(self.read_bytes(len as usize)?)),
+            Length::Encoded(ENC_INT8) => Ok((self.read_u8()? as i8).to_string().into()),
+            Length::Encoded(ENC_INT16) => Ok(i16::from_le_bytes(self.read_bytes(2)?.try_into()?).to_string().into()),
+            Length::Encoded(ENC_INT32) => Ok(i32::from_le_bytes(self.read_bytes(4)?.try_into()?).to_string().into()),
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.read_length()? as usize;
+                let len = self.read_length()? as usize;
+                let compressed = self.read_bytes(compressed_len)?;
+                Ok(lzf::decompress(compressed, len)?.into())
+            },
+            Length::Encoded(encoding) => bail!("Unknown string encoding {}", encoding),
+        }
+    }
+
+    fn read_strings(&mut self, count: u64) -> Result<Vec<Bytes>> {
+        (0..count).map(|_| self.read_string()).collect()
+    }
+
+    // ZSET scores are strings prefixed by their length, with three lengths for special values
+    fn read_score_string(&mut self) -> Result<f64> {
+        Ok(match self.read_u8()? {
+            253 => f64::NAN,
+            254 => f64::INFINITY,
+            255 => f64::NEG_INFINITY,
+            len => std::str::from_utf8(self.read_bytes(len as usize)?)?.parse()?,
+        })
+    }
+
+    fn skip_lengths(&mut self, count: usize) -> Result<()> {
+        for _ in 0..count {
+            self.read_length()?;
+        }
+        Ok(())
+    }
+
+    // walks over a stream, returns its encoding as is
+    fn read_stream(&mut self, value_type: u8) -> Result<Bytes> {
+        let start = self.pos;
+        for _ in 0..self.read_length()? {
+            // the id of the node's master entry, then the node itself
+            self.read_string()?;
+            self.read_string()?;
+        }
+        // length, last id
+        self.skip_lengths(3)?;
+        if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            // first id, max deleted id, entries added
+            self.skip_lengths(5)?;
+        }
+        for _ in 0..self.read_length()? {
+            // consumer group: name, last delivered id and, since version 2, entries read
+            self.read_string()?;
+            self.skip_lengths(if value_type >= TYPE_STREAM_LISTPACKS_2 { 3 } else { 2 })?;
+            // pending entries: raw id, delivery time, delivery count
+            for _ in 0..self.read_length()? {
+                self.read_bytes(16 + 8)?;
+                self.read_length()?;
+            }
+            for _ in 0..self.read_length()? {
+                // consumer: name, seen time, since version 3 active time, raw pending ids
+                self.read_string()?;
+                self.read_bytes(if value_type >= TYPE_STREAM_LISTPACKS_3 { 16 } else { 8 })?;
+                for _ in 0..self.read_length()? {
+                    self.read_bytes(16)?;
+                }
+            }
+        }
+        Ok(Bytes::copy_from_slice(&self.data[start..self.pos]))
+    }
+
+    fn read_value(&mut self, value_type: u8) -> Result<Value> {
+        Ok(match value_type {
+            TYPE_STRING => Value::String(self.read_string()?),
+            TYPE_LIST => {
+                let len = self.read_length()?;
+                Value::List(self.read_strings(len)?.into_iter().collect())
+            },
+            TYPE_LIST_QUICKLIST_2 => {
+                let mut items = QuickList::new();
+                for _ in 0..self.read_length()? {
+                    let container = self.read_length()?;
+                    let node = self.read_string()?;
+                    match container {
+                        QUICKLIST_NODE_PLAIN => items.push_back(node),
+                        QUICKLIST_NODE_PACKED => items.extend(listpack::entries(&node)?),
+                        _ => bail!("Unknown quicklist container {}", container),
+                    }
+                }
+                Value::List(items)
+            },
+            TYPE_SET => {
+                let len = self.read_length()?;
+                Value::Set(self.read_strings(len)?.into_iter().collect())
+            },
+            TYPE_SET_INTSET => Value::Set(intset::members(&self.read_string()?)?.into_iter().collect::<HashSet<_>>()),
+            TYPE_SET_LISTPACK => Value::Set(listpack::entries(&self.read_string()?)?.into_iter().collect()),
+            TYPE_HASH => {
+                let len = self.read_length()?;
+                let mut fields = FieldMap::new();
+                for _ in 0..len {
+                    let field = self.read_string()?;
+                    fields.insert(field, self.read_string()?);
+                }
+                Value::Hash(fields)
+            },
+            TYPE_HASH_LISTPACK => {
+                let entries = listpack::entries(&self.read_string()?)?;
+                Value::Hash(pairs(entries)?.collect())
+            },
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.read_length()?;
+                let mut members = HashMap::new();
+                for _ in 0..len {
+                    let member = self.read_string()?;
+                    let score = if value_type == TYPE_ZSET_2 {
+                        f64::from_le_bytes(self.read_bytes(8)?.try_into()?)
+                    } else {
+                        self.read_score_string()?
+                    };
+                    members.insert(member, score);
+                }
+                Value::ZSet(members)
+            },
+            TYPE_ZSET_LISTPACK => {
+                let entries = listpack::entries(&self.read_string()?)?;
+                let mut members = HashMap::new();
+                for (member, score) in pairs(entries)? {
+                    let score = std::str::from_utf8(&score)?.parse()?;
+                    members.insert(member, score);
+                }
+                Value::ZSet(members)
+            },
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                Value::Stream { rdb_type: value_type, encoded: self.read_stream(value_type)? }
+            },
+            _ => bail!("Unsupported RDB value type {}", value_type),
+        })
+    }
+}
+
+// field value field value ... as (field, value) pairs
+fn pairs(entries: Vec<Bytes>) -> Result<impl Iterator<Item = (Bytes, Bytes)>> {
+    if !entries.len().is_multiple_of(2) {
+        bail!("Odd number of listpack entries in a map");
+    }
+    let mut entries = entries.into_iter();
+    Ok(std::iter::from_fn(move || Some((entries.next()?, entries.next()?))))
+}
+
+/// Loads an RDB payload into the keyspace, returns how many keys were loaded.
+///
+/// Only database 0 is kept, keys that already expired are skipped.
+pub fn load(data: &[u8], db: &mut Db) -> Result<usize> {
+    let mut reader = RdbReader { data, pos: 0 };
+
+    if reader.read_bytes(MAGIC.len())? != MAGIC {
+        bail!("Not an RDB file");
+    }
+    let version: u32 = std::str::from_utf8(reader.read_bytes(4)?)?
+        .parse()
+        .map_err(|_| anyhow!("Invalid RDB version"))?;
+
+    let now = now_ms();
+    let mut selected_db = 0;
+    let mut expires_at = None;
+    let mut loaded = 0;
+
+    loop {
+        let opcode = reader.read_u8()?;
+        match opcode {
+            OPCODE_AUX => {
+                // redis-ver, ctime and friends, nothing we act on
+                reader.read_string()?;
+                reader.read_string()?;
+            },
+            OPCODE_SELECTDB => {
+                selected_db = reader.read_length()?;
+            },
+            OPCODE_RESIZEDB => {
+                reader.read_length()?;
+                reader.read_length()?;
+            },
+            OPCODE_EXPIRETIME => {
+                let seconds = u32::from_le_bytes(reader.read_bytes(4)?.try_into()?);
+                expires_at = Some(seconds as u64 * 1000);
+            },
+            OPCODE_EXPIRETIME_MS => {
+                expires_at = Some(u64::from_le_bytes(reader.read_bytes(8)?.try_into()?));
+            },
+            OPCODE_IDLE => {
+                reader.read_length()?;
+            },
+            OPCODE_FREQ => {
+                reader.read_u8()?;
+            },
+            OPCODE_MODULE_AUX | OPCODE_FUNCTION2 => {
+                bail!("RDB modules and functions are not supported");
+            },
+            OPCODE_EOF => {
+                if version >= 5 {
+                    let expected = u64::from_le_bytes(reader.read_bytes(8)?.try_into()?);
+                    let actual = crc64::crc64(0, &data[..reader.pos - 8]);
+                    // a zero checksum means it was disabled when saving
+                    if expected != 0 && expected != actual {
+                        bail!("RDB checksum mismatch, expected {:016x}, got {:016x}", expected, actual);
+                    }
+                }
+                break;
+            },
+            value_type => {
+                let key = reader.read_string()?;
+                let value = reader.read_value(value_type)?;
+                let deadline = expires_at.take();
+                if selected_db != 0 || deadline.is_some_and(|deadline| deadline <= now) {
+                    continue;
+                }
+                db.insert_with_expiry(key, value, deadline);
+                loaded += 1;
+            },
+        }
+    }
+
+    Ok(loaded)
+}
+
+/// Loads the RDB file at `path`, a missing file is an empty dataset.
+pub fn load_file(path: &Path, db: &mut Db) -> Result<usize> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    load(&data, db)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Entry;
+
+    fn strings(items: &[&str]) -> Vec<Bytes> {
+        items.iter().map(|item| Bytes::copy_from_slice(item.as_bytes())).collect()
+    }
+
+    // a payload with a single key, written by hand to reach the encodings we never write
+    fn payload(value_type: u8, key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(b"0011");
+        data.push(value_type);
+        data.push(key.len() as u8);
+        data.extend_from_slice(key);
+        data.extend_from_slice(value);
+        data.push(OPCODE_EOF);
+        // a zero checksum is not verified
+        data.extend_from_slice(&[0; 8]);
+        data
+    }
+
+    #[test]
+    fn round_trips_every_type() {
+        let expires_at = now_ms() + 60_000;
+        let long_list: QuickList = (0..5000).map(|n| Bytes::from(n.to_string())).collect();
+        // an empty stream: no nodes, length 0, last id 0-0, no consumer groups
+        let stream = Bytes::from_static(&[0, 0, 0, 0, 0]);
+        let snapshot = vec![
+            (Bytes::from("int"), Entry::new(Value::String(Bytes::from("-70000")), None)),
+            (Bytes::from("string"), Entry::new(Value::String(Bytes::from("x".repeat(20000))), Some(expires_at))),
+            (Bytes::from("list"), Entry::new(Value::List(long_list), None)),
+            (Bytes::from("set"), Entry::new(Value::Set(strings(&["a", "b", "42"]).into_iter().collect()), None)),
+            (Bytes::from("hash"), Entry::new(Value::Hash(FieldMap::from_iter([(Bytes::from("f"), Bytes::from("v"))])), None)),
+            (Bytes::from("zset"), Entry::new(Value::ZSet(HashMap::from([(Bytes::from("m"), 1.5), (Bytes::from("n"), -3.0)])), None)),
+            (Bytes::from("stream"), Entry::new(Value::Stream { rdb_type: TYPE_STREAM_LISTPACKS, encoded: stream.clone() }, None)),
+            (Bytes::from("expired"), Entry::new(Value::String(Bytes::from("gone")), Some(1))),
+        ];
+
+        let mut db = Db::new();
+        assert_eq!(load(&dump(&snapshot), &mut db).unwrap(), 7);
+
+        assert!(matches!(db.get(b"int"), Some(Value::String(s)) if s == "-70000"));
+        assert!(matches!(db.get(b"string"), Some(Value::String(s)) if s.len() == 20000));
+        assert_eq!(db.get_entry(b"string").unwrap().expires_at, Some(expires_at));
+        assert_eq!(db.get_entry(b"int").unwrap().expires_at, None);
+        match db.get(b"list") {
+            Some(Value::List(items)) => assert!(items.iter().enumerate().all(|(n, item)| *item == n.to_string())),
+            _ => panic!("list did not load"),
+        }
+        assert!(matches!(db.get(b"set"), Some(Value::Set(members)) if *members == strings(&["a", "b", "42"]).into_iter().collect()));
+        assert!(matches!(db.get(b"hash"), Some(Value::Hash(fields)) if fields.len() == 1 && fields.get(b"f").is_some_and(|value| value == "v")));
+        assert!(matches!(db.get(b"zset"), Some(Value::ZSet(members)) if members[&Bytes::from("m")] == 1.5 && members[&Bytes::from("n")] == -3.0));
+        assert!(matches!(db.get(b"stream"), Some(Value::Stream { rdb_type: TYPE_STREAM_LISTPACKS, encoded }) if *encoded == stream));
+        assert!(db.get(b"expired").is_none());
+    }
+
+    #[test]
+    fn loads_compressed_strings_and_listpacks() {
+        // "abcabcabc" as an LZF string
+        let mut value = vec![0xc0 | ENC_LZF, 6, 9];
+        value.extend_from_slice(&[0x02, b'a', b'b', b'c', 0x80, 0x02]);
+        let mut db = Db::new();
+        load(&payload(TYPE_STRING, b"k", &value), &mut db).unwrap();
+        assert!(matches!(db.get(b"k"), Some(Value::String(s)) if s == "abcabcabc"));
+
+        // a listpack with field "f" and value 7
+        let listpack = [11, 0, 0, 0, 2, 0, 0x81, b'f', 2, 0x07, 1, 0xff];
+        let mut value = vec![listpack.len() as u8];
+        value.extend_from_slice(&listpack);
+        let mut db = Db::new();
+        load(&payload(TYPE_HASH_LISTPACK, b"h", &value), &mut db).unwrap();
+        assert!(matches!(db.get(b"h"), Some(Value::Hash(fields)) if fields.get(b"f").is_some_and(|value| value == "7")));
+    }
+
+    #[test]
+    fn rejects_damaged_payloads() {
+        let snapshot = vec![(Bytes::from("key"), Entry::new(Value::String(Bytes::from("value")), None))];
+        let data = dump(&snapshot);
+
+        let mut corrupted = data.clone();
+        let at = corrupted.len() - 12;
+        corrupted[at] ^= 0xff;
+        assert!(load(&corrupted, &mut Db::new()).unwrap_err().to_string().contains("checksum mismatch"));
+
+        for len in [0, 5, data.len() / 2, data.len() - 1] {
+            assert!(load(&data[..len], &mut Db::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_lengths_past_the_end() {
+        // a string claiming u64::MAX bytes
+        let mut value = vec![0x81];
+        value.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(load(&payload(TYPE_STRING, b"k", &value), &mut Db::new()).is_err());
+
+        // an LZF string claiming to expand to u64::MAX bytes
+        let mut value = vec![0xc0 | ENC_LZF, 2, 0x81];
+        value.extend_from_slice(&u64::MAX.to_be_bytes());
+        value.extend_from_slice(&[0x00, b'a']);
+        assert!(load(&payload(TYPE_STRING, b"k", &value), &mut Db::new()).is_err());
+    }
+}
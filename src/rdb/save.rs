@@ -0,0 +1,139 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Result;
+use tokio::{sync::RwLock, time::Duration};
+
+use crate::{clock::now_ms, db::Db, error::RedisError, State};
+
+use super::writer::dump;
+
+// how often the save triggers are checked
+const CRON_PERIOD: Duration = Duration::from_millis(100);
+// after a failed background save, wait this long before the triggers may retry
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+/// Parses the `save` parameter, pairs of `<seconds> <changes>`, an empty string disables saving.
+pub fn parse_save_params(value: &str) -> Option<Vec<(u64, u64)>> {
+    let numbers = value.split_whitespace()
+        .map(|n| n.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if numbers.len() % 2 != 0 {
+        return None;
+    }
+    Some(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+pub fn format_save_params(params: &[(u64, u64)]) -> String {
+    params.iter()
+        .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Goes through a temporary file in the same directory so a crash never leaves a torn file behind.
+pub fn write_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let temp = path.with_file_name(format!("temp-{}-{}", std::process::id(), name));
+    let result = std::fs::write(&temp, data)
+        .and_then(|_| std::fs::File::open(&temp)?.sync_all())
+        .and_then(|_| std::fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+/// SAVE, snapshots in the foreground while holding the keyspace so nothing changes meanwhile.
+pub async fn save(storage: &Arc<RwLock<Db>>, state: &Arc<RwLock<State>>) -> Result<(), RedisError> {
+    let path = {
+        let state = state.read().await;
+        if state.bgsave_in_progress {
+            return Err(RedisError::Err("Background save already in progress".to_string()));
+        }
+        state.rdb_path()
+    };
+
+    let mut storage = storage.write().await;
+    write_file(&path, &dump(&storage.snapshot())).map_err(anyhow::Error::from)?;
+    storage.dirty = 0;
+    drop(storage);
+
+    let mut state = state.write().await;
+    state.lastsave = now_ms() / 1000;
+    state.last_bgsave_ok = true;
+    Ok(())
+}
+
+/// BGSAVE, copies the keyspace out and leaves serializing and writing to a blocking thread.
+pub async fn bgsave(storage: &Arc<RwLock<Db>>, state: &Arc<RwLock<State>>) -> Result<(), RedisError> {
+    let path = {
+        let mut state = state.write().await;
+        if state.bgsave_in_progress {
+            return Err(RedisError::Err("Background save already in progress".to_string()));
+        }
+        state.bgsave_in_progress = true;
+        state.bgsave_scheduled = false;
+        state.last_bgsave_try = now_ms() / 1000;
+        state.rdb_path()
+    };
+
+    let (snapshot, dirty) = {
+        let storage = storage.read().await;
+        (storage.snapshot(), storage.dirty)
+    };
+
+    let storage = Arc::clone(storage);
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        let result = tokio::task::spawn_blocking(move || write_file(&path, &dump(&snapshot))).await;
+        let saved = match result {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                eprintln!("Background saving error: {}", e);
+                false
+            },
+            Err(e) => {
+                eprintln!("Background saving panicked: {}", e);
+                false
+            },
+        };
+        if saved {
+            // writes that happened while saving still count towards the next one
+            let mut storage = storage.write().await;
+            storage.dirty = storage.dirty.saturating_sub(dirty);
+        }
+        let mut state = state.write().await;
+        if saved {
+            state.lastsave = now_ms() / 1000;
+        }
+        state.last_bgsave_ok = saved;
+        state.bgsave_in_progress = false;
+    });
+    Ok(())
+}
+
+/// Starts a background save whenever one of the `save <seconds> <changes>` points is reached,
+/// or when BGSAVE SCHEDULE asked for one.
+pub async fn save_cron(storage: Arc<RwLock<Db>>, state: Arc<RwLock<State>>) {
+    let mut interval = tokio::time::interval(CRON_PERIOD);
+    loop {
+        interval.tick().await;
+        let dirty = storage.read().await.dirty;
+        let due = {
+            let state = state.read().await;
+            let now = now_ms() / 1000;
+            let may_retry = state.last_bgsave_ok || now.saturating_sub(state.last_bgsave_try) >= BGSAVE_RETRY_DELAY;
+            !state.bgsave_in_progress && (
+                state.bgsave_scheduled
+                || state.save_params.iter().any(|&(seconds, changes)| {
+                    dirty >= changes && now.saturating_sub(state.lastsave) >= seconds && may_retry
+                })
+            )
+        };
+        if due {
+            if let Err(e) = bgsave(&storage, &state).await {
+                eprintln!("Failed to start background save: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use bytes::Bytes;
+
+use crate::{clock::now_ms, db::Entry, value::Value};
+
+use super::*;
+
+pub const RDB_VERSION: u32 = 11;
+
+struct RdbWriter {
+    buf: Vec<u8>,
+}
+
+impl RdbWriter {
+
+    fn write_length(&mut self, len: u64) {
+        if len < 1 << 6 {
+            self.buf.push(len as u8);
+        } else if len < 1 << 14 {
+            self.buf.push(0x40 | (len >> 8) as u8);
+            self.buf.push(len as u8);
+        } else if len <= u32::MAX as u64 {
+            self.buf.push(0x80);
+            self.buf.extend_from_slice(&(len as u32).to_be_bytes());
+        } else {
+            self.buf.push(0x81);
+            self.buf.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+
+    fn write_string(&mut self, s: &[u8]) {
+        if let Some(n) = integer_encodable(s) {
+            if let Ok(n) = i8::try_from(n) {
+                self.buf.push(0xc0 | ENC_INT8);
+                self.buf.extend_from_slice(&n.to_le_bytes());
+            } else if let Ok(n) = i16::try_from(n) {
+                self.buf.push(0xc0 | ENC_INT16);
+                self.buf.extend_from_slice(&n.to_le_bytes());
+            } else {
+                self.buf.push(0xc0 | ENC_INT32);
+                self.buf.extend_from_slice(&n.to_le_bytes());
+            }
+            return;
+        }
+        self.write_length(s.len() as u64);
+        self.buf.extend_from_slice(s);
+    }
+
+    // the plain encodings of every type, redis converts them to its compact ones when loading
+    fn write_value(&mut self, value: &Value) {
+        match value {
+            Value::String(s) => self.write_string(s),
+            Value::List(items) => {
+                self.write_length(items.len() as u64);
+                items.iter().for_each(|item| self.write_string(item));
+            },
+            Value::Set(members) => {
+                self.write_length(members.len() as u64);
+                members.iter().for_each(|member| self.write_string(member));
+            },
+            Value::Hash(fields) => {
+                self.write_length(fields.len() as u64);
+                for (field, value) in fields.iter() {
+                    self.write_string(field);
+                    self.write_string(value);
+                }
+            },
+            Value::ZSet(members) => {
+                self.write_length(members.len() as u64);
+                for (member, score) in members {
+                    self.write_string(member);
+                    self.buf.extend_from_slice(&score.to_le_bytes());
+                }
+            },
+            Value::Stream { encoded, .. } => self.buf.extend_from_slice(encoded),
+        }
+    }
+
+    fn write_aux(&mut self, key: &str, value: &str) {
+        self.buf.push(OPCODE_AUX);
+        self.write_string(key.as_bytes());
+        self.write_string(value.as_bytes());
+    }
+}
+
+// like redis, strings that read back as the same 32 bit integer are stored as one
+fn integer_encodable(s: &[u8]) -> Option<i32> {
+    if s.is_empty() || s.len() > 11 {
+        return None;
+    }
+    let n: i32 = std::str::from_utf8(s).ok()?.parse().ok()?;
+    (n.to_string().as_bytes() == s).then_some(n)
+}
+
+/// Serializes a keyspace snapshot as an RDB version 11 payload, checksum included.
+pub fn dump(entries: &[(Bytes, Entry)]) -> Vec<u8> {
+    let mut writer = RdbWriter { buf: Vec::new() };
+    writer.buf.extend_from_slice(MAGIC);
+    writer.buf.extend_from_slice(format!("{:04}", RDB_VERSION).as_bytes());
+
+    writer.write_aux("redis-ver", "7.2.0");
+    writer.write_aux("redis-bits", "64");
+    writer.write_aux("ctime", &(now_ms() / 1000).to_string());
+    writer.write_aux("aof-base", "0");
+
+    if !entries.is_empty() {
+        writer.buf.push(OPCODE_SELECTDB);
+        writer.write_length(0);
+        writer.buf.push(OPCODE_RESIZEDB);
+        writer.write_length(entries.len() as u64);
+        writer.write_length(entries.iter().filter(|(_, entry)| entry.expires_at.is_some()).count() as u64);
+    }
+
+    for (key, entry) in entries {
+        if let Some(expires_at) = entry.expires_at {
+            writer.buf.push(OPCODE_EXPIRETIME_MS);
+            writer.buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        writer.buf.push(match entry.value {
+            Value::String(_) => TYPE_STRING,
+            Value::List(_) => TYPE_LIST,
+            Value::Set(_) => TYPE_SET,
+            Value::Hash(_) => TYPE_HASH,
+            Value::ZSet(_) => TYPE_ZSET_2,
+            Value::Stream { rdb_type, .. } => rdb_type,
+        });
+        writer.write_string(key);
+        writer.write_value(&entry.value);
+    }
+
+    writer.buf.push(OPCODE_EOF);
+    let checksum = crc64::crc64(0, &writer.buf);
+    writer.buf.extend_from_slice(&checksum.to_le_bytes());
+    writer.buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_only_canonical_32_bit_integers() {
+        assert_eq!(integer_encodable(b"0"), Some(0));
+        assert_eq!(integer_encodable(b"-2147483648"), Some(i32::MIN));
+        assert_eq!(integer_encodable(b"2147483648"), None);
+        assert_eq!(integer_encodable(b"007"), None);
+        assert_eq!(integer_encodable(b"+7"), None);
+        assert_eq!(integer_encodable(b""), None);
+    }
+
+    #[test]
+    fn picks_the_smallest_length_and_integer_encodings() {
+        let mut writer = RdbWriter { buf: Vec::new() };
+        writer.write_length(63);
+        writer.write_length(64);
+        writer.write_length(1 << 14);
+        writer.write_string(b"-1");
+        writer.write_string(b"300");
+        writer.write_string(b"70000");
+        writer.write_string(b"abc");
+        assert_eq!(writer.buf, [
+            63,
+            0x40, 64,
+            0x80, 0, 0, 0x40, 0,
+            0xc0, 0xff,
+            0xc1, 0x2c, 0x01,
+            0xc2, 0x70, 0x11, 0x01, 0x00,
+            3, b'a', b'b', b'c',
+        ]);
+    }
+}
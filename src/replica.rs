@@ -0,0 +1,219 @@
+use std::{collections::hash_map::RandomState, hash::{BuildHasher, Hasher}, sync::Arc};
+
+use anyhow::anyhow;
+use tokio::{net::TcpStream, sync::RwLock, task::JoinHandle, time::{sleep, timeout, Duration}};
+
+use crate::{broadcast::Broadcaster, clock::now_ms, db::Db, handshake::{handshake, receive_payload}, serve, session::Session, State};
+
+// first retry delay after the link fails, doubled on every failure in a row
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+// how long connecting and the handshake up to the answer to PSYNC may take before the
+// attempt is abandoned
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often a replica sends REPLCONF ACK with its offset to the master.
+pub const REPLICA_ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Where the link to the master stands, redis' repl_state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    /// Waiting to (re)connect.
+    Connect,
+    Connecting,
+    Handshake,
+    /// Waiting for PSYNC to be answered and the payload of a full resync.
+    Sync,
+    /// Applying the replication stream.
+    Connected,
+}
+
+impl std::fmt::Display for LinkState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkState::Connect => write!(f, "connect"),
+            LinkState::Connecting => write!(f, "connecting"),
+            LinkState::Handshake => write!(f, "handshake"),
+            LinkState::Sync => write!(f, "sync"),
+            LinkState::Connected => write!(f, "connected"),
+        }
+    }
+}
+
+/// A fresh 40 character replication id, std's randomly seeded hasher is the entropy source.
+pub fn generate_replid() -> String {
+    (0..5).map(|i| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(now_ms() ^ i);
+        format!("{:08x}", hasher.finish() as u32)
+    }).collect()
+}
+
+async fn set_link_state(state: &Arc<RwLock<State>>, link_state: LinkState) {
+    let mut state = state.write().await;
+    if link_state != LinkState::Connected && state.master_link_state == LinkState::Connected {
+        state.master_link_down_since = now_ms();
+    }
+    state.master_link_state = link_state;
+}
+
+/// Starts the master link task. A plain function rather than an async one: REPLICAOF starts
+/// the link from within a connection the link itself may be serving, and the compiler cannot
+/// see through that cycle of futures.
+pub fn spawn_master_link(storage: Arc<RwLock<Db>>, state: Arc<RwLock<State>>, broadcaster: Arc<RwLock<Broadcaster>>) -> JoinHandle<()> {
+    tokio::spawn(master_link(storage, state, broadcaster))
+}
+
+/// Keeps a replica attached to its master: connects, handshakes, applies the stream,
+/// and starts over with a growing delay whenever any of it fails.
+async fn master_link(storage: Arc<RwLock<Db>>, state: Arc<RwLock<State>>, broadcaster: Arc<RwLock<Broadcaster>>) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let address = match state.read().await.replica_of.clone() {
+            Some(address) if !address.is_empty() => address,
+            _ => return,
+        };
+
+        set_link_state(&state, LinkState::Connecting).await;
+        // the payload of a full resync is left out, its reads only time out when they stall
+        let attempt = timeout(HANDSHAKE_TIMEOUT, async {
+            let mut stream = TcpStream::connect(&address).await?;
+            set_link_state(&state, LinkState::Handshake).await;
+            let resync = handshake(&mut stream, &state, &broadcaster).await?;
+            Ok::<_, anyhow::Error>((stream, resync))
+        }).await.unwrap_or_else(|_| Err(anyhow!("Timed out connecting and handshaking")));
+        let synced = match attempt {
+            Ok((mut stream, resync)) => receive_payload(&mut stream, resync, &state, &storage, &broadcaster).await
+                .map(|received| (stream, received)),
+            Err(e) => Err(e),
+        };
+
+        let (stream, received) = match synced {
+            Ok(synced) => synced,
+            Err(e) => {
+                eprintln!("Failed to sync with master {}: {:#}", address, e);
+                set_link_state(&state, LinkState::Connect).await;
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            },
+        };
+
+        eprintln!("Connected to master {}", address);
+        backoff = MIN_BACKOFF;
+        {
+            let mut state = state.write().await;
+            state.master_link_state = LinkState::Connected;
+            state.master_last_io = now_ms();
+        }
+        serve(stream, &received, Session::new(true), Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster)).await;
+
+        eprintln!("Lost the connection to master {}", address);
+        set_link_state(&state, LinkState::Connect).await;
+        sleep(MIN_BACKOFF).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use super::*;
+    use crate::{protocol::RObject, state::ServerRole, value::Value};
+
+    // the next command the replica sent, as text
+    async fn next_command(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Vec<String> {
+        loop {
+            if let Ok((RObject::Array(args), end)) = RObject::decode(buf, 0) {
+                buf.drain(..end);
+                return args.iter().map(|arg| arg.as_str().unwrap().to_string()).collect();
+            }
+            let mut chunk = [0; 1024];
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "the replica hung up");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    // plays the master through the handshake, returns the link and what PSYNC asked for
+    async fn accept_replica(listener: &TcpListener) -> (TcpStream, Vec<String>) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![];
+        for reply in ["+PONG\r\n", "+OK\r\n", "+OK\r\n"] {
+            next_command(&mut stream, &mut buf).await;
+            stream.write_all(reply.as_bytes()).await.unwrap();
+        }
+        let psync = next_command(&mut stream, &mut buf).await;
+        (stream, psync)
+    }
+
+    async fn eventually(condition: impl Fn(&State, &Db) -> bool, state: &Arc<RwLock<State>>, storage: &Arc<RwLock<Db>>) {
+        timeout(Duration::from_secs(5), async {
+            while !condition(&*state.read().await, &*storage.read().await) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("the replica never got there");
+    }
+
+    #[tokio::test]
+    async fn reconnects_and_continues_where_it_left_off() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let storage = Arc::new(RwLock::new(Db::new()));
+        let state = Arc::new(RwLock::new(State::for_tests()));
+        let broadcaster = Arc::new(RwLock::new(Broadcaster::default()));
+        {
+            let mut state = state.write().await;
+            state.role = ServerRole::Slave;
+            state.replica_of = Some(listener.local_addr().unwrap().to_string());
+        }
+        let link = spawn_master_link(Arc::clone(&storage), Arc::clone(&state), Arc::clone(&broadcaster));
+
+        let (mut master, psync) = accept_replica(&listener).await;
+        assert_eq!(psync, ["PSYNC", "?", "-1"]);
+        let replid = "b".repeat(40);
+        let rdb = crate::rdb::dump(&[]);
+        master.write_all(format!("+FULLRESYNC {} 0\r\n${}\r\n", replid, rdb.len()).as_bytes()).await.unwrap();
+        master.write_all(&rdb).await.unwrap();
+        let set = RObject::Array(vec![
+            RObject::BulkString("SET".into()),
+            RObject::BulkString("k".into()),
+            RObject::BulkString("v".into()),
+        ]).encode();
+        master.write_all(&set).await.unwrap();
+        eventually(|_, storage| storage.contains_key(b"k"), &state, &storage).await;
+        assert_eq!(state.read().await.master_link_state, LinkState::Connected);
+
+        // the link breaks, the replica comes back asking for what follows the SET
+        drop(master);
+        let (mut master, psync) = timeout(Duration::from_secs(5), accept_replica(&listener)).await.unwrap();
+        assert_eq!(psync, ["PSYNC".to_string(), replid, (set.len() + 1).to_string()]);
+        assert!(state.read().await.master_link_down_since > 0);
+        master.write_all(b"+CONTINUE\r\n").await.unwrap();
+        eventually(|state, _| state.master_link_state == LinkState::Connected, &state, &storage).await;
+        assert!(matches!(storage.read().await.get(b"k"), Some(Value::String(value)) if value == "v"));
+        link.abort();
+    }
+
+    #[tokio::test]
+    async fn keeps_retrying_a_master_that_is_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let storage = Arc::new(RwLock::new(Db::new()));
+        let state = Arc::new(RwLock::new(State::for_tests()));
+        {
+            let mut state = state.write().await;
+            state.role = ServerRole::Slave;
+            state.replica_of = Some(address.clone());
+        }
+        let link = spawn_master_link(Arc::clone(&storage), Arc::clone(&state), Arc::new(RwLock::new(Broadcaster::default())));
+        sleep(Duration::from_millis(50)).await;
+        assert!(!link.is_finished());
+        assert_ne!(state.read().await.master_link_state, LinkState::Connected);
+
+        // once it is back the replica attaches on its own
+        let listener = TcpListener::bind(&address).await.unwrap();
+        let (_master, psync) = timeout(Duration::from_secs(10), accept_replica(&listener)).await.unwrap();
+        assert_eq!(psync, ["PSYNC", "?", "-1"]);
+        link.abort();
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::protocol::RObject;
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Per-connection state that outlives a single request.
+pub struct Session {
+    /// Unique per connection, what HELLO reports.
+    pub id: u64,
+    /// The connection a replica keeps to its master. Commands arriving on it are applied
+    /// without replying, except for the ones the master explicitly asks an answer for.
+    pub master_link: bool,
+    /// Replication offset right after this client's last write, what WAIT waits for.
+    pub write_offset: usize,
+    /// Port a replica announced with REPLCONF listening-port, shown in INFO and ROLE.
+    pub listening_port: Option<u16>,
+    /// Whether a replica announced REPLCONF capa eof, so it can take a diskless payload.
+    pub capa_eof: bool,
+    /// RESP version negotiated with HELLO, 2 until then.
+    pub protocol: u8,
+}
+
+impl Session {
+    pub fn new(master_link: bool) -> Self {
+        Session {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            master_link,
+            write_offset: 0,
+            listening_port: None,
+            capa_eof: false,
+            protocol: 2,
+        }
+    }
+
+    /// A map reply, flattened into an array of alternating keys and values for RESP2 clients.
+    pub fn map_reply(&self, pairs: Vec<(RObject, RObject)>) -> RObject {
+        if self.protocol >= 3 {
+            RObject::Map(pairs.into_iter().collect())
+        } else {
+            RObject::Array(pairs.into_iter().flat_map(|(key, value)| [key, value]).collect())
+        }
+    }
+}
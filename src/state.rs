@@ -1,3 +1,5 @@
+use crate::{aof::FsyncPolicy, replica::LinkState};
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ServerRole {
     Master,
@@ -16,12 +18,129 @@ impl std::fmt::Display for ServerRole {
 pub struct State {
     pub role: ServerRole,
     pub master_replid: String,
-    pub master_repl_offset: usize,
+    /// The id we had before the last promotion, replicas of the old master may still continue with it.
+    pub master_replid2: String,
+    /// Last offset `master_replid2` is valid for, -1 when there is none.
+    pub second_replid_offset: i64,
     pub replica_of: Option<String>,
     pub working_port: u64,
+    /// Bytes of the replication stream processed from our master, the offset a replica acks.
     pub consumed: usize,
+    /// Whether `master_replid` and `consumed` describe a stream the master may let us continue.
+    pub has_cached_master: bool,
+    pub master_link_state: LinkState,
+    /// Unix milliseconds of the last read from the master.
+    pub master_last_io: u64,
+    pub master_link_down_since: u64,
+    /// The task running the master link, aborted when REPLICAOF points elsewhere.
+    pub master_link_task: Option<tokio::task::JoinHandle<()>>,
+    pub repl_backlog_size: usize,
+    /// Whether a replica refuses writes from its own clients.
+    pub replica_read_only: bool,
+    /// Whether full resyncs stream the RDB with an EOF mark instead of a length prefix.
+    pub repl_diskless_sync: bool,
+    /// Seconds a diskless sync waits for more replicas to share the transfer.
+    pub repl_diskless_sync_delay: u64,
+    /// Seconds between the PINGs a master sends down the replication stream.
+    pub repl_ping_replica_period: u64,
+    /// Seconds of silence after which either side of a replication link gives up on the other.
+    pub repl_timeout: u64,
+    /// A master refuses writes unless this many replicas acked within `min_replicas_max_lag`
+    /// seconds, 0 disables the check.
+    pub min_replicas_to_write: usize,
+    pub min_replicas_max_lag: u64,
     pub dir: Option<String>,
     pub dbfilename: Option<String>,
+    /// `save <seconds> <changes>` points, empty when automatic saving is off.
+    pub save_params: Vec<(u64, u64)>,
+    /// Unix time in seconds of the last successful save.
+    pub lastsave: u64,
+    pub bgsave_in_progress: bool,
+    pub bgsave_scheduled: bool,
+    pub last_bgsave_ok: bool,
+    pub last_bgsave_try: u64,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    /// Directory under `dir` holding the manifest and the files it lists.
+    pub appenddirname: String,
+    pub appendfsync: FsyncPolicy,
+    /// Whether an AOF ending in an incomplete command is truncated and loaded, or refused.
+    pub aof_load_truncated: bool,
+    /// Growth over the size after the last rewrite that triggers a new one, 0 disables it.
+    pub auto_aof_rewrite_percentage: u64,
+    pub auto_aof_rewrite_min_size: u64,
+    pub aof_rewrite_in_progress: bool,
+    pub aof_last_rewrite_ok: bool,
+    pub proto_max_bulk_len: usize,
+    pub proto_max_multibulk_len: usize,
+}
+
+impl State {
+
+    /// Where the RDB snapshot lives, `dir` defaults to the working directory
+    /// and `dbfilename` to dump.rdb.
+    pub fn rdb_path(&self) -> std::path::PathBuf {
+        std::path::Path::new(self.dir.as_deref().unwrap_or("."))
+            .join(self.dbfilename.as_deref().unwrap_or("dump.rdb"))
+    }
+
+    pub fn aof_dir(&self) -> std::path::PathBuf {
+        std::path::Path::new(self.dir.as_deref().unwrap_or(".")).join(&self.appenddirname)
+    }
+
+    /// The single file AOF from before the manifest layout, only read to upgrade it.
+    pub fn legacy_aof_path(&self) -> std::path::PathBuf {
+        std::path::Path::new(self.dir.as_deref().unwrap_or(".")).join(&self.appendfilename)
+    }
+}
+
+#[cfg(test)]
+impl State {
+
+    /// A master with redis' defaults and nothing on disk, for the tests of the handlers.
+    pub fn for_tests() -> Self {
+        State {
+            role: ServerRole::Master,
+            master_replid: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
+            master_replid2: "0".repeat(40),
+            second_replid_offset: -1,
+            replica_of: None,
+            working_port: 6379,
+            consumed: 0,
+            has_cached_master: false,
+            master_link_state: LinkState::Connect,
+            master_last_io: 0,
+            master_link_down_since: 0,
+            master_link_task: None,
+            repl_backlog_size: 1024 * 1024,
+            replica_read_only: true,
+            repl_diskless_sync: false,
+            repl_diskless_sync_delay: 5,
+            repl_ping_replica_period: 10,
+            repl_timeout: 60,
+            min_replicas_to_write: 0,
+            min_replicas_max_lag: 10,
+            dir: None,
+            dbfilename: None,
+            save_params: vec![],
+            lastsave: 0,
+            bgsave_in_progress: false,
+            bgsave_scheduled: false,
+            last_bgsave_ok: true,
+            last_bgsave_try: 0,
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appenddirname: "appendonlydir".to_string(),
+            appendfsync: FsyncPolicy::EverySec,
+            aof_load_truncated: true,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            aof_rewrite_in_progress: false,
+            aof_last_rewrite_ok: true,
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            proto_max_multibulk_len: 1024 * 1024,
+        }
+    }
 }
 
-pub const BUFFER_SIZE: usize = 128 * 2;
\ No newline at end of file
+pub const BUFFER_SIZE: usize = 16 * 1024;
\ No newline at end of file
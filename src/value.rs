@@ -0,0 +1,118 @@
+use std::collections::{HashMap, HashSet};
+
+use bytes::Bytes;
+
+use crate::{fieldmap::FieldMap, quicklist::QuickList};
+
+// the sizes up to which redis keeps a value in its compact encoding
+const HASH_MAX_LISTPACK_ENTRIES: usize = 128;
+const HASH_MAX_LISTPACK_VALUE: usize = 64;
+const SET_MAX_INTSET_ENTRIES: usize = 512;
+const SET_MAX_LISTPACK_ENTRIES: usize = 128;
+const SET_MAX_LISTPACK_VALUE: usize = 64;
+const ZSET_MAX_LISTPACK_ENTRIES: usize = 128;
+const ZSET_MAX_LISTPACK_VALUE: usize = 64;
+// strings up to this long fit in the same allocation as their object header
+const EMBSTR_MAX_LEN: usize = 44;
+
+// the integer a string spells, as long as it spells it the way the integer prints, like
+// redis' string2ll: `+5` or `007` stay strings since storing them as 5 and 7 would change them
+fn canonical_integer(s: &[u8]) -> Option<i64> {
+    if s.len() > 20 {
+        return None;
+    }
+    let n = std::str::from_utf8(s).ok()?.parse::<i64>().ok()?;
+    (n.to_string().as_bytes() == s).then_some(n)
+}
+
+/// What a key holds. Handlers work on these and only build protocol objects for replies.
+#[derive(Clone)]
+pub enum Value {
+    String(Bytes),
+    List(QuickList),
+    Hash(FieldMap),
+    Set(HashSet<Bytes>),
+    /// Members and their scores, ordered when read.
+    ZSet(HashMap<Bytes, f64>),
+    /// A stream as an RDB file encodes it, with its RDB type. No command reads streams yet,
+    /// keeping the encoding carries them through loading and saving untouched.
+    Stream { rdb_type: u8, encoded: Bytes },
+}
+
+impl Value {
+
+    /// The name TYPE reports.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream { .. } => "stream",
+        }
+    }
+
+    /// The encoding OBJECT ENCODING reports, the one redis would pick for the same content.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(s) => {
+                if canonical_integer(s).is_some() {
+                    "int"
+                } else if s.len() <= EMBSTR_MAX_LEN {
+                    "embstr"
+                } else {
+                    "raw"
+                }
+            },
+            // a list that outgrew a single node is a quicklist
+            Value::List(items) => if items.node_count() <= 1 { "listpack" } else { "quicklist" },
+            Value::Hash(fields) => {
+                let small = fields.len() <= HASH_MAX_LISTPACK_ENTRIES
+                    && fields.iter().all(|(field, value)| field.len() <= HASH_MAX_LISTPACK_VALUE && value.len() <= HASH_MAX_LISTPACK_VALUE);
+                if small { "listpack" } else { "hashtable" }
+            },
+            Value::Set(members) => {
+                let integers = members.iter().all(|member| canonical_integer(member).is_some());
+                if integers && members.len() <= SET_MAX_INTSET_ENTRIES {
+                    "intset"
+                } else if members.len() <= SET_MAX_LISTPACK_ENTRIES && members.iter().all(|member| member.len() <= SET_MAX_LISTPACK_VALUE) {
+                    "listpack"
+                } else {
+                    "hashtable"
+                }
+            },
+            Value::ZSet(members) => {
+                let small = members.len() <= ZSET_MAX_LISTPACK_ENTRIES
+                    && members.keys().all(|member| member.len() <= ZSET_MAX_LISTPACK_VALUE);
+                if small { "listpack" } else { "skiplist" }
+            },
+            Value::Stream { .. } => "stream",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(members: &[&str]) -> Value {
+        Value::Set(members.iter().map(|member| Bytes::copy_from_slice(member.as_bytes())).collect())
+    }
+
+    #[test]
+    fn only_canonical_integers_make_an_intset() {
+        assert_eq!(set(&["1", "-5", "9223372036854775807"]).encoding(), "intset");
+        assert_eq!(set(&["1", "+5"]).encoding(), "listpack");
+        assert_eq!(set(&["1", "007"]).encoding(), "listpack");
+        assert_eq!(set(&["1", "-0"]).encoding(), "listpack");
+        assert_eq!(set(&["9223372036854775808"]).encoding(), "listpack");
+    }
+
+    #[test]
+    fn only_canonical_integers_are_int_strings() {
+        assert_eq!(Value::String(Bytes::from("12")).encoding(), "int");
+        assert_eq!(Value::String(Bytes::from("012")).encoding(), "embstr");
+        assert_eq!(Value::String(Bytes::from("+12")).encoding(), "embstr");
+    }
+}